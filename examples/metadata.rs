@@ -59,9 +59,7 @@ fn main() {
 
         println!("duration={}", file.duration());
         for tag in file.metadata_iter() {
-            let k = tag.key().ok().unwrap();
-            let v = tag.value().ok().unwrap();
-            println!("{}={}", k, v);
+            println!("{}={}", tag.key_lossy(), tag.value_lossy());
         }
         if file.is_dirty() {
             file.save().ok().expect("unable to save file");
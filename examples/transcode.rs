@@ -6,7 +6,7 @@
 extern crate groove;
 
 use std::option::Option;
-use std::old_io::File;
+use groove::transcode::{Transcoder, Output};
 
 // transcode one or more files into one output file
 
@@ -23,7 +23,7 @@ fn main() {
 
     groove::set_logging(groove::Log::Info);
 
-    let playlist = groove::Playlist::new();
+    let mut transcoder = Transcoder::new();
 
     let mut i = 1;
     while i < args.len() {
@@ -55,16 +55,7 @@ fn main() {
                 return;
             }
         } else {
-            match groove::File::open(&Path::new(full_arg.as_bytes())) {
-                Option::Some(file) => {
-                    playlist.append(&file, 1.0, 1.0);
-                },
-                Option::None => {
-                    let _ = writeln!(&mut stderr, "Error opening input file {}", full_arg);
-                    std::os::set_exit_status(1);
-                    return;
-                },
-            }
+            transcoder.add_input(&Path::new(full_arg.as_bytes()));
         }
         i += 1;
     }
@@ -76,51 +67,33 @@ fn main() {
             return;
         },
     };
-    let encoder = groove::Encoder::new();
-    encoder.set_bit_rate(bit_rate_k * 1000);
-    match format_option {
-        Option::Some(format) => encoder.set_format_short_name(format),
-        _ => {},
-    }
-    match codec_option {
-        Option::Some(codec) => encoder.set_codec_short_name(codec),
-        _ => {},
-    }
-    match mime_option {
-        Option::Some(mime) => encoder.set_mime_type(mime),
-        _ => {},
-    }
-    encoder.set_filename(output_file_name);
-
-    if playlist.len() == 1 {
-        encoder.set_target_audio_format(playlist.first().file().audio_format());
-
-        // copy metadata
-        for tag in playlist.first().file().metadata_iter() {
-            let k = tag.key().ok().unwrap();
-            let v = tag.value().ok().unwrap();
-            encoder.metadata_set(k, v, false).ok().expect("unable to set metadata");
+    {
+        let encoder = transcoder.encoder();
+        encoder.set_bit_rate(bit_rate_k * 1000);
+        match format_option {
+            Option::Some(format) => encoder.set_format_short_name(format),
+            _ => {},
+        }
+        match codec_option {
+            Option::Some(codec) => encoder.set_codec_short_name(codec),
+            _ => {},
+        }
+        match mime_option {
+            Option::Some(mime) => encoder.set_mime_type(mime),
+            _ => {},
         }
     }
+    transcoder.set_progress_callback(|progress| {
+        print!("\r{:.1}/{:.1}s", progress.seconds, progress.total_duration);
+    });
 
-    encoder.attach(&playlist).ok().expect("error attaching encoder");
-
-    let mut f = match File::create(&Path::new(output_file_name)) {
-        Err(_) => {
-            let _ = writeln!(&mut stderr, "Error opening output file {}", output_file_name);
+    match transcoder.run(Output::Path(Path::new(output_file_name))) {
+        Ok(report) => println!("\nwrote {} bytes", report.bytes_written),
+        Err(err) => {
+            let _ = writeln!(&mut stderr, "\nError transcoding: {:?}", err);
             std::os::set_exit_status(1);
             return;
         },
-        Ok(file) => file,
-    };
-
-    loop {
-        match encoder.buffer_get_blocking() {
-            Option::Some(buf) => {
-                f.write_all(buf.as_vec()).ok().expect("write error");
-            },
-            Option::None => break,
-        }
     }
 
     groove::finish();
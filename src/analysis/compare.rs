@@ -0,0 +1,350 @@
+//! objective comparison of two decodes of the same audio.
+//!
+//! compare decodes a reference and another file, for example a transcode
+//! of it, at the reference's audio format, finds the offset between them
+//! and measures the difference of every channel. Lossy encoders usually
+//! add a few hundred to a few thousand samples of delay at the start; the
+//! offset is found by cross-correlating the mono downmix of the first
+//! seconds of both. Both files are held in memory in double format while
+//! they are compared.
+
+use std::cmp;
+use std::f64;
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::fft;
+use super::super::{AudioFormat, File, Playlist, SampleFormat, SampleType, Sink};
+
+#[derive(Copy, Debug)]
+pub struct Options {
+    /// the largest offset, in seconds either way, to search for
+    pub max_offset: f64,
+    /// seconds from the start of both files which are cross-correlated
+    pub window: f64,
+    /// absolute sample difference, in double format, above which frames
+    /// count as different
+    pub tolerance: f64,
+}
+
+impl Options {
+    /// offsets of up to a quarter of a second in the first ten seconds,
+    /// and any difference at all counts
+    pub fn default() -> Options {
+        Options {
+            max_offset: 0.25,
+            window: 10.0,
+            tolerance: 0.0,
+        }
+    }
+}
+
+#[derive(Copy, Debug)]
+pub struct ChannelDifference {
+    pub max_difference: f64,
+    /// root mean square of the differences
+    pub rms_error: f64,
+    /// signal to noise ratio in dB, with the reference as the signal and
+    /// the difference as the noise. infinite if the channels are equal.
+    pub snr: f64,
+    /// peak signal to noise ratio in dB, relative to full scale
+    pub psnr: f64,
+}
+
+pub struct Comparison {
+    /// the format both files were decoded at, that of the reference
+    pub audio_format: AudioFormat,
+    pub reference_frames: usize,
+    pub other_frames: usize,
+    /// frames by which the other file is late compared to the reference.
+    /// frame i of the reference is compared to frame i + offset of the
+    /// other file.
+    pub offset: isize,
+    /// frames which overlap after alignment and were compared
+    pub compared_frames: usize,
+    pub channels: Vec<ChannelDifference>,
+    /// the first reference frame where any channel differs by more than
+    /// the tolerance
+    pub first_difference: Option<usize>,
+}
+
+impl Comparison {
+    /// true if every compared frame is within the tolerance
+    pub fn is_match(&self) -> bool {
+        self.first_difference.is_none()
+    }
+
+    /// the offset in seconds
+    pub fn offset_seconds(&self) -> f64 {
+        self.offset as f64 / self.audio_format.sample_rate as f64
+    }
+}
+
+/// compare other to reference. both files are opened again, so they may
+/// be the same File or be in use by other playlists. returns the error
+/// code of attaching a sink, or -1 if a file could not be opened again.
+/// both files are decoded into memory first, at 8 bytes per sample: a
+/// four minute stereo track at 44100 Hz takes about 170 MB.
+pub fn compare(reference: &File, other: &File, options: &Options) -> Result<Comparison, i32> {
+    let source_format = reference.audio_format();
+    let format = AudioFormat {
+        sample_rate: source_format.sample_rate,
+        channel_layout: source_format.channel_layout,
+        sample_fmt: SampleFormat { sample_type: SampleType::Dbl, planar: true },
+    };
+    let rate = format.sample_rate as f64;
+    let reference_samples = try!(decode(reference, format));
+    let other_samples = try!(decode(other, format));
+    let reference_frames = reference_samples[0].len();
+    let other_frames = other_samples[0].len();
+
+    let max_lag = (options.max_offset * rate) as usize;
+    let window = (options.window * rate) as usize;
+    let offset = find_offset(mono(&reference_samples, window).as_slice(),
+                             mono(&other_samples, window).as_slice(), max_lag);
+
+    let (compared_frames, channels, first_difference) =
+        measure(reference_samples.as_slice(), other_samples.as_slice(), offset, options.tolerance);
+
+    Result::Ok(Comparison {
+        audio_format: format,
+        reference_frames: reference_frames,
+        other_frames: other_frames,
+        offset: offset,
+        compared_frames: compared_frames,
+        channels: channels,
+        first_difference: first_difference,
+    })
+}
+
+/// the number of frames compared, the difference of every channel and the
+/// first frame which differs by more than tolerance, with frame i of
+/// reference lined up with frame i + offset of other
+fn measure(reference: &[Vec<f64>], other: &[Vec<f64>], offset: isize, tolerance: f64)
+    -> (usize, Vec<ChannelDifference>, Option<usize>)
+{
+    let reference_frames = reference[0].len();
+    let other_frames = other[0].len();
+    // the range of reference frames which have a counterpart
+    let start = if offset < 0 {(-offset) as usize} else {0};
+    let end = cmp::min(reference_frames as isize, other_frames as isize - offset);
+    let end = if end < start as isize {start} else {end as usize};
+
+    let mut first_difference = Option::None;
+    let mut channels = Vec::new();
+    for (reference_channel, other_channel) in reference.iter().zip(other.iter()) {
+        let mut max_difference = 0.0;
+        let mut error_sum = 0.0;
+        let mut signal_sum = 0.0;
+        for i in start..end {
+            let sample = reference_channel[i];
+            let difference = (other_channel[(i as isize + offset) as usize] - sample).abs();
+            if difference > max_difference {
+                max_difference = difference;
+            }
+            if difference > tolerance {
+                first_difference = match first_difference {
+                    Option::Some(frame) if frame <= i => Option::Some(frame),
+                    _ => Option::Some(i),
+                };
+            }
+            error_sum += difference * difference;
+            signal_sum += sample * sample;
+        }
+        let count = cmp::max(end - start, 1) as f64;
+        let rms_error = (error_sum / count).sqrt();
+        channels.push(ChannelDifference {
+            max_difference: max_difference,
+            rms_error: rms_error,
+            snr: if error_sum == 0.0 {f64::INFINITY} else {10.0 * (signal_sum / error_sum).log10()},
+            psnr: if rms_error == 0.0 {f64::INFINITY} else {-20.0 * rms_error.log10()},
+        });
+    }
+
+    (end - start, channels, first_difference)
+}
+
+/// every channel of file, decoded at format, which must be planar Dbl
+fn decode(file: &File, format: AudioFormat) -> Result<Vec<Vec<f64>>, i32> {
+    let file = try!(File::open(&file.filename()).ok_or(-1));
+    let channel_count = format.channel_layout.count() as usize;
+    let playlist = Playlist::new();
+    let sink = Sink::new();
+    sink.set_audio_format(format);
+    playlist.append(&file, 1.0, 1.0);
+    try!(sink.attach(&playlist));
+
+    let mut channels: Vec<Vec<f64>> = (0..channel_count).map(|_| Vec::new()).collect();
+    loop {
+        let buffer = match sink.buffer_get_blocking() {
+            Option::Some(buffer) => buffer,
+            Option::None => break,
+        };
+        for (index, channel) in channels.iter_mut().enumerate() {
+            channel.push_all(buffer.channel_as_slice_f64(index as u32));
+        }
+    }
+    sink.detach();
+    playlist.clear();
+    Result::Ok(channels)
+}
+
+/// the average of all channels over the first frames
+fn mono(channels: &Vec<Vec<f64>>, frames: usize) -> Vec<f64> {
+    let frames = cmp::min(frames, channels[0].len());
+    let scale = 1.0 / channels.len() as f64;
+    (0..frames).map(|i| channels.iter().fold(0.0, |sum, channel| sum + channel[i]) * scale).collect()
+}
+
+/// the lag from -max_lag to max_lag at which the cross-correlation of a
+/// and b is largest, computed with FFTs
+fn find_offset(a: &[f64], b: &[f64], max_lag: usize) -> isize {
+    if a.is_empty() || b.is_empty() || max_lag == 0 {
+        return 0;
+    }
+    let mut n = 1;
+    while n < cmp::max(a.len(), b.len()) + max_lag {
+        n <<= 1;
+    }
+    let pad = |samples: &[f64]| -> Vec<f64> {
+        let mut padded = samples.to_vec();
+        padded.extend((0..n - samples.len()).map(|_| 0.0));
+        padded
+    };
+    let (mut a_re, mut b_re) = (pad(a), pad(b));
+    let mut a_im: Vec<f64> = (0..n).map(|_| 0.0).collect();
+    let mut b_im = a_im.clone();
+    fft(a_re.as_mut_slice(), a_im.as_mut_slice());
+    fft(b_re.as_mut_slice(), b_im.as_mut_slice());
+
+    // conj(A) * B, conjugated again so that the forward FFT inverts it.
+    // the 1 / n scale does not move the maximum.
+    let mut re: Vec<f64> = (0..n).map(|k| a_re[k] * b_re[k] + a_im[k] * b_im[k]).collect();
+    let mut im: Vec<f64> = (0..n).map(|k| -(a_re[k] * b_im[k] - a_im[k] * b_re[k])).collect();
+    fft(re.as_mut_slice(), im.as_mut_slice());
+
+    // re[k] is now the sum of a[i] * b[i + k], with negative k at n + k
+    let max_lag = cmp::min(max_lag, n / 2 - 1);
+    let mut best = 0;
+    let mut best_value = re[0];
+    for lag in 1..max_lag + 1 {
+        if re[lag] > best_value {
+            best = lag as isize;
+            best_value = re[lag];
+        }
+        if re[n - lag] > best_value {
+            best = -(lag as isize);
+            best_value = re[n - lag];
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use std::num::{Float, Int};
+    use std::option::Option;
+
+    use super::{find_offset, measure, mono};
+
+    fn noise(seed: u32, len: usize) -> Vec<f64> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 8) as f64 / (1u32 << 23) as f64 - 1.0
+        }).collect()
+    }
+
+    fn delayed(samples: &[f64], frames: usize) -> Vec<f64> {
+        let mut result: Vec<f64> = (0..frames).map(|_| 0.0).collect();
+        result.push_all(samples);
+        result
+    }
+
+    #[test]
+    fn offset_of_shifted_signals() {
+        let a = noise(1, 4000);
+        assert_eq!(find_offset(a.as_slice(), a.as_slice(), 100), 0);
+        // b is late
+        assert_eq!(find_offset(a.as_slice(), delayed(a.as_slice(), 37).as_slice(), 100), 37);
+        // b is early
+        assert_eq!(find_offset(a.as_slice(), &a[23..], 100), -23);
+        assert_eq!(find_offset(a.as_slice(), &[], 100), 0);
+    }
+
+    #[test]
+    fn offset_survives_noise_and_gain() {
+        let a = noise(1, 8000);
+        let hiss = noise(2, 8000 + 250);
+        let b: Vec<f64> = delayed(a.as_slice(), 250).iter().zip(hiss.iter())
+            .map(|(&sample, &hiss)| 0.5 * sample + 0.1 * hiss)
+            .collect();
+        assert_eq!(find_offset(a.as_slice(), b.as_slice(), 1000), 250);
+    }
+
+    #[test]
+    fn mono_mixes_the_first_frames() {
+        let channels = vec![vec![1.0, 0.5, 0.0], vec![0.0, 0.5, 1.0]];
+        assert_eq!(mono(&channels, 2), vec![0.5, 0.5]);
+        assert_eq!(mono(&channels, 10).len(), 3);
+    }
+
+    #[test]
+    fn equal_channels() {
+        let reference = vec![noise(1, 1000), noise(2, 1000)];
+        let (compared, channels, first) = measure(reference.as_slice(), reference.as_slice(), 0, 0.0);
+        assert_eq!(compared, 1000);
+        assert_eq!(first, Option::None);
+        for channel in channels.iter() {
+            assert_eq!(channel.max_difference, 0.0);
+            assert_eq!(channel.snr, f64::INFINITY);
+            assert_eq!(channel.psnr, f64::INFINITY);
+        }
+    }
+
+    #[test]
+    fn snr_and_psnr() {
+        // a square wave at full scale and a copy 10% louder: the error is
+        // a tenth of the signal everywhere
+        let reference = vec![(0..1000).map(|i| if i % 2 == 0 {1.0} else {-1.0}).collect::<Vec<f64>>()];
+        let other = vec![reference[0].iter().map(|&sample| sample * 1.1).collect::<Vec<f64>>()];
+        let (compared, channels, first) = measure(reference.as_slice(), other.as_slice(), 0, 0.05);
+        assert_eq!(compared, 1000);
+        assert_eq!(first, Option::Some(0));
+        assert!((channels[0].max_difference - 0.1).abs() < 1e-9);
+        assert!((channels[0].rms_error - 0.1).abs() < 1e-9);
+        assert!((channels[0].snr - 20.0).abs() < 1e-6);
+        assert!((channels[0].psnr - 20.0).abs() < 1e-6);
+        // a larger tolerance
+        let (_, _, first) = measure(reference.as_slice(), other.as_slice(), 0, 0.2);
+        assert_eq!(first, Option::None);
+
+        // a quarter of the signal power over the same error
+        let quiet = vec![reference[0].iter().map(|&sample| sample * 0.5).collect::<Vec<f64>>()];
+        let louder = vec![quiet[0].iter().map(|&sample| sample + 0.1).collect::<Vec<f64>>()];
+        let (_, channels, _) = measure(quiet.as_slice(), louder.as_slice(), 0, 0.0);
+        assert!((channels[0].snr - 10.0 * (0.25 / 0.01).log10()).abs() < 1e-6);
+        assert!((channels[0].psnr - 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn aligned_by_the_offset() {
+        let samples = noise(1, 1000);
+        let reference = vec![samples.clone()];
+        let other = vec![delayed(samples.as_slice(), 3)];
+        let (compared, _, first) = measure(reference.as_slice(), other.as_slice(), 3, 0.0);
+        assert_eq!(compared, 1000);
+        assert_eq!(first, Option::None);
+        // other starts later, so the first frames of reference have no
+        // counterpart
+        let other = vec![samples[5..].to_vec()];
+        let (compared, _, first) = measure(reference.as_slice(), other.as_slice(), -5, 0.0);
+        assert_eq!(compared, 995);
+        assert_eq!(first, Option::None);
+        // the wrong offset
+        let (_, _, first) = measure(reference.as_slice(), other.as_slice(), 0, 0.0);
+        assert_eq!(first, Option::Some(0));
+    }
+}
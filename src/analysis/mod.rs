@@ -0,0 +1,190 @@
+//! analysis of decoded audio.
+
+use std::f64::consts::PI;
+use std::num::Float;
+
+use super::{DecodedBuffer, SampleType};
+
+pub mod compare;
+pub mod silence;
+pub mod spectrum;
+pub mod tempo;
+
+/// in place radix-2 FFT. the length of re and im must be the same power
+/// of two.
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n > 0 && n & (n - 1) == 0 && im.len() == n);
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        let (w_re, w_im) = (angle.cos(), angle.sin());
+        let mut start = 0;
+        while start < n {
+            let (mut cur_re, mut cur_im) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * cur_re - im[b] * cur_im;
+                let t_im = re[b] * cur_im + im[b] * cur_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+                let next_re = cur_re * w_re - cur_im * w_im;
+                cur_im = cur_re * w_im + cur_im * w_re;
+                cur_re = next_re;
+            }
+            start += len;
+        }
+        len <<= 1;
+    }
+}
+
+/// the average of all channels of a Flt or Dbl buffer, planar or
+/// interleaved. panics for other sample types.
+fn mono_samples(buffer: &DecodedBuffer) -> Vec<f32> {
+    let format = buffer.audio_format();
+    let channels = format.channel_layout.count() as usize;
+    let frames = buffer.frame_count();
+    let mut mono: Vec<f32> = (0..frames).map(|_| 0.0).collect();
+    let scale = 1.0 / channels as f32;
+    match (format.sample_fmt.sample_type, format.sample_fmt.planar) {
+        (SampleType::Flt, true) => {
+            for channel in 0..channels {
+                let samples = buffer.channel_as_slice_f32(channel as u32);
+                for (out, &sample) in mono.iter_mut().zip(samples.iter()) {
+                    *out += sample * scale;
+                }
+            }
+        },
+        (SampleType::Flt, false) => {
+            let samples = buffer.as_slice_f32();
+            for frame in 0..frames {
+                for channel in 0..channels {
+                    mono[frame] += samples[frame * channels + channel] * scale;
+                }
+            }
+        },
+        (SampleType::Dbl, true) => {
+            for channel in 0..channels {
+                let samples = buffer.channel_as_slice_f64(channel as u32);
+                for (out, &sample) in mono.iter_mut().zip(samples.iter()) {
+                    *out += sample as f32 * scale;
+                }
+            }
+        },
+        (SampleType::Dbl, false) => {
+            let samples = buffer.as_slice_f64();
+            for frame in 0..frames {
+                for channel in 0..channels {
+                    mono[frame] += samples[frame * channels + channel] as f32 * scale;
+                }
+            }
+        },
+        _ => panic!("expected a Flt or Dbl buffer"),
+    }
+    mono
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::num::Float;
+
+    use super::fft;
+
+    fn filled(value: f64, len: usize) -> Vec<f64> {
+        (0..len).map(|_| value).collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((*a - *e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn impulse_is_flat() {
+        let mut re = vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let mut im = filled(0.0, 8);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        assert_close(re.as_slice(), &[1.0; 8]);
+        assert_close(im.as_slice(), &[0.0; 8]);
+    }
+
+    #[test]
+    fn constant_is_dc() {
+        let mut re = filled(0.5, 8);
+        let mut im = filled(0.0, 8);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        assert_close(re.as_slice(), &[4.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_close(im.as_slice(), &[0.0; 8]);
+    }
+
+    #[test]
+    fn cosine_and_sine() {
+        // 2 cycles in 16 samples land in bins 2 and 14
+        let n = 16;
+        let mut re: Vec<f64> = (0..n).map(|i| (2.0 * PI * 2.0 * i as f64 / n as f64).cos()).collect();
+        let mut im = filled(0.0, 16);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        let mut expected = filled(0.0, 16);
+        expected[2] = 8.0;
+        expected[14] = 8.0;
+        assert_close(re.as_slice(), expected.as_slice());
+        assert_close(im.as_slice(), &[0.0; 16]);
+
+        let mut re: Vec<f64> = (0..n).map(|i| (2.0 * PI * 3.0 * i as f64 / n as f64).sin()).collect();
+        let mut im = filled(0.0, 16);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        let mut expected = filled(0.0, 16);
+        expected[3] = -8.0;
+        expected[13] = 8.0;
+        assert_close(re.as_slice(), &[0.0; 16]);
+        assert_close(im.as_slice(), expected.as_slice());
+    }
+
+    #[test]
+    fn matches_direct_dft() {
+        let input = [0.3, -1.0, 2.5, 0.0, 1.25, -0.75, 0.5, 4.0];
+        let n = input.len();
+        let mut re = input.to_vec();
+        let mut im = filled(0.0, 8);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        for k in 0..n {
+            let (mut sum_re, mut sum_im) = (0.0, 0.0);
+            for (t, &x) in input.iter().enumerate() {
+                let angle = -2.0 * PI * (k * t) as f64 / n as f64;
+                sum_re += x * angle.cos();
+                sum_im += x * angle.sin();
+            }
+            assert!((re[k] - sum_re).abs() < 1e-9 && (im[k] - sum_im).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn rejects_other_lengths() {
+        let mut re = filled(0.0, 6);
+        let mut im = filled(0.0, 6);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+    }
+}
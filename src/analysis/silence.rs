@@ -0,0 +1,279 @@
+//! leading, trailing and internal silence of playlist items.
+//!
+//! A sample frame is silent when the absolute value of every channel is
+//! below the threshold. Leading and trailing silence are measured to the
+//! sample; internal gaps are only reported when they last at least the
+//! minimum duration. Positions are seconds into each item, so they can be
+//! passed to Playlist::append_trimmed as they are.
+
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::super::{AudioFormat, Playlist, PlaylistItem, SampleFormat, SampleType, Sink};
+use super::super::excerpt::Range;
+
+pub struct ItemSilence {
+    pub item: PlaylistItem,
+    /// decoded duration in seconds
+    pub duration: f64,
+    /// seconds of silence at the start. equal to duration if the item is
+    /// silent throughout.
+    pub leading: f64,
+    /// seconds of silence at the end
+    pub trailing: f64,
+    /// silent gaps between sound, as start and end seconds
+    pub gaps: Vec<(f64, f64)>,
+}
+
+impl ItemSilence {
+    pub fn is_silent(&self) -> bool {
+        self.leading >= self.duration
+    }
+
+    /// in and out points which cut off the leading and trailing silence,
+    /// for Playlist::append_trimmed. None if the item is silent throughout.
+    pub fn trim_points(&self) -> Option<(f64, Option<f64>)> {
+        if self.is_silent() {
+            Option::None
+        } else {
+            Option::Some((self.leading, Option::Some(self.duration - self.trailing)))
+        }
+    }
+
+    /// the parts with sound between the gaps, for one trimmed item each. a gap
+    /// is split in the middle so that no audio is lost, like track
+    /// boundaries in a CUE sheet.
+    pub fn segments(&self) -> Vec<Range> {
+        if self.is_silent() {
+            return Vec::new();
+        }
+        let mut segments = Vec::new();
+        let mut start = self.leading;
+        for &(gap_start, gap_end) in self.gaps.iter() {
+            let middle = (gap_start + gap_end) / 2.0;
+            segments.push(Range { start: start, end: Option::Some(middle) });
+            start = middle;
+        }
+        segments.push(Range { start: start, end: Option::Some(self.duration - self.trailing) });
+        segments
+    }
+}
+
+/// the state of the item being decoded
+struct Scan {
+    rate: f64,
+    channels: usize,
+    threshold: f32,
+    min_duration: f64,
+    duration: f64,
+    /// where the first sound is, if any yet
+    first_sound: Option<f64>,
+    /// where the current silent run started, if in one. items start out
+    /// silent until the first sound.
+    silence_start: Option<f64>,
+    gaps: Vec<(f64, f64)>,
+}
+
+impl Scan {
+    /// threshold is an absolute sample value in float format
+    fn new(rate: f64, channels: usize, threshold: f32, min_duration: f64) -> Scan {
+        Scan {
+            rate: rate,
+            channels: channels,
+            threshold: threshold,
+            min_duration: min_duration,
+            duration: 0.0,
+            first_sound: Option::None,
+            silence_start: Option::Some(0.0),
+            gaps: Vec::new(),
+        }
+    }
+
+    /// interleaved float samples which start at pos seconds into the item
+    fn push(&mut self, pos: f64, samples: &[f32]) {
+        let frames = samples.len() / self.channels;
+        for frame in 0..frames {
+            let time = pos + frame as f64 / self.rate;
+            let threshold = self.threshold;
+            let silent = samples[frame * self.channels..(frame + 1) * self.channels].iter()
+                .all(|sample| sample.abs() < threshold);
+            if silent {
+                if self.silence_start.is_none() {
+                    self.silence_start = Option::Some(time);
+                }
+            } else {
+                match self.silence_start.take() {
+                    Option::Some(start) => match self.first_sound {
+                        Option::Some(_) => if time - start >= self.min_duration {
+                            self.gaps.push((start, time));
+                        },
+                        Option::None => self.first_sound = Option::Some(time),
+                    },
+                    Option::None => {},
+                }
+            }
+        }
+        self.duration = pos + frames as f64 / self.rate;
+    }
+
+    fn finish(self, item: PlaylistItem) -> ItemSilence {
+        let trailing = match (self.first_sound, self.silence_start) {
+            (Option::Some(_), Option::Some(start)) => self.duration - start,
+            _ => 0.0,
+        };
+        ItemSilence {
+            item: item,
+            duration: self.duration,
+            leading: self.first_sound.unwrap_or(self.duration),
+            trailing: trailing,
+            gaps: self.gaps,
+        }
+    }
+}
+
+/// measure the silence of every item of playlist. threshold_db is in dBFS,
+/// for example -60.0. min_duration is the shortest internal gap, in
+/// seconds, to report. every item's file is decoded through a Sink and
+/// Playlist of their own, at the file's own sample rate and channel layout
+/// and with the item's gain, so the threshold applies to the samples as
+/// they are. in and out points of trimmed items are not applied. returns
+/// the error code of attaching a sink on failure.
+pub fn detect(playlist: &Playlist, threshold_db: f64, min_duration: f64) -> Result<Vec<ItemSilence>, i32> {
+    let threshold = 10.0f64.powf(threshold_db / 20.0) as f32;
+    let mut results = Vec::new();
+    for item in playlist.iter() {
+        results.push(try!(detect_item(item, threshold, min_duration)));
+    }
+    Result::Ok(results)
+}
+
+fn detect_item(item: PlaylistItem, threshold: f32, min_duration: f64) -> Result<ItemSilence, i32> {
+    let file = item.file();
+    let source_format = file.audio_format();
+    let playlist = Playlist::new();
+    let sink = Sink::new();
+    sink.set_audio_format(AudioFormat {
+        sample_rate: source_format.sample_rate,
+        channel_layout: source_format.channel_layout,
+        sample_fmt: SampleFormat { sample_type: SampleType::Flt, planar: false },
+    });
+    playlist.append(&file, item.gain(), item.peak());
+    try!(sink.attach(&playlist));
+
+    let mut scan = Scan::new(source_format.sample_rate as f64,
+                             source_format.channel_layout.count() as usize, threshold, min_duration);
+    loop {
+        match sink.buffer_get_blocking() {
+            Option::Some(buffer) => scan.push(buffer.pos(), buffer.as_slice_f32()),
+            Option::None => break,
+        }
+    }
+    sink.detach();
+    playlist.clear();
+    Result::Ok(scan.finish(item))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::option::Option;
+    use std::ptr;
+
+    use super::Scan;
+    use super::super::super::PlaylistItem;
+
+    const RATE: f64 = 1000.0;
+
+    /// stereo frames of silence or sound, one per millisecond
+    fn frames(runs: &[(bool, usize)]) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for &(sound, count) in runs.iter() {
+            for _ in 0..count {
+                // one channel is enough for a frame to have sound
+                samples.push(0.0001);
+                samples.push(if sound {-0.5} else {0.0});
+            }
+        }
+        samples
+    }
+
+    fn scan(runs: &[(bool, usize)], buffer_frames: usize) -> Scan {
+        // -40 dBFS
+        let mut scan = Scan::new(RATE, 2, 0.01, 0.1);
+        let samples = frames(runs);
+        for (index, chunk) in samples.chunks(buffer_frames * 2).enumerate() {
+            scan.push((index * buffer_frames) as f64 / RATE, chunk);
+        }
+        scan
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn item() -> PlaylistItem {
+        PlaylistItem { groove_playlist_item: ptr::null_mut() }
+    }
+
+    #[test]
+    fn leading_trailing_and_gaps() {
+        let runs = [(false, 250), (true, 500), (false, 50), (true, 200), (false, 300),
+                    (true, 100), (false, 400)];
+        // the same result whatever the buffer size
+        for &buffer_frames in [1, 64, 1000, 5000].iter() {
+            let silence = scan(&runs, buffer_frames).finish(item());
+            assert!(close(silence.duration, 1.8));
+            assert!(close(silence.leading, 0.25));
+            assert!(close(silence.trailing, 0.4));
+            // the 50 ms gap is shorter than the minimum
+            assert_eq!(silence.gaps.len(), 1);
+            assert!(close(silence.gaps[0].0, 1.0));
+            assert!(close(silence.gaps[0].1, 1.3));
+        }
+    }
+
+    #[test]
+    fn trim_points_and_segments() {
+        let runs = [(false, 250), (true, 500), (false, 300), (true, 100), (false, 400)];
+        let silence = scan(&runs, 64).finish(item());
+        let (start, end) = silence.trim_points().unwrap();
+        assert!(close(start, 0.25));
+        assert!(close(end.unwrap(), 1.15));
+
+        let segments = silence.segments();
+        assert_eq!(segments.len(), 2);
+        // split in the middle of the gap from 0.75 to 1.05
+        assert!(close(segments[0].start, 0.25));
+        assert!(close(segments[0].end.unwrap(), 0.9));
+        assert!(close(segments[1].start, 0.9));
+        assert!(close(segments[1].end.unwrap(), 1.15));
+    }
+
+    #[test]
+    fn no_silence() {
+        let silence = scan(&[(true, 300)], 64).finish(item());
+        assert_eq!(silence.leading, 0.0);
+        assert_eq!(silence.trailing, 0.0);
+        assert!(silence.gaps.is_empty());
+        let (start, end) = silence.trim_points().unwrap();
+        assert_eq!(start, 0.0);
+        assert!(close(end.unwrap(), 0.3));
+        let segments = silence.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start, 0.0);
+        assert_eq!(segments[0].end, end);
+    }
+
+    #[test]
+    fn silent_throughout() {
+        let silence = scan(&[(false, 300)], 64).finish(item());
+        assert!(silence.is_silent());
+        assert!(close(silence.leading, 0.3));
+        assert_eq!(silence.trailing, 0.0);
+        assert_eq!(silence.trim_points(), Option::None);
+        assert!(silence.segments().is_empty());
+
+        let silence = Scan::new(RATE, 2, 0.01, 0.1).finish(item());
+        assert!(silence.is_silent());
+    }
+}
@@ -0,0 +1,388 @@
+//! windowed FFT magnitudes of decoded audio, and spectrogram images.
+//!
+//! An Analyzer takes DecodedBuffers in Flt or Dbl format, planar or
+//! interleaved, mixes them down to mono and produces a Frame of magnitudes
+//! every hop samples. Feed it buffers one at a time with push, for example
+//! for a visualizer, or let run read a Sink to the end.
+
+use std::f64::consts::PI;
+use std::num::Float;
+use std::old_io;
+use std::old_io::{IoError, IoResult, Writer};
+use std::option::Option;
+use std::result::Result;
+
+use super::{fft, mono_samples};
+use super::super::{DecodedBuffer, PlaylistItem, Sink};
+
+#[derive(Copy, Debug, PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+/// how FFT bins are grouped into the magnitudes of a Frame
+#[derive(Copy, Debug, PartialEq)]
+pub enum Bands {
+    /// every bin from 0 Hz to half the sample rate, fft_size / 2 + 1 values
+    Linear,
+    /// count bands with logarithmically spaced edges from min_freq to
+    /// max_freq Hz. each band is the RMS of the bins in it.
+    Log { count: usize, min_freq: f64, max_freq: f64 },
+}
+
+/// the spectrum of fft_size samples
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// seconds into the playlist item where the frame starts
+    pub pos: f64,
+    /// linear magnitudes, 1.0 for a full scale sine wave
+    pub magnitudes: Vec<f32>,
+}
+
+pub struct Spectrogram {
+    pub sample_rate: i32,
+    pub hop: usize,
+    /// the center frequency in Hz of each magnitude
+    pub frequencies: Vec<f64>,
+    pub frames: Vec<Frame>,
+}
+
+pub struct Analyzer {
+    fft_size: usize,
+    hop: usize,
+    bands: Bands,
+    coefficients: Vec<f64>,
+    /// mono samples not yet consumed by a frame
+    pending: Vec<f32>,
+    /// position of pending[0]
+    pending_pos: f64,
+    /// the item pending belongs to
+    item: Option<PlaylistItem>,
+    sample_rate: i32,
+}
+
+impl Analyzer {
+    /// fft_size must be a power of two. defaults to a Hann window, a hop of
+    /// half the FFT size and linear bands.
+    pub fn new(fft_size: usize) -> Analyzer {
+        if fft_size < 2 || fft_size & (fft_size - 1) != 0 {
+            panic!("fft_size must be a power of two");
+        }
+        Analyzer {
+            fft_size: fft_size,
+            hop: fft_size / 2,
+            bands: Bands::Linear,
+            coefficients: window_coefficients(Window::Hann, fft_size),
+            pending: Vec::new(),
+            pending_pos: 0.0,
+            item: Option::None,
+            sample_rate: 0,
+        }
+    }
+
+    /// samples between the starts of consecutive frames, from 1 to
+    /// fft_size
+    pub fn set_hop(&mut self, hop: usize) {
+        self.hop = if hop == 0 {1} else if hop > self.fft_size {self.fft_size} else {hop};
+    }
+
+    pub fn set_window(&mut self, window: Window) {
+        self.coefficients = window_coefficients(window, self.fft_size);
+    }
+
+    pub fn set_bands(&mut self, bands: Bands) {
+        self.bands = bands;
+    }
+
+    /// the center frequency in Hz of each magnitude at sample_rate
+    pub fn frequencies(&self, sample_rate: i32) -> Vec<f64> {
+        let bin_hz = sample_rate as f64 / self.fft_size as f64;
+        match self.bands {
+            Bands::Linear => (0..self.fft_size / 2 + 1).map(|bin| bin as f64 * bin_hz).collect(),
+            Bands::Log { count, min_freq, max_freq } => {
+                let edges = log_edges(count, min_freq, max_freq);
+                (0..count).map(|band| (edges[band] * edges[band + 1]).sqrt()).collect()
+            },
+        }
+    }
+
+    /// add the samples of buffer and return the frames which are complete.
+    /// samples left over from a previous item are dropped, frames never
+    /// span two items.
+    pub fn push(&mut self, buffer: &DecodedBuffer) -> Vec<Frame> {
+        let item = buffer.item();
+        if self.item.as_ref() != Option::Some(&item) {
+            self.pending.clear();
+            self.item = Option::Some(item);
+        }
+        if self.pending.is_empty() {
+            self.pending_pos = buffer.pos();
+        }
+        self.sample_rate = buffer.audio_format().sample_rate;
+        self.pending.push_all(mono_samples(buffer).as_slice());
+
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while start + self.fft_size <= self.pending.len() {
+            let pos = self.pending_pos + start as f64 / self.sample_rate as f64;
+            let magnitudes = self.analyze(&self.pending[start..start + self.fft_size]);
+            frames.push(Frame { pos: pos, magnitudes: magnitudes });
+            start += self.hop;
+        }
+        if start > 0 {
+            self.pending_pos += start as f64 / self.sample_rate as f64;
+            let rest = self.pending[start..].to_vec();
+            self.pending = rest;
+        }
+        frames
+    }
+
+    /// read sink until the end of its playlist. the sink must output Flt
+    /// or Dbl samples.
+    pub fn run(&mut self, sink: &Sink) -> Spectrogram {
+        let mut frames = Vec::new();
+        loop {
+            match sink.buffer_get_blocking() {
+                Option::Some(buffer) => frames.extend(self.push(&buffer).into_iter()),
+                Option::None => break,
+            }
+        }
+        Spectrogram {
+            sample_rate: self.sample_rate,
+            hop: self.hop,
+            frequencies: self.frequencies(self.sample_rate),
+            frames: frames,
+        }
+    }
+
+    fn analyze(&self, samples: &[f32]) -> Vec<f32> {
+        let mut re: Vec<f64> = samples.iter().zip(self.coefficients.iter())
+            .map(|(&sample, &coefficient)| sample as f64 * coefficient)
+            .collect();
+        let mut im: Vec<f64> = re.iter().map(|_| 0.0).collect();
+        fft(re.as_mut_slice(), im.as_mut_slice());
+
+        // scale so that a full scale sine wave has magnitude 1
+        let window_sum = self.coefficients.iter().fold(0.0, |sum, &c| sum + c);
+        let scale = 2.0 / window_sum;
+        let bins: Vec<f64> = (0..self.fft_size / 2 + 1)
+            .map(|bin| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() * scale)
+            .collect();
+
+        match self.bands {
+            Bands::Linear => bins.iter().map(|&magnitude| magnitude as f32).collect(),
+            Bands::Log { count, min_freq, max_freq } => {
+                let bin_hz = self.sample_rate as f64 / self.fft_size as f64;
+                let edges = log_edges(count, min_freq, max_freq);
+                (0..count).map(|band| {
+                    let low = (edges[band] / bin_hz).ceil() as usize;
+                    let high = (edges[band + 1] / bin_hz).floor() as usize;
+                    if low > high || low >= bins.len() {
+                        // narrower than a bin: use the bin nearest to the center
+                        let center = ((edges[band] * edges[band + 1]).sqrt() / bin_hz).round() as usize;
+                        if center < bins.len() {bins[center] as f32} else {0.0}
+                    } else {
+                        let high = if high < bins.len() {high} else {bins.len() - 1};
+                        let sum = bins[low..high + 1].iter().fold(0.0, |sum, &m| sum + m * m);
+                        (sum / (high + 1 - low) as f64).sqrt() as f32
+                    }
+                }).collect()
+            },
+        }
+    }
+}
+
+impl Spectrogram {
+    /// a PNG image with one column per frame, low frequencies at the bottom.
+    /// magnitudes from floor_db to 0 dBFS are mapped to a black, purple,
+    /// orange, white scale. fails with InvalidInput if there are no frames
+    /// or no frequencies, since PNG images can not be empty.
+    pub fn write_png(&self, writer: &mut Writer, floor_db: f64) -> IoResult<()> {
+        if self.frames.is_empty() || self.frequencies.is_empty() {
+            return Result::Err(IoError {
+                kind: old_io::InvalidInput,
+                desc: "the spectrogram is empty",
+                detail: Option::Some(format!("{} frames, {} frequencies",
+                                             self.frames.len(), self.frequencies.len())),
+            });
+        }
+        let width = self.frames.len();
+        let height = self.frequencies.len();
+        let mut pixels: Vec<u8> = Vec::with_capacity((width * 3 + 1) * height);
+        for row in 0..height {
+            let band = height - 1 - row;
+            // filter type none
+            pixels.push(0);
+            for frame in self.frames.iter() {
+                let magnitude = if band < frame.magnitudes.len() {frame.magnitudes[band] as f64} else {0.0};
+                let db = 20.0 * magnitude.max(1e-10).log10();
+                let level = ((db - floor_db) / -floor_db).max(0.0).min(1.0);
+                pixels.push_all(&color(level));
+            }
+        }
+        write_png(writer, width as u32, height as u32, pixels.as_slice())
+    }
+}
+
+fn window_coefficients(window: Window, size: usize) -> Vec<f64> {
+    let n = (size - 1) as f64;
+    (0..size).map(|i| {
+        let x = i as f64 / n;
+        match window {
+            Window::Rectangular => 1.0,
+            Window::Hann => 0.5 - 0.5 * (2.0 * PI * x).cos(),
+            Window::Hamming => 0.54 - 0.46 * (2.0 * PI * x).cos(),
+            Window::Blackman => 0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos(),
+        }
+    }).collect()
+}
+
+/// count + 1 edges from min_freq to max_freq
+fn log_edges(count: usize, min_freq: f64, max_freq: f64) -> Vec<f64> {
+    let min_freq = min_freq.max(1.0);
+    let ratio = (max_freq / min_freq).ln();
+    (0..count + 1).map(|i| min_freq * (ratio * i as f64 / count as f64).exp()).collect()
+}
+
+/// level from 0 to 1 as RGB
+fn color(level: f64) -> [u8; 3] {
+    const STOPS: [(f64, [f64; 3]); 5] = [
+        (0.0,  [0.0, 0.0, 0.0]),
+        (0.25, [80.0, 20.0, 120.0]),
+        (0.5,  [200.0, 40.0, 80.0]),
+        (0.75, [250.0, 150.0, 30.0]),
+        (1.0,  [255.0, 255.0, 255.0]),
+    ];
+    let mut index = 1;
+    while index < STOPS.len() - 1 && level > STOPS[index].0 {
+        index += 1;
+    }
+    let (low_level, low) = STOPS[index - 1];
+    let (high_level, high) = STOPS[index];
+    let t = (level - low_level) / (high_level - low_level);
+    let mix = |channel: usize| (low[channel] + (high[channel] - low[channel]) * t).round() as u8;
+    [mix(0), mix(1), mix(2)]
+}
+
+/// an 8 bit RGB PNG. scanlines are each prefixed with their filter type.
+/// the zlib stream uses uncompressed deflate blocks, so no compressor is
+/// needed.
+fn write_png(writer: &mut Writer, width: u32, height: u32, scanlines: &[u8]) -> IoResult<()> {
+    try!(writer.write_all(b"\x89PNG\r\n\x1a\n"));
+
+    let mut header: Vec<u8> = Vec::new();
+    try!(header.write_be_u32(width));
+    try!(header.write_be_u32(height));
+    // bit depth 8, color type RGB, deflate, adaptive filtering, no interlace
+    try!(header.write_all(&[8, 2, 0, 0, 0]));
+    try!(write_chunk(writer, b"IHDR", header.as_slice()));
+
+    let mut zlib: Vec<u8> = vec![0x78, 0x01];
+    let mut blocks = scanlines.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        try!(zlib.write_all(&[1, 0, 0, 0xff, 0xff]));
+    }
+    loop {
+        let block = match blocks.next() {
+            Option::Some(block) => block,
+            Option::None => break,
+        };
+        let last = blocks.peek().is_none();
+        try!(zlib.write_u8(if last {1} else {0}));
+        try!(zlib.write_le_u16(block.len() as u16));
+        try!(zlib.write_le_u16(!(block.len() as u16)));
+        try!(zlib.write_all(block));
+    }
+    try!(zlib.write_be_u32(adler32(scanlines)));
+    try!(write_chunk(writer, b"IDAT", zlib.as_slice()));
+
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk(writer: &mut Writer, kind: &[u8], data: &[u8]) -> IoResult<()> {
+    try!(writer.write_be_u32(data.len() as u32));
+    try!(writer.write_all(kind));
+    try!(writer.write_all(data));
+    let crc = !crc32_update(crc32_update(0xffffffff, kind), data);
+    writer.write_be_u32(crc)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data.iter() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb88320} else {crc >> 1};
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data.iter() {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32_update, Frame, Spectrogram};
+
+    #[test]
+    fn crc32_reference_values() {
+        let crc32 = |data: &[u8]| !crc32_update(0xffffffff, data);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+        // split updates give the same result
+        assert_eq!(!crc32_update(crc32_update(0xffffffff, b"1234"), b"56789"), 0xcbf43926);
+        // the CRC of an IEND chunk as found in every PNG file
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+
+    #[test]
+    fn adler32_reference_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"a"), 0x00620062);
+        assert_eq!(adler32(b"abc"), 0x024d0127);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // long enough for both sums to wrap around the modulus
+        let bytes: Vec<u8> = (0..100000).map(|_| 0xff).collect();
+        assert_eq!(adler32(bytes.as_slice()), 0x149a302c);
+    }
+
+    #[test]
+    fn empty_spectrogram_is_an_error() {
+        let spectrogram = Spectrogram {
+            sample_rate: 44100,
+            hop: 512,
+            frequencies: vec![0.0, 22050.0],
+            frames: Vec::new(),
+        };
+        let mut out: Vec<u8> = Vec::new();
+        assert!(spectrogram.write_png(&mut out, -90.0).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn png_layout() {
+        let spectrogram = Spectrogram {
+            sample_rate: 44100,
+            hop: 512,
+            frequencies: vec![0.0, 22050.0],
+            frames: vec![Frame { pos: 0.0, magnitudes: vec![1.0, 0.0] }],
+        };
+        let mut out: Vec<u8> = Vec::new();
+        spectrogram.write_png(&mut out, -90.0).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&out[12..16], b"IHDR");
+        // width 1, height 2
+        assert_eq!(&out[16..24], &[0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(&out[out.len() - 12..], &[0, 0, 0, 0, 73, 69, 78, 68, 0xae, 0x42, 0x60, 0x82]);
+    }
+}
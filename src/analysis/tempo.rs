@@ -0,0 +1,377 @@
+//! tempo and beat grid estimation.
+//!
+//! An Analyzer mixes Flt or Dbl buffers down to mono and computes an onset
+//! envelope, the spectral flux of overlapping FFT frames: how much energy
+//! each frame adds compared to the one before it. The tempo is the lag with
+//! the strongest autocorrelation of the envelope within the BPM range. Lags
+//! are whole frames, so the period is then refined together with the beat
+//! grid: the period near that lag and the phase which line up with the most
+//! onset strength per beat. The grid has a constant tempo, so it drifts on
+//! live recordings whose tempo changes.
+//!
+//! Feed an Analyzer the buffers of one playlist item; positions are taken
+//! from the first buffer.
+
+use std::f64::consts::PI;
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::{fft, mono_samples};
+use super::super::{AudioFormat, ChannelLayout, DecodedBuffer, File, Playlist, SampleFormat, SampleType, Sink};
+use super::super::tags::Tags;
+
+const FRAME_SIZE: usize = 1024;
+const HOP: usize = 512;
+/// the grid search tries periods this many frames apart, PERIOD_STEPS
+/// either side of the autocorrelation peak
+const PERIOD_STEP: f64 = 0.02;
+const PERIOD_STEPS: isize = 25;
+
+/// the result of an Analyzer
+#[derive(Clone, Debug)]
+pub struct Tempo {
+    pub bpm: f64,
+    /// how much the chosen period stands out from the other periods in the
+    /// range, from 0 to 1. low values mean there is no clear beat.
+    pub confidence: f64,
+    /// seconds into the item of every beat
+    pub beats: Vec<f64>,
+}
+
+impl Tempo {
+    /// set the BPM tag of file to the rounded tempo with Tags::write.
+    /// returns whether anything changed. call File::save to write to disk.
+    pub fn write_tag(&self, file: &File) -> Result<bool, i32> {
+        let mut tags = Tags::read(file);
+        tags.bpm = Option::Some(self.bpm.round() as u32);
+        tags.write(file)
+    }
+}
+
+pub struct Analyzer {
+    min_bpm: f64,
+    max_bpm: f64,
+    coefficients: Vec<f64>,
+    /// mono samples not yet consumed by a frame
+    pending: Vec<f32>,
+    /// log magnitudes of the last frame
+    previous: Vec<f64>,
+    /// onset strength of every frame
+    envelope: Vec<f64>,
+    /// position of the first buffer
+    start: Option<f64>,
+    sample_rate: i32,
+}
+
+impl Analyzer {
+    /// looks for tempos from 60 to 180 BPM
+    pub fn new() -> Analyzer {
+        Analyzer {
+            min_bpm: 60.0,
+            max_bpm: 180.0,
+            coefficients: (0..FRAME_SIZE)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / (FRAME_SIZE - 1) as f64).cos())
+                .collect(),
+            pending: Vec::new(),
+            previous: Vec::new(),
+            envelope: Vec::new(),
+            start: Option::None,
+            sample_rate: 0,
+        }
+    }
+
+    /// the range of tempos to consider. a range of less than an octave
+    /// avoids answering half or double the tempo.
+    pub fn set_range(&mut self, min_bpm: f64, max_bpm: f64) {
+        self.min_bpm = min_bpm;
+        self.max_bpm = max_bpm;
+    }
+
+    pub fn push(&mut self, buffer: &DecodedBuffer) {
+        self.push_samples(buffer.pos(), buffer.audio_format().sample_rate,
+                          mono_samples(buffer).as_slice());
+    }
+
+    /// mono samples which start at pos seconds into the item
+    fn push_samples(&mut self, pos: f64, sample_rate: i32, samples: &[f32]) {
+        if self.start.is_none() {
+            self.start = Option::Some(pos);
+        }
+        self.sample_rate = sample_rate;
+        self.pending.push_all(samples);
+
+        let mut start = 0;
+        while start + FRAME_SIZE <= self.pending.len() {
+            let flux = self.flux(start);
+            self.envelope.push(flux);
+            start += HOP;
+        }
+        if start > 0 {
+            let rest = self.pending[start..].to_vec();
+            self.pending = rest;
+        }
+    }
+
+    /// read sink until the end of its playlist and estimate the tempo. the
+    /// sink must output Flt or Dbl samples.
+    pub fn run(&mut self, sink: &Sink) -> Option<Tempo> {
+        loop {
+            match sink.buffer_get_blocking() {
+                Option::Some(buffer) => self.push(&buffer),
+                Option::None => break,
+            }
+        }
+        self.tempo()
+    }
+
+    /// the tempo of what was pushed so far. None if there is not enough
+    /// audio for a few beats at the slowest tempo.
+    pub fn tempo(&self) -> Option<Tempo> {
+        let frame_rate = self.sample_rate as f64 / HOP as f64;
+        let min_lag = (60.0 * frame_rate / self.max_bpm).floor() as usize;
+        let max_lag = (60.0 * frame_rate / self.min_bpm).ceil() as usize;
+        if min_lag < 1 || max_lag < min_lag || self.envelope.len() < max_lag * 4 {
+            return Option::None;
+        }
+
+        // remove the mean so that the autocorrelation measures periodicity
+        // rather than loudness
+        let mean = self.envelope.iter().fold(0.0, |sum, &x| sum + x) / self.envelope.len() as f64;
+        let envelope: Vec<f64> = self.envelope.iter().map(|&x| x - mean).collect();
+        let autocorrelation = |lag: usize| -> f64 {
+            let count = envelope.len() - lag;
+            (0..count).fold(0.0, |sum, i| sum + envelope[i] * envelope[i + lag]) / count as f64
+        };
+        let energy = autocorrelation(0);
+        if energy <= 0.0 {
+            return Option::None;
+        }
+        // one lag either side of the range, for interpolation
+        let lags: Vec<f64> = (min_lag - 1..max_lag + 2).map(|lag| autocorrelation(lag)).collect();
+        let mut best = 1;
+        for index in 1..lags.len() - 1 {
+            if lags[index] > lags[best] {
+                best = index;
+            }
+        }
+        // parabolic interpolation around the peak
+        let (left, peak, right) = (lags[best - 1], lags[best], lags[best + 1]);
+        let denominator = left - 2.0 * peak + right;
+        let shift = if denominator != 0.0 {0.5 * (left - right) / denominator} else {0.0};
+        let period = (min_lag - 1 + best) as f64 + shift.max(-0.5).min(0.5);
+
+        let in_range = &lags[1..lags.len() - 1];
+        let range_mean = in_range.iter().fold(0.0, |sum, &x| sum + x) / in_range.len() as f64;
+        let confidence = ((peak - range_mean) / (energy - range_mean)).max(0.0).min(1.0);
+
+        // the grid which collects the most onset strength per beat
+        let mut best_period = period;
+        let mut best_phase = 0;
+        let mut best_strength = Float::neg_infinity();
+        for step in -PERIOD_STEPS..PERIOD_STEPS + 1 {
+            let candidate = period + step as f64 * PERIOD_STEP;
+            for phase in 0..candidate.ceil() as usize {
+                let strength = self.grid_strength(candidate, phase);
+                if strength > best_strength {
+                    best_strength = strength;
+                    best_period = candidate;
+                    best_phase = phase;
+                }
+            }
+        }
+        let period = best_period;
+        let start = self.start.unwrap_or(0.0);
+        // a flux frame marks the onset at its center
+        let center = FRAME_SIZE as f64 / 2.0 / self.sample_rate as f64;
+        let mut beats = Vec::new();
+        let mut position = best_phase as f64;
+        while (position.round() as usize) < self.envelope.len() {
+            beats.push(start + position / frame_rate + center);
+            position += period;
+        }
+
+        Option::Some(Tempo {
+            bpm: 60.0 * frame_rate / period,
+            confidence: confidence,
+            beats: beats,
+        })
+    }
+
+    /// mean onset strength of the frames at phase, phase + period, ...
+    fn grid_strength(&self, period: f64, phase: usize) -> f64 {
+        let mut strength = 0.0;
+        let mut count = 0;
+        let mut position = phase as f64;
+        while (position.round() as usize) < self.envelope.len() {
+            strength += self.envelope[position.round() as usize];
+            count += 1;
+            position += period;
+        }
+        if count > 0 {strength / count as f64} else {0.0}
+    }
+
+    /// half wave rectified increase in log magnitude of the frame at start
+    fn flux(&mut self, start: usize) -> f64 {
+        let mut re: Vec<f64> = self.pending[start..start + FRAME_SIZE].iter()
+            .zip(self.coefficients.iter())
+            .map(|(&sample, &coefficient)| sample as f64 * coefficient)
+            .collect();
+        let mut im: Vec<f64> = re.iter().map(|_| 0.0).collect();
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        let magnitudes: Vec<f64> = (0..FRAME_SIZE / 2 + 1)
+            .map(|bin| (1.0 + 1000.0 * (re[bin] * re[bin] + im[bin] * im[bin]).sqrt()).ln())
+            .collect();
+        let flux = if self.previous.is_empty() {
+            0.0
+        } else {
+            magnitudes.iter().zip(self.previous.iter())
+                .fold(0.0, |sum, (&current, &previous)| sum + (current - previous).max(0.0))
+        };
+        self.previous = magnitudes;
+        flux
+    }
+}
+
+/// decode file through a Sink and Playlist of their own and estimate its
+/// tempo. use Tempo::write_tag to store it. returns the error code of
+/// attaching the sink.
+pub fn detect(file: &File) -> Result<Option<Tempo>, i32> {
+    let playlist = Playlist::new();
+    let sink = Sink::new();
+    sink.set_audio_format(AudioFormat {
+        sample_rate: file.audio_format().sample_rate,
+        channel_layout: ChannelLayout::LayoutMono,
+        sample_fmt: SampleFormat { sample_type: SampleType::Flt, planar: false },
+    });
+    playlist.append(file, 1.0, 1.0);
+    try!(sink.attach(&playlist));
+    let mut analyzer = Analyzer::new();
+    let tempo = analyzer.run(&sink);
+    sink.detach();
+    playlist.clear();
+    Result::Ok(tempo)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::{Float, Int};
+
+    use super::{Analyzer, Tempo, HOP};
+
+    const RATE: i32 = 11025;
+
+    /// 30 seconds of short clicks, every accent_every-th one at full level
+    /// and the others at weak, starting offset seconds in
+    fn clicks(bpm: f64, accent_every: usize, weak: f32, offset: f64) -> Vec<f32> {
+        let len = 30 * RATE as usize;
+        let mut samples: Vec<f32> = (0..len).map(|_| 0.0).collect();
+        let mut beat = 0;
+        loop {
+            let start = ((offset + beat as f64 * 60.0 / bpm) * RATE as f64).round() as usize;
+            if start >= len {
+                break;
+            }
+            let level = if beat % accent_every == 0 {1.0} else {weak};
+            for i in 0..64 {
+                if start + i < len {
+                    let sign = if i % 2 == 0 {1.0} else {-1.0};
+                    samples[start + i] += sign * level * (-(i as f32) / 8.0).exp();
+                }
+            }
+            beat += 1;
+        }
+        samples
+    }
+
+    fn analyze(samples: &[f32], min_bpm: f64, max_bpm: f64) -> Tempo {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_range(min_bpm, max_bpm);
+        // buffers which do not line up with the frames
+        for chunk in samples.chunks(3000) {
+            analyzer.push_samples(0.0, RATE, chunk);
+        }
+        analyzer.tempo().unwrap()
+    }
+
+    #[test]
+    fn flux_marks_onsets() {
+        let mut analyzer = Analyzer::new();
+        let silence: Vec<f32> = (0..4096).map(|_| 0.0).collect();
+        analyzer.push_samples(0.0, RATE, silence.as_slice());
+        assert!(analyzer.envelope.iter().all(|&flux| flux == 0.0));
+
+        // a click half a second in, at sample 5512, is in frames 9 and 10
+        let click = clicks(60.0, 1, 1.0, 0.5);
+        let mut analyzer = Analyzer::new();
+        analyzer.push_samples(0.0, RATE, &click[..8192]);
+        assert_eq!(analyzer.envelope.len(), 15);
+        for (frame, &flux) in analyzer.envelope.iter().enumerate() {
+            if frame == 9 || frame == 10 {
+                assert!(flux > 100.0);
+            } else {
+                assert!(flux < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn autocorrelation_of_a_periodic_envelope() {
+        let frame_rate = RATE as f64 / HOP as f64;
+        let mut analyzer = Analyzer::new();
+        analyzer.set_range(90.0, 180.0);
+        analyzer.sample_rate = RATE;
+        analyzer.envelope = (0..400).map(|frame| if frame % 10 == 3 {1.0} else {0.0}).collect();
+        let tempo = analyzer.tempo().unwrap();
+        assert!((tempo.bpm - 60.0 * frame_rate / 10.0).abs() < 0.1);
+        assert!(tempo.confidence > 0.9);
+
+        // the same envelope without a beat
+        let mut seed = 1u32;
+        analyzer.envelope = (0..400).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as f64 / 65536.0
+        }).collect();
+        assert!(analyzer.tempo().unwrap().confidence < 0.3);
+    }
+
+    #[test]
+    fn known_tempos() {
+        for &bpm in [120.0, 133.0, 165.0].iter() {
+            let tempo = analyze(clicks(bpm, 1, 1.0, 0.05).as_slice(), 90.0, 180.0);
+            assert!((tempo.bpm - bpm).abs() < 0.5);
+            assert!(tempo.confidence > 0.5);
+        }
+        // the default range, which only has one octave of these
+        for &bpm in [60.0, 90.0].iter() {
+            let tempo = analyze(clicks(bpm, 1, 1.0, 0.1).as_slice(), 60.0, 180.0);
+            assert!((tempo.bpm - bpm).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn beat_grid_follows_the_clicks() {
+        let tempo = analyze(clicks(120.0, 1, 1.0, 0.25).as_slice(), 90.0, 180.0);
+        assert!((tempo.beats[0] - 0.25).abs() < 0.03);
+        for pair in tempo.beats.windows(2) {
+            assert!((pair[1] - pair[0] - 0.5).abs() < 0.01);
+        }
+        assert!((tempo.beats.len() as isize - 60).abs() <= 1);
+    }
+
+    #[test]
+    fn half_tempo_when_the_tempo_is_above_the_range() {
+        let tempo = analyze(clicks(240.0, 1, 1.0, 0.3).as_slice(), 90.0, 180.0);
+        assert!((tempo.bpm - 120.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn accents_or_every_click_depending_on_the_range() {
+        // accents at 70 BPM with weaker clicks between them
+        let samples = clicks(140.0, 2, 0.3, 0.2);
+        let tempo = analyze(samples.as_slice(), 100.0, 190.0);
+        assert!((tempo.bpm - 140.0).abs() < 0.5);
+        let tempo = analyze(samples.as_slice(), 50.0, 95.0);
+        assert!((tempo.bpm - 70.0).abs() < 0.5);
+    }
+}
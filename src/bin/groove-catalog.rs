@@ -0,0 +1,107 @@
+#![feature(os)]
+#![feature(io)]
+#![feature(core)]
+#![feature(path)]
+extern crate groove;
+
+use std::option::Option;
+use std::result::Result;
+use groove::catalog;
+
+// export library metadata to JSON Lines or CSV, and import an edited CSV
+
+fn main() {
+    let mut stderr = std::old_io::stderr();
+    let args = std::os::args();
+    let exe = args[0].as_slice();
+
+    if args.len() < 3 {
+        print_usage(&mut stderr, exe);
+        std::os::set_exit_status(1);
+        return;
+    }
+    groove::set_logging(groove::Log::Error);
+
+    let command = args[1].as_slice();
+    if command == "export" {
+        let mut format = catalog::Format::JsonLines;
+        let mut paths = Vec::new();
+        for arg in args[2..].iter() {
+            if arg.as_slice() == "--csv" {
+                format = catalog::Format::Csv;
+            } else if arg.as_slice() == "--jsonl" {
+                format = catalog::Format::JsonLines;
+            } else {
+                paths.push(Path::new(arg.as_slice()));
+            }
+        }
+        let records = match catalog::scan(paths.as_slice()) {
+            Result::Ok(records) => records,
+            Result::Err(err) => {
+                let _ = writeln!(&mut stderr, "Error scanning: {}", err);
+                std::os::set_exit_status(1);
+                return;
+            },
+        };
+        let mut stdout = std::old_io::stdout();
+        match catalog::export(records.as_slice(), format, &mut stdout) {
+            Result::Ok(()) => {},
+            Result::Err(err) => {
+                let _ = writeln!(&mut stderr, "Error writing: {}", err);
+                std::os::set_exit_status(1);
+            },
+        }
+    } else if command == "import" {
+        let mut dry_run = false;
+        let mut csv_path = Option::None;
+        for arg in args[2..].iter() {
+            if arg.as_slice() == "--dry-run" {
+                dry_run = true;
+            } else {
+                csv_path = Option::Some(Path::new(arg.as_slice()));
+            }
+        }
+        let csv_path = match csv_path {
+            Option::Some(path) => path,
+            Option::None => {
+                print_usage(&mut stderr, exe);
+                std::os::set_exit_status(1);
+                return;
+            },
+        };
+        let mut csv_file = match std::old_io::File::open(&csv_path) {
+            Result::Ok(file) => file,
+            Result::Err(err) => {
+                let _ = writeln!(&mut stderr, "Error opening {}: {}", csv_path.display(), err);
+                std::os::set_exit_status(1);
+                return;
+            },
+        };
+        let report = match catalog::import_csv(&mut csv_file, dry_run) {
+            Result::Ok(report) => report,
+            Result::Err(err) => {
+                let _ = writeln!(&mut stderr, "Error reading {}: {}", csv_path.display(), err);
+                std::os::set_exit_status(1);
+                return;
+            },
+        };
+        for path in report.changed.iter() {
+            println!("{} {}", if dry_run {"would change"} else {"changed"}, path.display());
+        }
+        println!("{} unchanged", report.unchanged);
+        for &(ref path, ref msg) in report.errors.iter() {
+            let _ = writeln!(&mut stderr, "{}: {}", path.display(), msg);
+        }
+        if !report.errors.is_empty() {
+            std::os::set_exit_status(1);
+        }
+    } else {
+        print_usage(&mut stderr, exe);
+        std::os::set_exit_status(1);
+    }
+}
+
+fn print_usage(stderr: &mut std::old_io::LineBufferedWriter<std::old_io::stdio::StdWriter>, exe: &str) {
+    let _ = write!(stderr, "Usage: {} export [--jsonl|--csv] dir|file...\n", exe);
+    let _ = write!(stderr, "       {} import [--dry-run] edited.csv\n", exe);
+}
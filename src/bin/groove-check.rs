@@ -0,0 +1,93 @@
+#![feature(os)]
+#![feature(io)]
+#![feature(core)]
+#![feature(path)]
+extern crate groove;
+
+use std::option::Option;
+use std::result::Result;
+use groove::check;
+
+// decode every audio file completely and report problems, one JSON object
+// per line with --json. exits with status 1 if any file has a problem.
+
+fn main() {
+    let mut stderr = std::old_io::stderr();
+    let args = std::os::args();
+    let exe = args[0].as_slice();
+
+    let mut json = false;
+    let mut thresholds = check::Thresholds::default();
+    let mut inputs = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        if arg == "--json" {
+            json = true;
+        } else if arg == "--duration-tolerance" || arg == "--dc-offset" {
+            let value = if i + 1 < args.len() { args[i + 1].parse::<f64>().ok() } else { Option::None };
+            let value = match value {
+                Option::Some(value) => value,
+                Option::None => {
+                    print_usage(&mut stderr, exe);
+                    std::os::set_exit_status(1);
+                    return;
+                },
+            };
+            if arg == "--duration-tolerance" {
+                thresholds.duration_tolerance = value;
+            } else {
+                thresholds.max_dc_offset = value;
+            }
+            i += 1;
+        } else {
+            inputs.push(Path::new(arg));
+        }
+        i += 1;
+    }
+    if inputs.is_empty() {
+        print_usage(&mut stderr, exe);
+        std::os::set_exit_status(1);
+        return;
+    }
+    groove::set_logging(groove::Log::Quiet);
+
+    let mut paths = Vec::new();
+    for input in inputs.into_iter() {
+        match groove::find_audio_files(&[input.clone()]) {
+            Result::Ok(found) => paths.extend(found.into_iter().map(|(path, _)| path)),
+            Result::Err(err) => {
+                let _ = writeln!(&mut stderr, "Error reading {}: {}", input.display(), err);
+                std::os::set_exit_status(1);
+            },
+        }
+    }
+
+    let mut failed = 0;
+    for path in paths.iter() {
+        let report = check::check(path, &thresholds);
+        if !report.is_ok() {
+            failed += 1;
+        }
+        if json {
+            println!("{}", report.to_json());
+        } else if report.is_ok() {
+            println!("{}: ok", path.display());
+        } else {
+            for problem in report.problems.iter() {
+                println!("{}: {:?}", path.display(), problem);
+            }
+            for message in report.decode_errors.iter() {
+                println!("{}: {}", path.display(), message);
+            }
+        }
+    }
+    if failed > 0 {
+        let _ = writeln!(&mut stderr, "{} of {} files have problems", failed, paths.len());
+        std::os::set_exit_status(1);
+    }
+}
+
+fn print_usage(stderr: &mut std::old_io::LineBufferedWriter<std::old_io::stdio::StdWriter>, exe: &str) {
+    let _ = write!(stderr, "Usage: {} [--json] [--duration-tolerance seconds] [--dc-offset level] dir|file...\n", exe);
+}
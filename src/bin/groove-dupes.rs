@@ -0,0 +1,96 @@
+#![feature(os)]
+#![feature(io)]
+#![feature(core)]
+#![feature(path)]
+extern crate groove;
+
+use std::option::Option;
+use std::result::Result;
+use groove::dupes;
+use groove::fingerprint::FingerprintError;
+
+// fingerprint every file in the given directories and print each group of
+// copies of the same recording, with what helps to choose one to keep.
+
+fn main() {
+    let mut stderr = std::old_io::stderr();
+    let args = std::os::args();
+    let exe = args[0].as_slice();
+
+    let mut options = dupes::Options::default();
+    options.threads = std::os::num_cpus();
+    let mut inputs = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        if arg == "--threads" || arg == "--min-score" || arg == "--duration-tolerance" {
+            let value = if i + 1 < args.len() { args[i + 1].parse::<f64>().ok() } else { Option::None };
+            let value = match value {
+                Option::Some(value) => value,
+                Option::None => {
+                    print_usage(&mut stderr, exe);
+                    std::os::set_exit_status(1);
+                    return;
+                },
+            };
+            if arg == "--threads" {
+                options.threads = value as usize;
+            } else if arg == "--min-score" {
+                options.min_score = value;
+            } else {
+                options.duration_tolerance = value;
+            }
+            i += 1;
+        } else {
+            inputs.push(Path::new(arg));
+        }
+        i += 1;
+    }
+    if inputs.is_empty() {
+        print_usage(&mut stderr, exe);
+        std::os::set_exit_status(1);
+        return;
+    }
+    groove::set_logging(groove::Log::Quiet);
+
+    let report = match dupes::find(inputs.as_slice(), &options) {
+        Result::Ok(report) => report,
+        Result::Err(err) => {
+            let _ = writeln!(&mut stderr, "Error: {}", err);
+            std::os::set_exit_status(1);
+            return;
+        },
+    };
+    for err in report.errors.iter() {
+        let path = match *err {
+            FingerprintError::Open(ref path) => path,
+            FingerprintError::Groove(ref path, _) => path,
+            FingerprintError::NoFingerprint(ref path) => path,
+        };
+        let _ = writeln!(&mut stderr, "skipped {}: {:?}", path.display(), err);
+    }
+
+    for group in report.groups.iter() {
+        println!("{} copies, score {:.3}", group.copies.len(), group.min_score);
+        for copy in group.copies.iter() {
+            let sample_format = match copy.audio_format {
+                Option::Some(format) => format!("{} Hz {} channels", format.sample_rate,
+                                                format.channel_layout.count()),
+                Option::None => "unknown".to_string(),
+            };
+            let bit_rate = match copy.bit_rate {
+                Option::Some(bit_rate) => format!("{} kbps", bit_rate / 1000),
+                Option::None => "unknown".to_string(),
+            };
+            println!("  {}", copy.path.display());
+            println!("    {} {:.1} s, {}, {}, tags {:.0}% complete, missing: {}", copy.format, copy.duration,
+                     sample_format, bit_rate, copy.tag_completeness() * 100.0,
+                     copy.missing_tags.connect(", "));
+        }
+    }
+    println!("{} files scanned, {} groups of duplicates", report.scanned, report.groups.len());
+}
+
+fn print_usage(stderr: &mut std::old_io::LineBufferedWriter<std::old_io::stdio::StdWriter>, exe: &str) {
+    let _ = write!(stderr, "Usage: {} [--threads n] [--min-score score] [--duration-tolerance seconds] dir|file...\n", exe);
+}
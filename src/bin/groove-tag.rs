@@ -0,0 +1,601 @@
+#![feature(os)]
+#![feature(io)]
+#![feature(core)]
+#![feature(collections)]
+#![feature(path)]
+extern crate groove;
+
+use std::collections::HashSet;
+use std::old_io::fs;
+use std::old_io::fs::PathExtensions;
+use std::option::Option;
+use std::result::Result;
+
+// batch tag editor. every change is planned in memory first; files are only
+// written if every file could be changed, and if saving or renaming any file
+// fails the files saved before it get their old tags back.
+
+enum Op {
+    Set(String, String),
+    Delete(String),
+    Rename(String, String),
+    Copy(String, String),
+    FromFilename(Vec<Token>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    Field(String),
+}
+
+struct Plan {
+    path: Path,
+    file: groove::File,
+    before: Vec<(String, String)>,
+    new_path: Option<Path>,
+}
+
+fn main() {
+    let mut stderr = std::old_io::stderr();
+    let args = std::os::args();
+    let exe = args[0].as_slice();
+
+    let mut ops = Vec::new();
+    let mut to_filename = Option::None;
+    let mut dry_run = false;
+    let mut keep_backup = false;
+    let mut inputs = Vec::new();
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        if arg == "--dry-run" {
+            dry_run = true;
+        } else if arg == "--backup" {
+            keep_backup = true;
+        } else if arg.starts_with("--") {
+            if i + 1 >= args.len() {
+                print_usage(&mut stderr, exe);
+                std::os::set_exit_status(1);
+                return;
+            }
+            i += 1;
+            let param = args[i].as_slice();
+            let op = match &arg[2..] {
+                "set" => split_pair(param).map(|(k, v)| Op::Set(k, v)),
+                "delete" => Result::Ok(Op::Delete(param.to_string())),
+                "rename" => split_pair(param).map(|(k, v)| Op::Rename(k, v)),
+                "copy" => split_pair(param).map(|(k, v)| Op::Copy(k, v)),
+                "from-filename" => parse_pattern(param).map(Op::FromFilename),
+                "to-filename" => {
+                    match parse_pattern(param) {
+                        Result::Ok(tokens) => to_filename = Option::Some(tokens),
+                        Result::Err(msg) => {
+                            let _ = writeln!(&mut stderr, "{}", msg);
+                            std::os::set_exit_status(1);
+                            return;
+                        },
+                    }
+                    i += 1;
+                    continue;
+                },
+                _ => {
+                    print_usage(&mut stderr, exe);
+                    std::os::set_exit_status(1);
+                    return;
+                },
+            };
+            match op {
+                Result::Ok(op) => ops.push(op),
+                Result::Err(msg) => {
+                    let _ = writeln!(&mut stderr, "{}", msg);
+                    std::os::set_exit_status(1);
+                    return;
+                },
+            }
+        } else {
+            inputs.push(arg.to_string());
+        }
+        i += 1;
+    }
+    if inputs.is_empty() || (ops.is_empty() && to_filename.is_none()) {
+        print_usage(&mut stderr, exe);
+        std::os::set_exit_status(1);
+        return;
+    }
+
+    groove::set_logging(groove::Log::Error);
+
+    let paths = match expand_inputs(inputs.as_slice()) {
+        Result::Ok(paths) => paths,
+        Result::Err(msg) => {
+            let _ = writeln!(&mut stderr, "{}", msg);
+            std::os::set_exit_status(1);
+            return;
+        },
+    };
+
+    let mut plans = Vec::new();
+    for &(ref path, explicit) in paths.iter() {
+        match plan(path, explicit, ops.as_slice(), &to_filename) {
+            Result::Ok(Option::Some(plan)) => plans.push(plan),
+            Result::Ok(Option::None) => {},
+            Result::Err(msg) => {
+                let _ = writeln!(&mut stderr, "{}: {}", path.display(), msg);
+                let _ = writeln!(&mut stderr, "no files were changed");
+                std::os::set_exit_status(1);
+                return;
+            },
+        }
+    }
+
+    // two files renamed to the same name would overwrite each other
+    let mut targets = HashSet::new();
+    for plan in plans.iter() {
+        match plan.new_path {
+            Option::Some(ref new_path) => if !targets.insert(new_path.clone()) {
+                let _ = writeln!(&mut stderr, "{}: more than one file would be renamed to {}",
+                                 plan.path.display(), new_path.display());
+                let _ = writeln!(&mut stderr, "no files were changed");
+                std::os::set_exit_status(1);
+                return;
+            },
+            Option::None => {},
+        }
+    }
+
+    for plan in plans.iter() {
+        print_diff(plan);
+    }
+    if dry_run {
+        return;
+    }
+
+    match commit(plans.as_slice(), keep_backup) {
+        Result::Ok(()) => {},
+        Result::Err(msg) => {
+            let _ = writeln!(&mut stderr, "{}", msg);
+            std::os::set_exit_status(1);
+        },
+    }
+}
+
+/// open a file and apply every operation to it in memory.
+/// returns None for files found in a directory which are not audio files.
+fn plan(path: &Path, explicit: bool, ops: &[Op],
+        to_filename: &Option<Vec<Token>>) -> Result<Option<Plan>, String>
+{
+    let file = match groove::File::open(path) {
+        Option::Some(file) => file,
+        Option::None if explicit => return Result::Err("unable to open".to_string()),
+        Option::None => return Result::Ok(Option::None),
+    };
+    let before = snapshot(&file);
+    for op in ops.iter() {
+        try!(apply(&file, path, op));
+    }
+    let new_path = match *to_filename {
+        Option::Some(ref tokens) => {
+            let new_path = try!(format_filename(&file, path, tokens.as_slice()));
+            if new_path == *path {
+                Option::None
+            } else if new_path.exists() {
+                return Result::Err(format!("{} already exists", new_path.display()));
+            } else {
+                Option::Some(new_path)
+            }
+        },
+        Option::None => Option::None,
+    };
+    Result::Ok(Option::Some(Plan { path: path.clone(), file: file, before: before, new_path: new_path }))
+}
+
+fn apply(file: &groove::File, path: &Path, op: &Op) -> Result<(), String> {
+    let result = match *op {
+        Op::Set(ref key, ref value) => file.metadata_set(key.as_slice(), value.as_slice(), false),
+        Op::Delete(ref key) => file.metadata_delete(key.as_slice(), false),
+        Op::Rename(ref from, ref to) => {
+            let values = values_of(file, from.as_slice());
+            if values.is_empty() {
+                Result::Ok(())
+            } else {
+                let values: Vec<&str> = values.iter().map(|v| v.as_slice()).collect();
+                file.metadata_delete(from.as_slice(), false)
+                    .and_then(|_| file.metadata_set_all(to.as_slice(), values.as_slice(), false))
+            }
+        },
+        Op::Copy(ref from, ref to) => {
+            let values = values_of(file, from.as_slice());
+            if values.is_empty() {
+                Result::Ok(())
+            } else {
+                let values: Vec<&str> = values.iter().map(|v| v.as_slice()).collect();
+                file.metadata_set_all(to.as_slice(), values.as_slice(), false)
+            }
+        },
+        Op::FromFilename(ref tokens) => {
+            let stem = path.filestem_str().unwrap_or("");
+            let fields = try!(match_pattern(tokens.as_slice(), stem).ok_or_else(|| {
+                format!("file name does not match the pattern")
+            }));
+            let mut result = Result::Ok(());
+            for &(ref key, ref value) in fields.iter() {
+                result = result.and_then(|_| file.metadata_set(key.as_slice(), value.as_slice(), false));
+            }
+            result
+        },
+    };
+    result.map_err(|err_code| format!("unable to edit metadata: error {}", err_code))
+}
+
+fn values_of(file: &groove::File, key: &str) -> Vec<String> {
+    file.metadata_get_all(key, false).iter().map(|tag| tag.value_lossy()).collect()
+}
+
+fn snapshot(file: &groove::File) -> Vec<(String, String)> {
+    file.metadata_iter().map(|tag| (tag.key_lossy(), tag.value_lossy())).collect()
+}
+
+fn print_diff(plan: &Plan) {
+    let after = snapshot(&plan.file);
+    if after == plan.before && plan.new_path.is_none() {
+        return;
+    }
+    println!("{}", plan.path.display());
+    for entry in plan.before.iter() {
+        if !after.contains(entry) {
+            println!("  - {}={}", entry.0, entry.1);
+        }
+    }
+    for entry in after.iter() {
+        if !plan.before.contains(entry) {
+            println!("  + {}={}", entry.0, entry.1);
+        }
+    }
+    match plan.new_path {
+        Option::Some(ref new_path) => println!("  -> {}", new_path.display()),
+        Option::None => {},
+    }
+}
+
+/// save every changed file, then rename files. if anything fails, the
+/// renames are undone and the saved files get their old tags back.
+fn commit(plans: &[Plan], keep_backup: bool) -> Result<(), String> {
+    let backup = if keep_backup { groove::Backup::Keep } else { groove::Backup::None };
+    let mut saved: Vec<&Plan> = Vec::new();
+    for plan in plans.iter() {
+        if !plan.file.is_dirty() {
+            continue;
+        }
+        match plan.file.save_atomic(backup) {
+            Result::Ok(_) => saved.push(plan),
+            Result::Err(err) => {
+                let msg = format!("{}: unable to save: {:?}", plan.path.display(), err);
+                return Result::Err(roll_back(msg, saved.as_slice(), keep_backup));
+            },
+        }
+    }
+
+    let mut renamed: Vec<(&Path, &Path)> = Vec::new();
+    for plan in plans.iter() {
+        match plan.new_path {
+            Option::Some(ref new_path) => match fs::rename(&plan.path, new_path) {
+                Result::Ok(()) => renamed.push((&plan.path, new_path)),
+                Result::Err(err) => {
+                    let mut msg = format!("{}: unable to rename: {}", plan.path.display(), err);
+                    for &(from, to) in renamed.iter().rev() {
+                        match fs::rename(to, from) {
+                            Result::Ok(()) => {},
+                            Result::Err(err) => msg.push_str(format!(
+                                "\n{}: unable to rename back: {}", to.display(), err).as_slice()),
+                        }
+                    }
+                    return Result::Err(roll_back(msg, saved.as_slice(), keep_backup));
+                },
+            },
+            Option::None => {},
+        }
+    }
+    Result::Ok(())
+}
+
+/// give every saved file its old tags back, and describe what happened
+fn roll_back(mut msg: String, saved: &[&Plan], keep_backup: bool) -> String {
+    let mut restored = true;
+    for plan in saved.iter() {
+        let result = if keep_backup {
+            fs::rename(&groove::Backup::path_for(&plan.path), &plan.path)
+                .map_err(|err| format!("{}", err))
+        } else {
+            restore_tags(&plan.path, plan.before.as_slice())
+        };
+        match result {
+            Result::Ok(()) => {},
+            Result::Err(err) => {
+                msg.push_str(format!("\n{}: unable to restore the old tags: {}",
+                                     plan.path.display(), err).as_slice());
+                restored = false;
+            },
+        }
+    }
+    if restored {
+        msg.push_str("\nno files were changed");
+    }
+    msg
+}
+
+/// replace the tags of the file at path with tags, keeping repeated keys
+fn restore_tags(path: &Path, tags: &[(String, String)]) -> Result<(), String> {
+    let file = try!(groove::File::open(path).ok_or("unable to open".to_string()));
+    for (key, _) in snapshot(&file).into_iter() {
+        try!(file.metadata_delete(key.as_slice(), true).map_err(|err| format!("error {}", err)));
+    }
+    let mut keys: Vec<&str> = Vec::new();
+    for &(ref key, _) in tags.iter() {
+        if !keys.contains(&key.as_slice()) {
+            keys.push(key.as_slice());
+        }
+    }
+    for key in keys.iter() {
+        let values: Vec<&str> = tags.iter()
+            .filter(|&&(ref k, _)| k.as_slice() == *key)
+            .map(|&(_, ref v)| v.as_slice())
+            .collect();
+        try!(file.metadata_set_all(*key, values.as_slice(), true).map_err(|err| format!("error {}", err)));
+    }
+    file.save_atomic(groove::Backup::None).map(|_| ()).map_err(|err| format!("{:?}", err))
+}
+
+fn split_pair(param: &str) -> Result<(String, String), String> {
+    match param.find('=') {
+        Option::Some(index) => Result::Ok((param[..index].to_string(), param[index + 1..].to_string())),
+        Option::None => Result::Err(format!("expected key=value, got {}", param)),
+    }
+}
+
+/// parse a pattern such as "%artist% - %title%". %% is a literal %.
+fn parse_pattern(pattern: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut rest = pattern;
+    while !rest.is_empty() {
+        match rest.find('%') {
+            Option::Some(start) => {
+                literal.push_str(&rest[..start]);
+                let after = &rest[start + 1..];
+                let end = try!(after.find('%').ok_or_else(|| {
+                    format!("unterminated field in pattern {}", pattern)
+                }));
+                if end == 0 {
+                    literal.push('%');
+                } else {
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(literal));
+                        literal = String::new();
+                    }
+                    match tokens.last() {
+                        Option::Some(&Token::Field(_)) => {
+                            return Result::Err(format!("fields must be separated in pattern {}", pattern));
+                        },
+                        _ => {},
+                    }
+                    tokens.push(Token::Field(after[..end].to_string()));
+                }
+                rest = &after[end + 1..];
+            },
+            Option::None => {
+                literal.push_str(rest);
+                rest = "";
+            },
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    Result::Ok(tokens)
+}
+
+/// each field ends where the literal text after it first appears
+fn match_pattern(tokens: &[Token], text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    let mut rest = text;
+    for (index, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::Literal(ref literal) => {
+                if !rest.starts_with(literal.as_slice()) {
+                    return Option::None;
+                }
+                rest = &rest[literal.len()..];
+            },
+            Token::Field(ref key) => {
+                let end = match tokens.get(index + 1) {
+                    Option::Some(&Token::Literal(ref literal)) => match rest.find_str(literal.as_slice()) {
+                        Option::Some(end) => end,
+                        Option::None => return Option::None,
+                    },
+                    _ => rest.len(),
+                };
+                fields.push((key.clone(), rest[..end].trim().to_string()));
+                rest = &rest[end..];
+            },
+        }
+    }
+    if rest.is_empty() { Option::Some(fields) } else { Option::None }
+}
+
+fn format_filename(file: &groove::File, path: &Path, tokens: &[Token]) -> Result<Path, String> {
+    let mut name = String::new();
+    for token in tokens.iter() {
+        match *token {
+            Token::Literal(ref literal) => name.push_str(literal.as_slice()),
+            Token::Field(ref key) => {
+                let value = try!(file.metadata_get(key.as_slice(), false).ok_or_else(|| {
+                    format!("no {} tag for the file name", key)
+                })).value_lossy();
+                // a tag must never introduce a directory
+                let value: String = value.chars().map(|c| if c == '/' || c == '\0' {'_'} else {c}).collect();
+                name.push_str(value.trim());
+            },
+        }
+    }
+    match path.extension_str() {
+        Option::Some(ext) => {
+            name.push('.');
+            name.push_str(ext);
+        },
+        Option::None => {},
+    }
+    Result::Ok(path.with_filename(name))
+}
+
+/// expand globs and directories. the flag is false for files found inside
+/// a directory, which are skipped if they are not audio files.
+fn expand_inputs(inputs: &[String]) -> Result<Vec<(Path, bool)>, String> {
+    let mut paths = Vec::new();
+    for input in inputs.iter() {
+        if has_wildcard(input.as_slice()) {
+            let matches = try!(glob(input.as_slice()));
+            if matches.is_empty() {
+                return Result::Err(format!("no files match {}", input));
+            }
+            paths.extend(matches.into_iter().map(|path| (path, false)));
+        } else {
+            let path = Path::new(input.as_slice());
+            let found = try!(groove::find_audio_files(&[path]).map_err(|err| {
+                format!("{}: {}", input, err)
+            }));
+            paths.extend(found.into_iter());
+        }
+    }
+    Result::Ok(paths)
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains_char('*') || s.contains_char('?')
+}
+
+/// * and ? do not match /, ** matches any number of directories
+fn glob(pattern: &str) -> Result<Vec<Path>, String> {
+    // walk from the deepest directory without wildcards
+    let components: Vec<&str> = pattern.split('/').collect();
+    let fixed = components.iter().take_while(|c| !has_wildcard(**c)).count();
+    let base = components[..fixed].connect("/");
+    let base = if base.is_empty() {
+        if pattern.starts_with("/") { "/".to_string() } else { ".".to_string() }
+    } else {
+        base
+    };
+    let pattern = if pattern.starts_with("./") { &pattern[2..] } else { pattern };
+
+    let walker = try!(fs::walk_dir(&Path::new(base.as_slice())).map_err(|err| {
+        format!("{}: {}", base, err)
+    }));
+    let mut matches: Vec<Path> = walker.filter(|path| {
+        let candidate = path.as_vec();
+        let candidate = if candidate.starts_with(b"./") { &candidate[2..] } else { candidate };
+        path.is_file() && glob_match(pattern.as_bytes(), candidate)
+    }).collect();
+    matches.sort();
+    Result::Ok(matches)
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+    if pattern.starts_with(b"**") {
+        let rest = &pattern[2..];
+        // "a/**/b" also matches "a/b"
+        let rest_no_slash = if rest.starts_with(b"/") { &rest[1..] } else { rest };
+        return (0..text.len() + 1).any(|i| {
+            glob_match(rest, &text[i..]) || glob_match(rest_no_slash, &text[i..])
+        });
+    }
+    match pattern[0] {
+        b'*' => (0..text.len() + 1)
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        b'?' => !text.is_empty() && text[0] != b'/' && glob_match(&pattern[1..], &text[1..]),
+        c => !text.is_empty() && text[0] == c && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+fn print_usage(stderr: &mut std::old_io::LineBufferedWriter<std::old_io::stdio::StdWriter>, exe: &str) {
+    let _ = write!(stderr, "Usage: {} [options] file|dir|glob...\n", exe);
+    let _ = write!(stderr, "Options:\n");
+    let _ = write!(stderr, "  --set key=value          set a tag\n");
+    let _ = write!(stderr, "  --delete key             delete a tag\n");
+    let _ = write!(stderr, "  --rename old=new         move the values of a tag to another key\n");
+    let _ = write!(stderr, "  --copy from=to           copy the values of a tag to another key\n");
+    let _ = write!(stderr, "  --from-filename pattern  set tags from the file name, e.g. \"%artist% - %title%\"\n");
+    let _ = write!(stderr, "  --to-filename pattern    rename files from their tags\n");
+    let _ = write!(stderr, "  --dry-run                show what would change without writing\n");
+    let _ = write!(stderr, "  --backup                 keep the original of every saved file as .bak\n");
+    let _ = write!(stderr, "Options are applied in order, --to-filename last.\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, match_pattern, parse_pattern, Token};
+
+    fn literal(s: &str) -> Token { Token::Literal(s.to_string()) }
+    fn field(s: &str) -> Token { Token::Field(s.to_string()) }
+
+    #[test]
+    fn parse_pattern_splits_fields_and_literals() {
+        assert_eq!(parse_pattern("%artist% - %title%").unwrap(),
+                   vec![field("artist"), literal(" - "), field("title")]);
+        assert_eq!(parse_pattern("%track%. %title% (live)").unwrap(),
+                   vec![field("track"), literal(". "), field("title"), literal(" (live)")]);
+        assert_eq!(parse_pattern("no fields").unwrap(), vec![literal("no fields")]);
+        assert!(parse_pattern("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_pattern_escapes_percent() {
+        assert_eq!(parse_pattern("100%% %title%").unwrap(), vec![literal("100% "), field("title")]);
+        assert_eq!(parse_pattern("%%").unwrap(), vec![literal("%")]);
+    }
+
+    #[test]
+    fn parse_pattern_rejects_bad_patterns() {
+        assert!(parse_pattern("%artist").is_err());
+        assert!(parse_pattern("%artist%%title%").is_err());
+    }
+
+    #[test]
+    fn match_pattern_fills_fields() {
+        let tokens = parse_pattern("%artist% - %title%").unwrap();
+        assert_eq!(match_pattern(tokens.as_slice(), "Low - Words").unwrap(),
+                   vec![("artist".to_string(), "Low".to_string()),
+                        ("title".to_string(), "Words".to_string())]);
+        assert_eq!(match_pattern(tokens.as_slice(), "Low - Words - Live").unwrap()[1].1, "Words - Live");
+        assert!(match_pattern(tokens.as_slice(), "Low_Words").is_none());
+    }
+
+    #[test]
+    fn glob_star_stays_in_one_directory() {
+        assert!(glob_match(b"*.flac", b"a.flac"));
+        assert!(glob_match(b"*.flac", b".flac"));
+        assert!(!glob_match(b"*.flac", b"a.mp3"));
+        assert!(!glob_match(b"*.flac", b"dir/a.flac"));
+        assert!(glob_match(b"dir/*/*.flac", b"dir/album/a.flac"));
+        assert!(!glob_match(b"dir/*/*.flac", b"dir/a/b/c.flac"));
+    }
+
+    #[test]
+    fn glob_question_mark_matches_one_character() {
+        assert!(glob_match(b"0?.ogg", b"01.ogg"));
+        assert!(!glob_match(b"0?.ogg", b"0.ogg"));
+        assert!(!glob_match(b"a?b", b"a/b"));
+    }
+
+    #[test]
+    fn glob_double_star_crosses_directories() {
+        assert!(glob_match(b"music/**/*.mp3", b"music/a/b/c.mp3"));
+        assert!(glob_match(b"music/**/*.mp3", b"music/c.mp3"));
+        assert!(glob_match(b"**", b"a/b/c"));
+        assert!(!glob_match(b"music/**/*.mp3", b"other/c.mp3"));
+    }
+}
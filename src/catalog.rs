@@ -0,0 +1,509 @@
+//! export the metadata of a music library as JSON Lines or CSV, and apply
+//! an edited CSV back to the files.
+//!
+//! Tag keys are lowercased so that files using different spellings of the
+//! same key share a CSV column. A CSV cell holds a single value as it is,
+//! so values may contain newlines and commas. Repeated keys become a JSON
+//! array of strings in the cell, such as ["Alice","Bob"], as does a single
+//! value which starts with [. In JSON Lines repeated keys become an array.
+
+use std::old_io::{IoResult, Reader, Writer};
+use std::ascii::AsciiExt;
+use std::char;
+use std::mem;
+use std::option::Option;
+use std::result::Result;
+
+use super::{find_audio_files, push_json_string, AudioFormat, Backup, File};
+
+#[derive(Copy, Debug)]
+pub enum Format {
+    /// one JSON object per line
+    JsonLines,
+    /// a header row followed by one row per file
+    Csv,
+}
+
+/// everything exported about one file
+pub struct Record {
+    pub path: Path,
+    pub duration: f64,
+    pub audio_format: AudioFormat,
+    /// lowercased keys in the order the file stores them
+    pub tags: Vec<(String, String)>,
+}
+
+pub struct ImportReport {
+    /// files whose tags were changed and saved
+    pub changed: Vec<Path>,
+    /// number of rows which already matched their file
+    pub unchanged: usize,
+    /// rows which could not be applied. the other rows are still applied.
+    pub errors: Vec<(Path, String)>,
+}
+
+// the columns before the tag columns. only path is read back on import.
+const FIXED_COLUMNS: [&'static str; 5] = ["path", "duration", "sample_rate", "channels", "sample_format"];
+
+/// open every audio file in the given directories, recursively, see
+/// find_audio_files. paths which are files are included directly. files
+/// which can not be opened are skipped.
+pub fn scan(paths: &[Path]) -> IoResult<Vec<Record>> {
+    let mut files: Vec<Path> = try!(find_audio_files(paths)).into_iter().map(|(path, _)| path).collect();
+    files.sort();
+
+    let mut records = Vec::new();
+    for path in files.into_iter() {
+        match File::open(&path) {
+            Option::Some(file) => records.push(Record {
+                duration: file.duration(),
+                audio_format: file.audio_format(),
+                tags: file.metadata_iter()
+                    .map(|tag| (tag.key_lossy().to_ascii_lowercase(), tag.value_lossy()))
+                    .collect(),
+                path: path,
+            }),
+            Option::None => {},
+        }
+    }
+    Result::Ok(records)
+}
+
+pub fn export(records: &[Record], format: Format, writer: &mut Writer) -> IoResult<()> {
+    match format {
+        Format::JsonLines => export_json_lines(records, writer),
+        Format::Csv => export_csv(records, writer),
+    }
+}
+
+fn export_json_lines(records: &[Record], writer: &mut Writer) -> IoResult<()> {
+    for record in records.iter() {
+        let mut line = String::new();
+        line.push_str("{\"path\":");
+        push_json_string(&mut line, path_string(&record.path).as_slice());
+        line.push_str(format!(",\"duration\":{},\"sample_rate\":{},\"channels\":{},\"sample_format\":",
+                              record.duration, record.audio_format.sample_rate,
+                              record.audio_format.channel_layout.count()).as_slice());
+        push_json_string(&mut line, record.audio_format.sample_fmt.name());
+        line.push_str(",\"tags\":{");
+        for (index, key) in tag_keys(record.tags.as_slice()).iter().enumerate() {
+            if index > 0 {
+                line.push(',');
+            }
+            push_json_string(&mut line, key.as_slice());
+            line.push(':');
+            let values = values_of(record.tags.as_slice(), key.as_slice());
+            if values.len() == 1 {
+                push_json_string(&mut line, values[0]);
+            } else {
+                line.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    push_json_string(&mut line, *value);
+                }
+                line.push(']');
+            }
+        }
+        line.push_str("}}\n");
+        try!(writer.write_str(line.as_slice()));
+    }
+    Result::Ok(())
+}
+
+fn export_csv(records: &[Record], writer: &mut Writer) -> IoResult<()> {
+    let mut all_tags = Vec::new();
+    for record in records.iter() {
+        all_tags.push_all(record.tags.as_slice());
+    }
+    let keys = tag_keys(all_tags.as_slice());
+
+    let mut header: Vec<String> = FIXED_COLUMNS.iter().map(|c| c.to_string()).collect();
+    header.push_all(keys.as_slice());
+    try!(write_csv_row(writer, header.as_slice()));
+
+    for record in records.iter() {
+        let mut row = vec![
+            path_string(&record.path),
+            record.duration.to_string(),
+            record.audio_format.sample_rate.to_string(),
+            record.audio_format.channel_layout.count().to_string(),
+            record.audio_format.sample_fmt.name().to_string(),
+        ];
+        for key in keys.iter() {
+            row.push(csv_cell(values_of(record.tags.as_slice(), key.as_slice()).as_slice()));
+        }
+        try!(write_csv_row(writer, row.as_slice()));
+    }
+    Result::Ok(())
+}
+
+/// apply an edited CSV, as written by export, to the files it names.
+/// for every tag column, a file gets exactly the values in its cell; an
+/// empty cell deletes the tag. tags without a column are left alone.
+/// files are saved with File::save_atomic. with dry_run, nothing is saved
+/// but the report says what would change.
+pub fn import_csv(reader: &mut Reader, dry_run: bool) -> IoResult<ImportReport> {
+    let text = try!(reader.read_to_string());
+    let rows = parse_csv(text.as_slice());
+    let mut report = ImportReport { changed: Vec::new(), unchanged: 0, errors: Vec::new() };
+    if rows.is_empty() {
+        return Result::Ok(report);
+    }
+    let header = &rows[0];
+    let path_column = header.iter().position(|c| c.as_slice() == "path");
+    let path_column = match path_column {
+        Option::Some(column) => column,
+        Option::None => {
+            report.errors.push((Path::new(""), "no path column".to_string()));
+            return Result::Ok(report);
+        },
+    };
+
+    for row in rows[1..].iter() {
+        if row.len() <= path_column {
+            continue;
+        }
+        let path = Path::new(row[path_column].as_slice());
+        match apply_row(&path, header.as_slice(), row.as_slice(), dry_run) {
+            Result::Ok(true) => report.changed.push(path),
+            Result::Ok(false) => report.unchanged += 1,
+            Result::Err(msg) => report.errors.push((path, msg)),
+        }
+    }
+    Result::Ok(report)
+}
+
+/// returns whether the file changed
+fn apply_row(path: &Path, header: &[String], row: &[String], dry_run: bool) -> Result<bool, String> {
+    let file = try!(File::open(path).ok_or_else(|| "unable to open".to_string()));
+    let current: Vec<(String, String)> = file.metadata_iter()
+        .map(|tag| (tag.key_lossy().to_ascii_lowercase(), tag.value_lossy()))
+        .collect();
+    let mut changed = false;
+    for (column, key) in header.iter().enumerate() {
+        if FIXED_COLUMNS.contains(&key.as_slice()) || key.is_empty() {
+            continue;
+        }
+        let cell = if column < row.len() { row[column].as_slice() } else { "" };
+        let wanted = try!(parse_cell(cell).ok_or_else(|| {
+            format!("the {} cell is not a JSON array of strings", key)
+        }));
+        let wanted: Vec<&str> = wanted.iter().map(|v| v.as_slice()).collect();
+        if wanted == values_of(current.as_slice(), key.as_slice()) {
+            continue;
+        }
+        changed = true;
+        let result = if wanted.is_empty() {
+            file.metadata_delete(key.as_slice(), false)
+        } else {
+            file.metadata_set_all(key.as_slice(), wanted.as_slice(), false)
+        };
+        try!(result.map_err(|err_code| format!("unable to set {}: error {}", key, err_code)));
+    }
+    if changed && !dry_run {
+        try!(file.save_atomic(Backup::None).map_err(|err| format!("unable to save: {:?}", err)));
+    }
+    Result::Ok(changed)
+}
+
+/// distinct keys in order of first appearance
+fn tag_keys(tags: &[(String, String)]) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    for &(ref key, _) in tags.iter() {
+        if !keys.contains(key) {
+            keys.push(key.clone());
+        }
+    }
+    keys
+}
+
+fn values_of<'a>(tags: &'a [(String, String)], key: &str) -> Vec<&'a str> {
+    tags.iter().filter(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref v)| v.as_slice()).collect()
+}
+
+/// one value as it is, no values as an empty cell, anything else as a JSON
+/// array
+fn csv_cell(values: &[&str]) -> String {
+    if values.is_empty() {
+        return String::new();
+    }
+    if values.len() == 1 && !values[0].is_empty() && !values[0].starts_with("[") {
+        return values[0].to_string();
+    }
+    let mut cell = String::from_str("[");
+    for (index, value) in values.iter().enumerate() {
+        if index > 0 {
+            cell.push(',');
+        }
+        push_json_string(&mut cell, *value);
+    }
+    cell.push(']');
+    cell
+}
+
+/// the values of a cell written by csv_cell. None if a cell starting with [
+/// is not a JSON array of strings.
+fn parse_cell(cell: &str) -> Option<Vec<String>> {
+    if cell.is_empty() {
+        Option::Some(Vec::new())
+    } else if cell.starts_with("[") {
+        parse_json_strings(cell)
+    } else {
+        Option::Some(vec![cell.to_string()])
+    }
+}
+
+fn parse_json_strings(text: &str) -> Option<Vec<String>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = skip_json_space(chars.as_slice(), 0);
+    if chars.get(pos) != Option::Some(&'[') {
+        return Option::None;
+    }
+    pos = skip_json_space(chars.as_slice(), pos + 1);
+    let mut values = Vec::new();
+    if chars.get(pos) == Option::Some(&']') {
+        pos += 1;
+    } else {
+        loop {
+            let (value, end) = match parse_json_string(chars.as_slice(), pos) {
+                Option::Some(parsed) => parsed,
+                Option::None => return Option::None,
+            };
+            values.push(value);
+            pos = skip_json_space(chars.as_slice(), end);
+            match chars.get(pos) {
+                Option::Some(&',') => pos = skip_json_space(chars.as_slice(), pos + 1),
+                Option::Some(&']') => {
+                    pos += 1;
+                    break;
+                },
+                _ => return Option::None,
+            }
+        }
+    }
+    if skip_json_space(chars.as_slice(), pos) == chars.len() {
+        Option::Some(values)
+    } else {
+        Option::None
+    }
+}
+
+fn skip_json_space(chars: &[char], mut pos: usize) -> usize {
+    while pos < chars.len() && (chars[pos] == ' ' || chars[pos] == '\t' ||
+                                chars[pos] == '\n' || chars[pos] == '\r') {
+        pos += 1;
+    }
+    pos
+}
+
+/// the string starting with the quote at pos, and the position after it
+fn parse_json_string(chars: &[char], pos: usize) -> Option<(String, usize)> {
+    if chars.get(pos) != Option::Some(&'"') {
+        return Option::None;
+    }
+    let mut value = String::new();
+    let mut pos = pos + 1;
+    // the first half of a surrogate pair, which the next escape must complete
+    let mut high_surrogate: Option<u32> = Option::None;
+    loop {
+        let c = match chars.get(pos) {
+            Option::Some(&c) => c,
+            Option::None => return Option::None,
+        };
+        pos += 1;
+        if high_surrogate.is_some() && c != '\\' {
+            return Option::None;
+        }
+        if c == '"' {
+            break;
+        }
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        let escaped = match chars.get(pos) {
+            Option::Some(&escaped) => escaped,
+            Option::None => return Option::None,
+        };
+        pos += 1;
+        if high_surrogate.is_some() && escaped != 'u' {
+            return Option::None;
+        }
+        match escaped {
+            '"' => value.push('"'),
+            '\\' => value.push('\\'),
+            '/' => value.push('/'),
+            'b' => value.push('\x08'),
+            'f' => value.push('\x0c'),
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'u' => {
+                if pos + 4 > chars.len() {
+                    return Option::None;
+                }
+                let mut code = 0u32;
+                for &digit in chars[pos..pos + 4].iter() {
+                    code = code * 16 + match digit.to_digit(16) {
+                        Option::Some(digit) => digit as u32,
+                        Option::None => return Option::None,
+                    };
+                }
+                pos += 4;
+                match high_surrogate.take() {
+                    Option::Some(high) if code >= 0xDC00 && code < 0xE000 => {
+                        code = 0x10000 + ((high - 0xD800) << 10) + (code - 0xDC00);
+                    },
+                    Option::Some(_) => return Option::None,
+                    Option::None if code >= 0xD800 && code < 0xDC00 => {
+                        high_surrogate = Option::Some(code);
+                        continue;
+                    },
+                    Option::None => {},
+                }
+                match char::from_u32(code) {
+                    Option::Some(c) => value.push(c),
+                    Option::None => return Option::None,
+                }
+            },
+            _ => return Option::None,
+        }
+    }
+    Option::Some((value, pos))
+}
+
+fn path_string(path: &Path) -> String {
+    String::from_utf8_lossy(path.as_vec()).into_owned()
+}
+
+fn write_csv_row(writer: &mut Writer, fields: &[String]) -> IoResult<()> {
+    let mut line = String::new();
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            line.push(',');
+        }
+        if field.contains_char(',') || field.contains_char('"') ||
+            field.contains_char('\n') || field.contains_char('\r')
+        {
+            line.push('"');
+            line.push_str(field.replace("\"", "\"\"").as_slice());
+            line.push('"');
+        } else {
+            line.push_str(field.as_slice());
+        }
+    }
+    line.push_str("\r\n");
+    writer.write_str(line.as_slice())
+}
+
+/// RFC 4180: quoted fields may contain commas, newlines and doubled quotes.
+/// spreadsheets may end lines with \n or \r\n; quoted fields are kept
+/// exactly as written, line breaks included.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    loop {
+        let c = match chars.next() {
+            Option::Some(c) => c,
+            Option::None => break,
+        };
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Option::Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else {
+            match c {
+                '"' => in_quotes = true,
+                ',' => row.push(mem::replace(&mut field, String::new())),
+                '\r' => {},
+                '\n' => {
+                    row.push(mem::replace(&mut field, String::new()));
+                    rows.push(mem::replace(&mut row, Vec::new()));
+                },
+                c => field.push(c),
+            }
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_cell, parse_cell, parse_csv, write_csv_row};
+
+    fn row(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_csv_plain_rows() {
+        assert_eq!(parse_csv("path,title\r\na.flac,One\r\nb.flac,Two\r\n"),
+                   vec![row(&["path", "title"]), row(&["a.flac", "One"]), row(&["b.flac", "Two"])]);
+        // no line break at the end, and \n line breaks
+        assert_eq!(parse_csv("a,b\nc,"), vec![row(&["a", "b"]), row(&["c", ""])]);
+        assert!(parse_csv("").is_empty());
+    }
+
+    #[test]
+    fn parse_csv_quoted_fields() {
+        assert_eq!(parse_csv("\"a, b\",\"say \"\"hi\"\"\",\"two\r\nlines\"\n"),
+                   vec![row(&["a, b", "say \"hi\"", "two\r\nlines"])]);
+        assert_eq!(parse_csv("\"\",x\n"), vec![row(&["", "x"])]);
+    }
+
+    #[test]
+    fn write_csv_row_round_trips() {
+        let fields = row(&["plain", "comma, here", "quote \"", "line\nbreak", "crlf\r\nbreak", ""]);
+        let mut out = Vec::new();
+        write_csv_row(&mut out, fields.as_slice()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(parse_csv(text.as_slice()), vec![fields]);
+    }
+
+    #[test]
+    fn cells_keep_multi_line_and_repeated_values() {
+        let cases: Vec<Vec<&str>> = vec![
+            vec![],
+            vec!["one"],
+            vec!["first line\nsecond line"],
+            vec!["Alice", "Bob"],
+            vec!["a\nb", "c,\"d\""],
+            vec!["[not an array]"],
+            vec![""],
+            vec!["ünïcödé", "\u{1f3b5}"],
+        ];
+        for values in cases.iter() {
+            let parsed = parse_cell(csv_cell(values.as_slice()).as_slice()).unwrap();
+            let parsed: Vec<&str> = parsed.iter().map(|v| v.as_slice()).collect();
+            assert_eq!(&parsed, values);
+        }
+    }
+
+    #[test]
+    fn parse_cell_reads_hand_written_arrays() {
+        assert_eq!(parse_cell(" x ").unwrap(), vec![" x ".to_string()]);
+        assert_eq!(parse_cell("[ \"a\" , \"b\\u00e9\" ]").unwrap(), vec!["a".to_string(), "bé".to_string()]);
+        assert_eq!(parse_cell("[\"\\ud83c\\udfb5\"]").unwrap(), vec!["\u{1f3b5}".to_string()]);
+        assert!(parse_cell("[]").unwrap().is_empty());
+        assert!(parse_cell("[\"a\"").is_none());
+        assert!(parse_cell("[a]").is_none());
+        assert!(parse_cell("[\"a\"] x").is_none());
+        assert!(parse_cell("[\"\\ud83c\"]").is_none());
+    }
+}
@@ -0,0 +1,429 @@
+//! integrity checks for audio files.
+//!
+//! check decodes a whole file through a Sink of its own in planar float
+//! format and looks for problems: files which do not open, streams which
+//! end early, durations which disagree with the header, runs of clipped
+//! samples, DC offset and channels which are all zero.
+//!
+//! libgroove does not report decode errors to its callers; it logs them
+//! and skips ahead. The first check therefore installs a libav log callback
+//! which stays in place: it prints messages up to the level set_logging is
+//! at, as the default callback does, and while a check runs it also
+//! collects the messages of error level or worse. They are reported as
+//! DecodeErrors, and the stream usually shows up as Truncated as well, or
+//! as a DurationMismatch if the header was wrong too.
+//!
+//! libav has one log callback per process and its messages do not say
+//! which file they are about, so checks run one at a time, and errors
+//! another thread's decoding logs during a check are counted for it too.
+//! A log callback the application installed itself is replaced.
+
+use std::ffi;
+use std::mem;
+use std::num::Float;
+use std::old_io::stdio;
+use std::option::Option;
+use std::result::Result;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use libc::{c_char, c_int, c_void};
+
+use super::{push_json_string, AudioFormat, File, Playlist, SampleFormat, SampleType, Sink,
+            VaList, av_log_format_line, av_log_get_level, av_log_set_callback};
+
+const AV_LOG_ERROR: c_int = 16;
+
+/// messages kept per file. further messages are only counted.
+const MAX_DECODE_ERRORS: usize = 100;
+
+lazy_static! {
+    /// held for the duration of a check
+    static ref CHECK_LOCK: Mutex<()> = Mutex::new(());
+    static ref DECODE_ERRORS: Mutex<DecodeErrors> = Mutex::new(DecodeErrors {
+        collecting: false,
+        messages: Vec::new(),
+        count: 0,
+        line: String::new(),
+        print_prefix: 1,
+    });
+}
+
+/// what counts as a problem
+#[derive(Copy, Debug)]
+pub struct Thresholds {
+    /// seconds the decoded duration may differ from File::duration
+    pub duration_tolerance: f64,
+    /// absolute sample value, in float format, at which a sample is clipped
+    pub clip_level: f32,
+    /// consecutive clipped samples in one channel which count as a run
+    pub min_clip_run: usize,
+    /// absolute mean sample value, in float format, reported as DC offset
+    pub max_dc_offset: f64,
+}
+
+impl Thresholds {
+    /// half a second of duration difference, runs of 3 samples at 0.999 or
+    /// more, and a DC offset of 0.01
+    pub fn default() -> Thresholds {
+        Thresholds {
+            duration_tolerance: 0.5,
+            clip_level: 0.999,
+            min_clip_run: 3,
+            max_dc_offset: 0.01,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// the file could not be opened or has no audio stream
+    Open,
+    /// attaching the sink failed with this error code
+    Groove(i32),
+    /// no audio could be decoded
+    NoAudio,
+    /// libav logged this many errors while opening or decoding the file.
+    /// the first ones are in Report::decode_errors.
+    DecodeErrors { count: usize },
+    /// the decoded audio is shorter than the header says
+    Truncated { expected: f64, decoded: f64 },
+    /// the decoded audio is longer than the header says
+    DurationMismatch { expected: f64, decoded: f64 },
+    /// runs of clipped samples in a channel, and where the first one starts
+    Clipping { channel: usize, runs: usize, first: f64 },
+    DcOffset { channel: usize, offset: f64 },
+    /// every sample of the channel is zero
+    ZeroChannel { channel: usize },
+}
+
+pub struct Report {
+    pub path: Path,
+    /// None if the file could not be opened
+    pub audio_format: Option<AudioFormat>,
+    /// File::duration, from the header
+    pub header_duration: f64,
+    /// seconds of audio actually decoded
+    pub decoded_duration: f64,
+    /// mean sample value per channel
+    pub dc_offset: Vec<f64>,
+    /// sample peak per channel
+    pub peak: Vec<f32>,
+    /// the error messages libav logged, at most 100
+    pub decode_errors: Vec<String>,
+    pub problems: Vec<Problem>,
+}
+
+/// what the log callback collected so far
+struct DecodeErrors {
+    /// set while a check runs
+    collecting: bool,
+    messages: Vec<String>,
+    count: usize,
+    /// the start of a message which does not end in a newline yet
+    line: String,
+    print_prefix: c_int,
+}
+
+/// per channel measurements
+struct Channel {
+    sum: f64,
+    peak: f32,
+    clipped_run: usize,
+    clip_runs: usize,
+    first_clip: Option<f64>,
+}
+
+/// decode the file at path and report what is wrong with it
+pub fn check(path: &Path, thresholds: &Thresholds) -> Report {
+    let mut report = Report {
+        path: path.clone(),
+        audio_format: Option::None,
+        header_duration: 0.0,
+        decoded_duration: 0.0,
+        dc_offset: Vec::new(),
+        peak: Vec::new(),
+        decode_errors: Vec::new(),
+        problems: Vec::new(),
+    };
+
+    static INSTALL_CALLBACK: Once = ONCE_INIT;
+    INSTALL_CALLBACK.call_once(|| unsafe { av_log_set_callback(log_callback) });
+
+    let _lock = CHECK_LOCK.lock().unwrap();
+    {
+        let mut errors = DECODE_ERRORS.lock().unwrap();
+        errors.collecting = true;
+        errors.messages.clear();
+        errors.count = 0;
+        errors.line.clear();
+    }
+    decode(path, thresholds, &mut report);
+
+    let mut errors = DECODE_ERRORS.lock().unwrap();
+    errors.collecting = false;
+    if !errors.line.is_empty() {
+        let line = errors.line.clone();
+        errors.line.clear();
+        add_decode_error(&mut *errors, line.as_slice());
+    }
+    if errors.count > 0 {
+        report.problems.push(Problem::DecodeErrors { count: errors.count });
+        report.decode_errors = errors.messages.clone();
+    }
+    report
+}
+
+/// fills in report
+fn decode(path: &Path, thresholds: &Thresholds, report: &mut Report) {
+    let file = match File::open(path) {
+        Option::Some(file) => file,
+        Option::None => {
+            report.problems.push(Problem::Open);
+            return;
+        },
+    };
+    let format = file.audio_format();
+    report.audio_format = Option::Some(format);
+    report.header_duration = file.duration();
+    let channel_count = format.channel_layout.count() as usize;
+    let rate = format.sample_rate as f64;
+
+    let playlist = Playlist::new();
+    let sink = Sink::new();
+    sink.set_audio_format(AudioFormat {
+        sample_rate: format.sample_rate,
+        channel_layout: format.channel_layout,
+        sample_fmt: SampleFormat { sample_type: SampleType::Flt, planar: true },
+    });
+    playlist.append(&file, 1.0, 1.0);
+    match sink.attach(&playlist) {
+        Result::Ok(()) => {},
+        Result::Err(err_code) => {
+            report.problems.push(Problem::Groove(err_code));
+            return;
+        },
+    }
+
+    let mut channels: Vec<Channel> = (0..channel_count).map(|_| Channel {
+        sum: 0.0,
+        peak: 0.0,
+        clipped_run: 0,
+        clip_runs: 0,
+        first_clip: Option::None,
+    }).collect();
+    let mut frames = 0;
+    loop {
+        let buffer = match sink.buffer_get_blocking() {
+            Option::Some(buffer) => buffer,
+            Option::None => break,
+        };
+        let pos = buffer.pos();
+        for (index, channel) in channels.iter_mut().enumerate() {
+            for (i, &sample) in buffer.channel_as_slice_f32(index as u32).iter().enumerate() {
+                let magnitude = sample.abs();
+                channel.sum += sample as f64;
+                if magnitude > channel.peak {
+                    channel.peak = magnitude;
+                }
+                if magnitude >= thresholds.clip_level {
+                    channel.clipped_run += 1;
+                    if channel.clipped_run == thresholds.min_clip_run {
+                        channel.clip_runs += 1;
+                        if channel.first_clip.is_none() {
+                            let run_start = i as f64 + 1.0 - thresholds.min_clip_run as f64;
+                            channel.first_clip = Option::Some(pos + run_start / rate);
+                        }
+                    }
+                } else {
+                    channel.clipped_run = 0;
+                }
+            }
+        }
+        frames += buffer.frame_count();
+    }
+    sink.detach();
+    playlist.clear();
+
+    report.decoded_duration = frames as f64 / rate;
+    if frames == 0 {
+        report.problems.push(Problem::NoAudio);
+        return;
+    }
+    let difference = report.decoded_duration - report.header_duration;
+    if difference < -thresholds.duration_tolerance {
+        report.problems.push(Problem::Truncated {
+            expected: report.header_duration,
+            decoded: report.decoded_duration,
+        });
+    } else if difference > thresholds.duration_tolerance {
+        report.problems.push(Problem::DurationMismatch {
+            expected: report.header_duration,
+            decoded: report.decoded_duration,
+        });
+    }
+    for (index, channel) in channels.iter().enumerate() {
+        let offset = channel.sum / frames as f64;
+        report.dc_offset.push(offset);
+        report.peak.push(channel.peak);
+        if channel.peak == 0.0 {
+            report.problems.push(Problem::ZeroChannel { channel: index });
+            continue;
+        }
+        if channel.clip_runs > 0 {
+            report.problems.push(Problem::Clipping {
+                channel: index,
+                runs: channel.clip_runs,
+                first: channel.first_clip.unwrap_or(0.0),
+            });
+        }
+        if offset.abs() > thresholds.max_dc_offset {
+            report.problems.push(Problem::DcOffset { channel: index, offset: offset });
+        }
+    }
+}
+
+/// called by libav, on whichever thread logs. args can only be read once,
+/// so the line is formatted here both for printing and for collecting.
+extern fn log_callback(ptr: *mut c_void, level: c_int, fmt: *const c_char, args: VaList) {
+    let mut errors = match DECODE_ERRORS.lock() {
+        Result::Ok(errors) => errors,
+        Result::Err(_) => return,
+    };
+    let mut buffer = [0 as c_char; 1024];
+    let text = unsafe {
+        av_log_format_line(ptr, level, fmt, args, buffer.as_mut_ptr(), buffer.len() as c_int,
+                           &mut errors.print_prefix);
+        let ptr = buffer.as_ptr();
+        String::from_utf8_lossy(ffi::c_str_to_bytes(&ptr)).into_owned()
+    };
+    // the upper bits may hold a color
+    let level = level & 0xff;
+    if level <= unsafe { av_log_get_level() } {
+        let _ = stdio::stderr_raw().write_str(text.as_slice());
+    }
+    if !errors.collecting || level > AV_LOG_ERROR {
+        return;
+    }
+    errors.line.push_str(text.as_slice());
+    if errors.line.ends_with("\n") {
+        let line = mem::replace(&mut errors.line, String::new());
+        add_decode_error(&mut *errors, line.as_slice());
+    }
+}
+
+fn add_decode_error(errors: &mut DecodeErrors, line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    errors.count += 1;
+    if errors.messages.len() < MAX_DECODE_ERRORS {
+        errors.messages.push(line.to_string());
+    }
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// the report as one line of JSON, without a trailing newline
+    pub fn to_json(&self) -> String {
+        let mut json = String::from_str("{\"path\":");
+        push_json_string(&mut json, String::from_utf8_lossy(self.path.as_vec()).as_slice());
+        json.push_str(format!(",\"ok\":{}", self.is_ok()).as_slice());
+        match self.audio_format {
+            Option::Some(format) => {
+                json.push_str(format!(",\"sample_rate\":{},\"channels\":{},\"sample_format\":",
+                                      format.sample_rate, format.channel_layout.count()).as_slice());
+                push_json_string(&mut json, format.sample_fmt.name());
+            },
+            Option::None => {},
+        }
+        json.push_str(format!(",\"header_duration\":{},\"decoded_duration\":{}",
+                              json_number(self.header_duration),
+                              json_number(self.decoded_duration)).as_slice());
+        json.push_str(format!(",\"dc_offset\":[{}]",
+                              join(self.dc_offset.iter().map(|&offset| offset))).as_slice());
+        json.push_str(format!(",\"peak\":[{}]",
+                              join(self.peak.iter().map(|&peak| peak))).as_slice());
+        json.push_str(",\"decode_errors\":[");
+        for (index, message) in self.decode_errors.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, message.as_slice());
+        }
+        json.push(']');
+        json.push_str(",\"problems\":[");
+        for (index, problem) in self.problems.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str(problem_json(problem).as_slice());
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn problem_json(problem: &Problem) -> String {
+    match *problem {
+        Problem::Open => "{\"type\":\"open\"}".to_string(),
+        Problem::Groove(err_code) => format!("{{\"type\":\"groove\",\"error\":{}}}", err_code),
+        Problem::NoAudio => "{\"type\":\"no_audio\"}".to_string(),
+        Problem::DecodeErrors { count } =>
+            format!("{{\"type\":\"decode_errors\",\"count\":{}}}", count),
+        Problem::Truncated { expected, decoded } =>
+            format!("{{\"type\":\"truncated\",\"expected\":{},\"decoded\":{}}}",
+                    json_number(expected), json_number(decoded)),
+        Problem::DurationMismatch { expected, decoded } =>
+            format!("{{\"type\":\"duration_mismatch\",\"expected\":{},\"decoded\":{}}}",
+                    json_number(expected), json_number(decoded)),
+        Problem::Clipping { channel, runs, first } =>
+            format!("{{\"type\":\"clipping\",\"channel\":{},\"runs\":{},\"first\":{}}}",
+                    channel, runs, json_number(first)),
+        Problem::DcOffset { channel, offset } =>
+            format!("{{\"type\":\"dc_offset\",\"channel\":{},\"offset\":{}}}",
+                    channel, json_number(offset)),
+        Problem::ZeroChannel { channel } =>
+            format!("{{\"type\":\"zero_channel\",\"channel\":{}}}", channel),
+    }
+}
+
+/// JSON has no infinity or NaN, those become null
+fn json_number<T: Float + ToString>(value: T) -> String {
+    if value.is_finite() {value.to_string()} else {"null".to_string()}
+}
+
+fn join<T: Float + ToString, I: Iterator<Item=T>>(values: I) -> String {
+    values.map(json_number).collect::<Vec<String>>().connect(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::option::Option;
+
+    use super::{Problem, Report};
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        let report = Report {
+            path: Path::new("a.flac"),
+            audio_format: Option::None,
+            header_duration: Float::infinity(),
+            decoded_duration: 1.5,
+            dc_offset: vec![Float::nan(), 0.25],
+            peak: vec![Float::infinity(), 0.5],
+            decode_errors: vec!["[mp3 @ 0x1] invalid \"frame\"".to_string()],
+            problems: vec![Problem::DecodeErrors { count: 1 },
+                           Problem::Truncated { expected: Float::infinity(), decoded: 1.5 }],
+        };
+        assert_eq!(report.to_json().as_slice(),
+                   "{\"path\":\"a.flac\",\"ok\":false,\"header_duration\":null,\"decoded_duration\":1.5,\
+                    \"dc_offset\":[null,0.25],\"peak\":[null,0.5],\
+                    \"decode_errors\":[\"[mp3 @ 0x1] invalid \\\"frame\\\"\"],\
+                    \"problems\":[{\"type\":\"decode_errors\",\"count\":1},\
+                    {\"type\":\"truncated\",\"expected\":null,\"decoded\":1.5}]}");
+    }
+}
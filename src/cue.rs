@@ -0,0 +1,522 @@
+//! parse CUE sheets and use their tracks as separate playlist items.
+//!
+//! A CUE sheet describes tracks as regions of one or more audio files.
+//! CueSheet::append_to adds every track as a trimmed playlist item of its
+//! FILE (see Playlist::append_trimmed), so a Sink sees each track as an
+//! item of its own while every FILE is opened only once. CueTrack::tags
+//! gives the tags of a track.
+//!
+//! Only a Sink trims items: an Encoder, LoudnessDetector or Fingerprinter
+//! refuses to attach to a playlist of tracks. See excerpt.
+//!
+//! The gap before a track (INDEX 00 to INDEX 01) is kept at the end of the
+//! previous track, as most players do.
+
+use std::old_io;
+use std::old_io::{IoError, Reader};
+use std::ascii::AsciiExt;
+use std::option::Option;
+use std::result::Result;
+
+use super::{decode_lossy, File, Playlist, PlaylistItem};
+use super::excerpt::ExcerptError;
+use super::tags::Tags;
+
+/// CD frames per second, the unit of the last field of mm:ss:ff
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Clone, Debug)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    /// the UPC/EAN of the disc
+    pub catalog: Option<String>,
+    /// REM lines such as GENRE, DATE and COMMENT, with uppercased keys
+    pub comments: Vec<(String, String)>,
+    /// audio tracks only; data tracks are left out
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CueTrack {
+    pub number: u32,
+    /// resolved against the directory of the CUE sheet
+    pub file: Path,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    pub comments: Vec<(String, String)>,
+    /// seconds into file where the gap before the track starts (INDEX 00)
+    pub pregap: Option<f64>,
+    /// seconds into file where the track starts (INDEX 01)
+    pub start: f64,
+    /// seconds into file where the next track starts. None means the end
+    /// of the file.
+    pub end: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum CueError {
+    Io(IoError),
+    /// line number, starting at 1, and what is wrong
+    Parse(usize, String),
+    /// a FILE of the sheet could not be opened
+    Open(Path),
+    /// a track does not end after it starts
+    Excerpt(ExcerptError),
+}
+
+impl CueSheet {
+    /// read a CUE sheet. CUE sheets are UTF-8 or, from older rippers,
+    /// Windows-1252.
+    pub fn load(path: &Path) -> Result<CueSheet, CueError> {
+        let bytes = try!(old_io::File::open(path).and_then(|mut f| f.read_to_end())
+                         .map_err(CueError::Io));
+        CueSheet::parse(decode_lossy(bytes.as_slice()).as_slice(), &path.dir_path())
+    }
+
+    /// parse the text of a CUE sheet. FILE names are resolved against
+    /// base_dir.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<CueSheet, CueError> {
+        let mut sheet = CueSheet {
+            title: Option::None,
+            performer: Option::None,
+            songwriter: Option::None,
+            catalog: Option::None,
+            comments: Vec::new(),
+            tracks: Vec::new(),
+        };
+        let mut file: Option<Path> = Option::None;
+        // every track with whether it is an audio track. data tracks are
+        // needed to know where the audio track before them ends.
+        let mut tracks: Vec<(CueTrack, bool)> = Vec::new();
+        // the track being parsed
+        let mut track: Option<(CueTrack, bool)> = Option::None;
+        let mut start_line = 0;
+
+        let text = text.trim_left_matches('\u{feff}');
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let tokens = tokenize(line);
+            if tokens.is_empty() {
+                continue;
+            }
+            let command = tokens[0].to_ascii_uppercase();
+            let arg = |n: usize| -> Result<String, CueError> {
+                if n < tokens.len() {
+                    Result::Ok(tokens[n].clone())
+                } else {
+                    Result::Err(CueError::Parse(line_number, format!("{} needs more arguments", command)))
+                }
+            };
+            match command.as_slice() {
+                "FILE" => {
+                    try!(finish_track(&mut tracks, track.take(), start_line));
+                    file = Option::Some(resolve(base_dir, try!(arg(1)).as_slice()));
+                },
+                "TRACK" => {
+                    try!(finish_track(&mut tracks, track.take(), start_line));
+                    let path = match file {
+                        Option::Some(ref path) => path.clone(),
+                        Option::None => return Result::Err(CueError::Parse(line_number,
+                            "TRACK before FILE".to_string())),
+                    };
+                    let number = match try!(arg(1)).parse::<u32>() {
+                        Result::Ok(number) => number,
+                        Result::Err(_) => return Result::Err(CueError::Parse(line_number,
+                            "invalid track number".to_string())),
+                    };
+                    let is_audio = try!(arg(2)).eq_ignore_ascii_case("AUDIO");
+                    track = Option::Some((CueTrack {
+                        number: number,
+                        file: path,
+                        title: Option::None,
+                        performer: Option::None,
+                        songwriter: Option::None,
+                        isrc: Option::None,
+                        comments: Vec::new(),
+                        pregap: Option::None,
+                        start: -1.0,
+                        end: Option::None,
+                    }, is_audio));
+                    start_line = line_number;
+                },
+                "INDEX" => {
+                    let seconds = match parse_time(try!(arg(2)).as_slice()) {
+                        Option::Some(seconds) => seconds,
+                        Option::None => return Result::Err(CueError::Parse(line_number,
+                            "invalid time, expected mm:ss:ff".to_string())),
+                    };
+                    match track {
+                        Option::Some((ref mut track, _)) => match try!(arg(1)).parse::<u32>() {
+                            Result::Ok(0) => track.pregap = Option::Some(seconds),
+                            Result::Ok(1) => track.start = seconds,
+                            // sub indexes do not affect where tracks start
+                            Result::Ok(_) => {},
+                            Result::Err(_) => return Result::Err(CueError::Parse(line_number,
+                                "invalid index number".to_string())),
+                        },
+                        Option::None => return Result::Err(CueError::Parse(line_number,
+                            "INDEX outside of TRACK".to_string())),
+                    }
+                },
+                "TITLE" | "PERFORMER" | "SONGWRITER" => {
+                    let value = Option::Some(try!(arg(1)));
+                    match track {
+                        Option::Some((ref mut track, _)) => match command.as_slice() {
+                            "TITLE" => track.title = value,
+                            "PERFORMER" => track.performer = value,
+                            _ => track.songwriter = value,
+                        },
+                        Option::None => match command.as_slice() {
+                            "TITLE" => sheet.title = value,
+                            "PERFORMER" => sheet.performer = value,
+                            _ => sheet.songwriter = value,
+                        },
+                    }
+                },
+                "ISRC" => match track {
+                    Option::Some((ref mut track, _)) => track.isrc = Option::Some(try!(arg(1))),
+                    Option::None => {},
+                },
+                "CATALOG" => sheet.catalog = Option::Some(try!(arg(1))),
+                "REM" => {
+                    if tokens.len() >= 3 {
+                        let comment = (tokens[1].to_ascii_uppercase(), tokens[2..].connect(" "));
+                        match track {
+                            Option::Some((ref mut track, _)) => track.comments.push(comment),
+                            Option::None => sheet.comments.push(comment),
+                        }
+                    }
+                },
+                // FLAGS, PREGAP, POSTGAP, CDTEXTFILE and unknown commands do
+                // not change which audio belongs to a track
+                _ => {},
+            }
+        }
+        try!(finish_track(&mut tracks, track.take(), start_line));
+
+        // a track ends where the next track in the same file starts, audio
+        // or not
+        for i in 1..tracks.len() {
+            if tracks[i].0.file == tracks[i - 1].0.file {
+                let start = tracks[i].0.start;
+                tracks[i - 1].0.end = Option::Some(start);
+            }
+        }
+        sheet.tracks = tracks.into_iter().filter(|&(_, is_audio)| is_audio).map(|(track, _)| track).collect();
+        Result::Ok(sheet)
+    }
+
+    /// append every track to playlist as a trimmed item of its FILE, with
+    /// no gain adjustment. returns the new items in track order. each FILE
+    /// is opened once.
+    pub fn append_to(&self, playlist: &Playlist) -> Result<Vec<PlaylistItem>, CueError> {
+        let mut files: Vec<(Path, File)> = Vec::new();
+        let mut items = Vec::new();
+        for track in self.tracks.iter() {
+            let index = match files.iter().position(|&(ref path, _)| *path == track.file) {
+                Option::Some(index) => index,
+                Option::None => {
+                    let file = try!(File::open(&track.file).ok_or_else(|| CueError::Open(track.file.clone())));
+                    files.push((track.file.clone(), file));
+                    files.len() - 1
+                },
+            };
+            let item = try!(playlist.append_trimmed(&files[index].1, 1.0, 1.0, track.start, track.end)
+                            .map_err(CueError::Excerpt));
+            items.push(item);
+        }
+        Result::Ok(items)
+    }
+}
+
+impl CueTrack {
+    /// the tags of this track, filled in from the sheet where the track
+    /// does not say otherwise
+    pub fn tags(&self, sheet: &CueSheet) -> Tags {
+        let comment = |comments: &Vec<(String, String)>, key: &str| {
+            comments.iter().find(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref v)| v.clone())
+        };
+        let sheet_or_track = |key: &str| comment(&self.comments, key).or_else(|| comment(&sheet.comments, key));
+        let mut tags = Tags::new();
+        tags.title = self.title.clone();
+        tags.artist = self.performer.clone().or_else(|| sheet.performer.clone());
+        tags.album = sheet.title.clone();
+        tags.album_artist = sheet.performer.clone();
+        tags.composer = self.songwriter.clone().or_else(|| sheet.songwriter.clone());
+        tags.track = Option::Some(self.number);
+        tags.track_total = Option::Some(sheet.tracks.len() as u32);
+        tags.date = sheet_or_track("DATE");
+        tags.genre = sheet_or_track("GENRE");
+        tags.comment = sheet_or_track("COMMENT");
+        tags.isrc = self.isrc.clone();
+        tags
+    }
+}
+
+fn finish_track(tracks: &mut Vec<(CueTrack, bool)>, track: Option<(CueTrack, bool)>, line_number: usize)
+    -> Result<(), CueError>
+{
+    match track {
+        Option::Some((track, is_audio)) => {
+            if track.start < 0.0 {
+                return Result::Err(CueError::Parse(line_number, "TRACK without INDEX 01".to_string()));
+            }
+            tracks.push((track, is_audio));
+            Result::Ok(())
+        },
+        Option::None => Result::Ok(()),
+    }
+}
+
+/// mm:ss:ff, where minutes may exceed 99 for long files
+fn parse_time(s: &str) -> Option<f64> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 3 {
+        return Option::None;
+    }
+    let minutes = try_parse(parts[0]);
+    let seconds = try_parse(parts[1]);
+    let frames = try_parse(parts[2]);
+    match (minutes, seconds, frames) {
+        (Option::Some(m), Option::Some(s), Option::Some(f)) if s < 60 && f < 75 => {
+            Option::Some(m as f64 * 60.0 + s as f64 + f as f64 / FRAMES_PER_SECOND)
+        },
+        _ => Option::None,
+    }
+}
+
+fn try_parse(s: &str) -> Option<u32> {
+    s.parse::<u32>().ok()
+}
+
+fn resolve(base_dir: &Path, name: &str) -> Path {
+    // sheets written on Windows use backslashes
+    let path = Path::new(name.replace("\\", "/"));
+    if path.is_absolute() { path } else { base_dir.join(path) }
+}
+
+/// split a line into words. double quoted strings are one word.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        loop {
+            let next = chars.peek().map(|c| *c);
+            match next {
+                Option::Some(c) if c.is_whitespace() => { chars.next(); },
+                _ => break,
+            }
+        }
+        let mut token = String::new();
+        match chars.next() {
+            Option::None => break,
+            Option::Some('"') => loop {
+                match chars.next() {
+                    Option::Some('"') | Option::None => break,
+                    Option::Some(c) => token.push(c),
+                }
+            },
+            Option::Some(c) => {
+                token.push(c);
+                loop {
+                    let next = chars.peek().map(|c| *c);
+                    match next {
+                        Option::Some(c) if !c.is_whitespace() => {
+                            token.push(c);
+                            chars.next();
+                        },
+                        _ => break,
+                    }
+                }
+            },
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_time, tokenize, CueError, CueSheet};
+    use super::super::{AudioFormat, ChannelLayout, Encoder, LoudnessDetector, Playlist, SampleFormat,
+                       SampleType, Sink};
+    use super::super::excerpt::TRIMMED_PLAYLIST;
+    use std::old_io;
+    use std::old_io::{fs, Writer};
+    use std::os;
+
+    const SHEET: &'static str = "\u{feff}REM GENRE Rock\r
+REM DATE 1991\r
+CATALOG 0123456789012\r
+PERFORMER \"The Band\"\r
+TITLE \"Live at Home\"\r
+FILE \"disc.flac\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"Intro\"\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    TITLE \"Song\"\r
+    PERFORMER \"Guest\"\r
+    ISRC USABC9100001\r
+    INDEX 00 03:58:50\r
+    INDEX 01 04:00:00\r
+    INDEX 02 04:30:00\r
+  TRACK 03 MODE1/2352\r
+    INDEX 01 10:00:00\r
+FILE \"bonus.wav\" WAVE\r
+  TRACK 04 AUDIO\r
+    TITLE \"Bonus\"\r
+    REM COMMENT hidden track\r
+    INDEX 01 00:01:37\r
+";
+
+    fn parse(text: &str) -> Result<CueSheet, CueError> {
+        CueSheet::parse(text, &Path::new("/music/album"))
+    }
+
+    #[test]
+    fn parses_sheet_and_tracks() {
+        let sheet = parse(SHEET).unwrap();
+        assert_eq!(sheet.performer, Some("The Band".to_string()));
+        assert_eq!(sheet.catalog, Some("0123456789012".to_string()));
+        assert_eq!(sheet.comments, vec![("GENRE".to_string(), "Rock".to_string()),
+                                        ("DATE".to_string(), "1991".to_string())]);
+        let numbers: Vec<u32> = sheet.tracks.iter().map(|t| t.number).collect();
+        assert_eq!(numbers, vec![1, 2, 4]);
+
+        let song = &sheet.tracks[1];
+        assert_eq!(song.file, Path::new("/music/album/disc.flac"));
+        assert_eq!(song.title, Some("Song".to_string()));
+        assert_eq!(song.performer, Some("Guest".to_string()));
+        assert_eq!(song.isrc, Some("USABC9100001".to_string()));
+        assert_eq!(song.pregap, Some(238.0 + 50.0 / 75.0));
+        assert_eq!(song.start, 240.0);
+
+        let bonus = &sheet.tracks[2];
+        assert_eq!(bonus.file, Path::new("/music/album/bonus.wav"));
+        assert_eq!(bonus.start, 1.0 + 37.0 / 75.0);
+        assert_eq!(bonus.end, None);
+        assert_eq!(bonus.comments, vec![("COMMENT".to_string(), "hidden track".to_string())]);
+    }
+
+    #[test]
+    fn tracks_end_at_the_next_index_01() {
+        let sheet = parse(SHEET).unwrap();
+        // the gap before track 2 stays with track 1
+        assert_eq!(sheet.tracks[0].end, Some(240.0));
+        // the data track after track 2 ends it
+        assert_eq!(sheet.tracks[1].end, Some(600.0));
+    }
+
+    #[test]
+    fn tags_fall_back_to_the_sheet() {
+        let sheet = parse(SHEET).unwrap();
+        let tags = sheet.tracks[0].tags(&sheet);
+        assert_eq!(tags.title, Some("Intro".to_string()));
+        assert_eq!(tags.artist, Some("The Band".to_string()));
+        assert_eq!(tags.album_artist, Some("The Band".to_string()));
+        assert_eq!(tags.track, Some(1));
+        assert_eq!(tags.track_total, Some(3));
+        assert_eq!(tags.genre, Some("Rock".to_string()));
+        assert_eq!(sheet.tracks[1].tags(&sheet).artist, Some("Guest".to_string()));
+    }
+
+    #[test]
+    fn rejects_broken_sheets() {
+        match parse("TRACK 01 AUDIO\n") {
+            Err(CueError::Parse(1, _)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nTITLE x\n") {
+            Err(CueError::Parse(2, _)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:60:00\n").is_err());
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("00:00:00"), Some(0.0));
+        assert_eq!(parse_time("120:01:74"), Some(7201.0 + 74.0 / 75.0));
+        assert_eq!(parse_time("00:00:75"), None);
+        assert_eq!(parse_time("1:2"), None);
+    }
+
+    #[test]
+    fn tokenizes_quoted_words() {
+        assert_eq!(tokenize("  FILE \"My Album.flac\"  WAVE"),
+                   vec!["FILE".to_string(), "My Album.flac".to_string(), "WAVE".to_string()]);
+        assert_eq!(tokenize("TITLE \"\""), vec!["TITLE".to_string(), "".to_string()]);
+        assert!(tokenize("   ").is_empty());
+    }
+
+    /// a mono 16 bit WAV file
+    fn write_wav(path: &Path, sample_rate: u32, samples: &[i16]) {
+        let mut file = old_io::File::create(path).unwrap();
+        let data_size = samples.len() as u32 * 2;
+        file.write_all(b"RIFF").unwrap();
+        file.write_le_u32(36 + data_size).unwrap();
+        file.write_all(b"WAVEfmt ").unwrap();
+        file.write_le_u32(16).unwrap();
+        file.write_le_u16(1).unwrap();
+        file.write_le_u16(1).unwrap();
+        file.write_le_u32(sample_rate).unwrap();
+        file.write_le_u32(sample_rate * 2).unwrap();
+        file.write_le_u16(2).unwrap();
+        file.write_le_u16(16).unwrap();
+        file.write_all(b"data").unwrap();
+        file.write_le_u32(data_size).unwrap();
+        for &sample in samples.iter() {
+            file.write_le_i16(sample).unwrap();
+        }
+    }
+
+    #[test]
+    fn tracks_play_as_trimmed_items() {
+        let format = AudioFormat {
+            sample_rate: 8000,
+            channel_layout: ChannelLayout::LayoutMono,
+            sample_fmt: SampleFormat { sample_type: SampleType::S16, planar: false },
+        };
+        // three seconds of a saw wave
+        let path = os::tmpdir().join("groove-cue-test.wav");
+        let samples: Vec<i16> = (0..3 * 8000).map(|i: i32| ((i % 100) * 200 - 10000) as i16).collect();
+        write_wav(&path, 8000, samples.as_slice());
+        let text = format!("FILE \"{}\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+                            TRACK 02 AUDIO\nINDEX 01 00:01:00\n", path.display());
+        let sheet = CueSheet::parse(text.as_slice(), &Path::new("/")).unwrap();
+        let playlist = Playlist::new();
+        let items = sheet.append_to(&playlist).unwrap();
+        assert_eq!(items.len(), 2);
+
+        assert_eq!(Encoder::new().attach(&playlist), Result::Err(TRIMMED_PLAYLIST));
+        assert_eq!(LoudnessDetector::new().attach(&playlist), Result::Err(TRIMMED_PLAYLIST));
+
+        let sink = Sink::new();
+        sink.set_audio_format(format);
+        sink.attach(&playlist).unwrap();
+        let mut frames = [0, 0];
+        let mut first_samples = Vec::new();
+        loop {
+            let buffer = match sink.buffer_get_blocking() {
+                Option::Some(buffer) => buffer,
+                Option::None => break,
+            };
+            let index = items.iter().position(|item| *item == buffer.item()).unwrap();
+            if frames[index] == 0 {
+                first_samples.push(buffer.as_slice_i16()[0]);
+            }
+            frames[index] += buffer.frame_count();
+        }
+        sink.detach();
+        assert_eq!(frames, [8000, 16000]);
+        // track 2 starts at frame 8000, a multiple of the saw's period
+        assert_eq!(first_samples, vec![-10000, -10000]);
+        drop(playlist);
+        fs::unlink(&path).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! find recordings which are in a music library more than once.
+//!
+//! Every file is fingerprinted, in parallel, and files whose durations are
+//! close and whose fingerprints match are grouped together. Matching is by
+//! sound, not by tags or bytes, so an MP3 and a FLAC of the same recording
+//! end up in the same group. For each copy the group reports what helps to
+//! choose which one to keep: the container, the sample format, the bit
+//! rate and how many of the common tags are filled in.
+//!
+//! libgroove does not expose the stream bit rate, so bit_rate is the
+//! average over the whole file, including tags and cover art.
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::num::Float;
+use std::old_io::{fs, IoResult};
+use std::option::Option;
+use std::result::Result;
+
+use super::{find_audio_files, AudioFormat, File};
+use super::fingerprint::{self, Fingerprint, FingerprintError};
+use super::tags::Tags;
+
+#[derive(Copy, Debug)]
+pub struct Options {
+    /// fingerprint score, from 0.5 to 1, at which files are duplicates
+    pub min_score: f64,
+    /// seconds the durations of duplicates may differ
+    pub duration_tolerance: f64,
+    /// seconds from the start of each fingerprint which are compared
+    pub compare_duration: f64,
+    /// seconds either way by which copies may be shifted, for example by
+    /// encoder delay or leading silence
+    pub max_offset: f64,
+    /// files fingerprinted at the same time
+    pub threads: usize,
+}
+
+impl Options {
+    /// a score of 0.8 and durations within 3 seconds, comparing two minutes
+    /// with up to 2 seconds of offset, on 4 threads
+    pub fn default() -> Options {
+        Options {
+            min_score: 0.8,
+            duration_tolerance: 3.0,
+            compare_duration: 120.0,
+            max_offset: 2.0,
+            threads: 4,
+        }
+    }
+}
+
+/// one copy of a recording
+pub struct Duplicate {
+    pub path: Path,
+    pub duration: f64,
+    /// None if the file can no longer be opened
+    pub audio_format: Option<AudioFormat>,
+    /// the lowercased file extension
+    pub format: String,
+    /// average bits per second of the file
+    pub bit_rate: Option<u32>,
+    /// the common tags which are missing, out of TAG_FIELDS
+    pub missing_tags: Vec<&'static str>,
+}
+
+/// the tags counted for tag completeness
+pub const TAG_FIELDS: [&'static str; 7] = ["title", "artist", "album", "album_artist", "track", "date", "genre"];
+
+impl Duplicate {
+    /// the fraction of TAG_FIELDS which are present, from 0 to 1
+    pub fn tag_completeness(&self) -> f64 {
+        1.0 - self.missing_tags.len() as f64 / TAG_FIELDS.len() as f64
+    }
+}
+
+/// copies of the same recording, in the order of their paths
+pub struct Group {
+    pub copies: Vec<Duplicate>,
+    /// the lowest fingerprint score between a copy and the copy it was
+    /// matched with
+    pub min_score: f64,
+}
+
+pub struct Report {
+    pub groups: Vec<Group>,
+    /// number of files which were fingerprinted
+    pub scanned: usize,
+    /// files which could not be fingerprinted
+    pub errors: Vec<FingerprintError>,
+}
+
+/// fingerprint every audio file in the given directories, recursively, see
+/// find_audio_files, and group the duplicates. paths which are files are
+/// included directly. files found in a directory which turn out to have no
+/// audio stream are skipped, named files are reported as errors.
+pub fn find(paths: &[Path], options: &Options) -> IoResult<Report> {
+    let mut inputs = try!(find_audio_files(paths));
+    inputs.sort();
+    let files: Vec<Path> = inputs.iter().map(|&(ref path, _)| path.clone()).collect();
+
+    let mut fingerprints = Vec::new();
+    let mut errors = Vec::new();
+    let results = fingerprint::fingerprint_files(files.as_slice(), options.threads);
+    for (result, &(_, explicit)) in results.into_iter().zip(inputs.iter()) {
+        match result {
+            Result::Ok(fingerprint) => fingerprints.push(fingerprint),
+            Result::Err(FingerprintError::Open(_)) if !explicit => {},
+            Result::Err(err) => errors.push(err),
+        }
+    }
+
+    let groups = group(fingerprints.as_slice(), options).into_iter().map(|(members, min_score)| {
+        Group {
+            copies: members.into_iter().map(|index| describe(&fingerprints[index])).collect(),
+            min_score: min_score,
+        }
+    }).collect();
+    Result::Ok(Report {
+        groups: groups,
+        scanned: fingerprints.len(),
+        errors: errors,
+    })
+}
+
+/// the indexes of fingerprints which are duplicates of each other, in
+/// groups of two or more, with the lowest score which joined each group
+pub fn group(fingerprints: &[Fingerprint], options: &Options) -> Vec<(Vec<usize>, f64)> {
+    let compare_items = (options.compare_duration / fingerprint::ITEM_DURATION) as usize;
+    let max_offset = (options.max_offset / fingerprint::ITEM_DURATION) as usize;
+
+    // compare only neighbours in duration order which are close enough
+    let mut by_duration: Vec<usize> = (0..fingerprints.len()).collect();
+    by_duration.sort_by(|&a, &b| fingerprints[a].duration.partial_cmp(&fingerprints[b].duration).unwrap());
+
+    let mut parents: Vec<usize> = (0..fingerprints.len()).collect();
+    let mut scores: Vec<f64> = fingerprints.iter().map(|_| 1.0).collect();
+    for (position, &a) in by_duration.iter().enumerate() {
+        for &b in by_duration[position + 1..].iter() {
+            if fingerprints[b].duration - fingerprints[a].duration > options.duration_tolerance {
+                break;
+            }
+            let a_items = prefix(fingerprints[a].fingerprint.as_slice(), compare_items);
+            let b_items = prefix(fingerprints[b].fingerprint.as_slice(), compare_items);
+            // at least half of the shorter prefix must overlap
+            let min_overlap = cmp::min(a_items.len(), b_items.len()) / 2;
+            let score = match fingerprint::compare(a_items, b_items, max_offset, min_overlap) {
+                Option::Some(found) => found.score,
+                Option::None => continue,
+            };
+            if score < options.min_score {
+                continue;
+            }
+            let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+            if root_a != root_b {
+                parents[root_b] = root_a;
+            }
+            let lowest = scores[root_a].min(scores[root_b]).min(score);
+            scores[root_a] = lowest;
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for index in 0..fingerprints.len() {
+        let root = find_root(&mut parents, index);
+        match groups.iter().position(|&(group_root, _)| group_root == root) {
+            Option::Some(position) => groups[position].1.push(index),
+            Option::None => groups.push((root, vec![index])),
+        }
+    }
+    groups.into_iter()
+        .filter(|&(_, ref members)| members.len() > 1)
+        .map(|(root, members)| (members, scores[root]))
+        .collect()
+}
+
+fn prefix(items: &[i32], count: usize) -> &[i32] {
+    if items.len() > count {&items[..count]} else {items}
+}
+
+fn find_root(parents: &mut Vec<usize>, index: usize) -> usize {
+    let mut root = index;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    // path compression
+    let mut node = index;
+    while parents[node] != root {
+        let next = parents[node];
+        parents[node] = root;
+        node = next;
+    }
+    root
+}
+
+fn describe(fingerprint: &Fingerprint) -> Duplicate {
+    let path = &fingerprint.path;
+    let format = path.extension().map(|extension| {
+        String::from_utf8_lossy(extension).into_owned().to_ascii_lowercase()
+    }).unwrap_or(String::new());
+    let bit_rate = match fs::stat(path) {
+        Result::Ok(stat) if fingerprint.duration > 0.0 =>
+            Option::Some((stat.size as f64 * 8.0 / fingerprint.duration) as u32),
+        _ => Option::None,
+    };
+    let (audio_format, missing_tags) = match File::open(path) {
+        Option::Some(file) => {
+            let tags = Tags::read(&file);
+            let present = [
+                tags.title.is_some(),
+                tags.artist.is_some(),
+                tags.album.is_some(),
+                tags.album_artist.is_some(),
+                tags.track.is_some(),
+                tags.date.is_some(),
+                tags.genre.is_some(),
+            ];
+            let missing = TAG_FIELDS.iter().zip(present.iter())
+                .filter(|&(_, &present)| !present)
+                .map(|(&field, _)| field)
+                .collect();
+            (Option::Some(file.audio_format()), missing)
+        },
+        // it was decoded a moment ago, so it was removed since
+        Option::None => (Option::None, TAG_FIELDS.to_vec()),
+    };
+    Duplicate {
+        path: path.clone(),
+        duration: fingerprint.duration,
+        audio_format: audio_format,
+        format: format,
+        bit_rate: bit_rate,
+        missing_tags: missing_tags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Int;
+
+    use super::{group, Options};
+    use super::super::fingerprint::Fingerprint;
+
+    fn track(path: &str, duration: f64, seed: i32) -> Fingerprint {
+        let mut item = seed;
+        let fingerprint = (0..200).map(|_| {
+            item = item.wrapping_mul(1103515245).wrapping_add(12345);
+            item
+        }).collect();
+        Fingerprint { path: Path::new(path), duration: duration, fingerprint: fingerprint }
+    }
+
+    #[test]
+    fn groups_close_matches() {
+        let mut tracks = vec![
+            track("a.flac", 180.0, 1),
+            track("b.mp3", 181.0, 1),
+            track("c.ogg", 183.5, 1),
+            // unrelated, with a duration in between
+            track("d.flac", 180.5, 2),
+            // the same audio, but far too long
+            track("e.flac", 300.0, 1),
+            track("f.flac", 240.0, 3),
+            track("g.mp3", 240.0, 3),
+        ];
+        // a few differing bits in b
+        for item in tracks[1].fingerprint.iter_mut().take(20) {
+            *item ^= 1 << 20;
+        }
+        let groups = group(tracks.as_slice(), &Options::default());
+        assert_eq!(groups.len(), 2);
+        // c is more than 3 seconds longer than a, but close to b
+        assert_eq!(groups[0].0, vec![0, 1, 2]);
+        assert_eq!(groups[0].1, 1.0 - 20.0 / (32.0 * 200.0));
+        assert_eq!(groups[1].0, vec![5, 6]);
+        assert_eq!(groups[1].1, 1.0);
+    }
+
+    #[test]
+    fn no_groups_of_one() {
+        let tracks = vec![track("a.flac", 180.0, 1), track("b.flac", 180.0, 2)];
+        assert!(group(tracks.as_slice(), &Options::default()).is_empty());
+        assert!(group(&[], &Options::default()).is_empty());
+    }
+}
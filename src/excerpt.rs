@@ -0,0 +1,104 @@
+//! playlist items with in and out points.
+//!
+//! libgroove always decodes a playlist item from the start to the end of
+//! its file. A trimmed item remembers its in and out points, and Sink
+//! enforces them while decoding: buffers are cut to the frame at both
+//! points, and a Sink which is alone on the playlist also seeks past the
+//! audio outside them. No audio is decoded ahead of time and nothing is
+//! written to disk. See Sink::buffer_get_blocking.
+//!
+//! Encoder, LoudnessDetector and Fingerprinter read the decoded audio
+//! inside libgroove, where the points can not be applied, so they refuse
+//! to attach to a playlist with trimmed items, and trimmed items can not
+//! be added while one of them is attached.
+
+use std::option::Option;
+use std::result::Result;
+
+use super::{consumers, File, Playlist, PlaylistItem, ITEM_RANGES, item_range};
+
+/// the error code of attaching an Encoder, LoudnessDetector or
+/// Fingerprinter to a playlist with trimmed items. made from a tag like
+/// the error codes of libav, "TRIM".
+pub const TRIMMED_PLAYLIST: i32 = -0x4d495254;
+
+/// a region of a file in seconds
+#[derive(Copy, Debug, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    /// None means the end of the file
+    pub end: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum ExcerptError {
+    /// start is negative or end is not after start
+    InvalidRange(Range),
+    /// an Encoder, LoudnessDetector or Fingerprinter is attached to the
+    /// playlist
+    Untrimmable,
+}
+
+impl Playlist {
+    /// like append, but the item plays file only from start to end seconds.
+    /// end None means the end of the file.
+    pub fn append_trimmed(&self, file: &File, gain: f64, peak: f64, start: f64, end: Option<f64>)
+        -> Result<PlaylistItem, ExcerptError>
+    {
+        let range = try!(self.trim_range(start, end));
+        let item = self.append(file, gain, peak);
+        set_range(&item, range);
+        Result::Ok(item)
+    }
+
+    /// like insert, but the item plays file only from start to end seconds.
+    /// end None means the end of the file.
+    pub fn insert_trimmed(&self, file: &File, gain: f64, peak: f64, start: f64, end: Option<f64>,
+                          before: &PlaylistItem) -> Result<PlaylistItem, ExcerptError>
+    {
+        let range = try!(self.trim_range(start, end));
+        let item = self.insert(file, gain, peak, before);
+        set_range(&item, range);
+        Result::Ok(item)
+    }
+
+    /// whether any item of the playlist was trimmed
+    pub fn has_trimmed_items(&self) -> bool {
+        self.iter().any(|item| item_range(item.groove_playlist_item).is_some())
+    }
+
+    fn trim_range(&self, start: f64, end: Option<f64>) -> Result<Range, ExcerptError> {
+        if consumers(self.groove_playlist).others > 0 {
+            return Result::Err(ExcerptError::Untrimmable);
+        }
+        check_range(start, end)
+    }
+}
+
+impl PlaylistItem {
+    /// seconds into the file where this item starts. 0 for items which
+    /// were not trimmed.
+    pub fn in_point(&self) -> f64 {
+        item_range(self.groove_playlist_item).map_or(0.0, |range| range.start)
+    }
+
+    /// seconds into the file where this item ends. None means the end of
+    /// the file.
+    pub fn out_point(&self) -> Option<f64> {
+        item_range(self.groove_playlist_item).and_then(|range| range.end)
+    }
+}
+
+fn check_range(start: f64, end: Option<f64>) -> Result<Range, ExcerptError> {
+    let range = Range { start: start, end: end };
+    if start < 0.0 || end.map_or(false, |end| end <= start) {
+        Result::Err(ExcerptError::InvalidRange(range))
+    } else {
+        Result::Ok(range)
+    }
+}
+
+/// the item is forgotten again by Playlist::remove and Playlist::clear
+fn set_range(item: &PlaylistItem, range: Range) {
+    ITEM_RANGES.lock().unwrap().insert(item.groove_playlist_item as usize, range);
+}
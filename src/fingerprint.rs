@@ -0,0 +1,213 @@
+//! fingerprinting many files at once, and comparing fingerprints.
+//!
+//! Fingerprints are the raw chromaprint fingerprints of Fingerprinter: one
+//! 32 bit integer for every ITEM_DURATION seconds of audio. Two fingerprints
+//! are compared by aligning them and counting the bits which differ.
+//! Unrelated audio differs in about half of the bits; the same recording
+//! in another format or bit rate differs in far fewer.
+
+use std::cmp;
+use std::num::Int;
+use std::option::Option;
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread::Thread;
+
+use super::{File, Fingerprinter, Playlist};
+
+/// seconds of audio per fingerprint item
+pub const ITEM_DURATION: f64 = 0.1238;
+
+#[derive(Clone, Debug)]
+pub struct Fingerprint {
+    pub path: Path,
+    /// duration in seconds, measured by decoding
+    pub duration: f64,
+    pub fingerprint: Vec<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FingerprintError {
+    /// the file could not be opened or has no audio stream
+    Open(Path),
+    /// attaching the fingerprinter failed with this error code
+    Groove(Path, i32),
+    /// the fingerprinter produced nothing for the file
+    NoFingerprint(Path),
+}
+
+/// how well two fingerprints match at their best alignment
+#[derive(Copy, Debug, PartialEq)]
+pub struct Match {
+    /// the fraction of bits which are equal, from 0 to 1. about 0.5 for
+    /// unrelated audio.
+    pub score: f64,
+    /// items by which b is late compared to a. item i of a lines up with
+    /// item i + offset of b.
+    pub offset: isize,
+}
+
+impl Match {
+    /// the offset in seconds
+    pub fn offset_seconds(&self) -> f64 {
+        self.offset as f64 * ITEM_DURATION
+    }
+}
+
+/// fingerprint the file at path with a Fingerprinter and Playlist of its
+/// own
+pub fn fingerprint_file(path: &Path) -> Result<Fingerprint, FingerprintError> {
+    let file = try!(File::open(path).ok_or(FingerprintError::Open(path.clone())));
+    let playlist = Playlist::new();
+    let fingerprinter = Fingerprinter::new();
+    playlist.append(&file, 1.0, 1.0);
+    match fingerprinter.attach(&playlist) {
+        Result::Ok(()) => {},
+        Result::Err(err_code) => return Result::Err(FingerprintError::Groove(path.clone(), err_code)),
+    }
+    let info = fingerprinter.info_get_blocking();
+    fingerprinter.detach();
+    playlist.clear();
+    match info {
+        Option::Some(info) => Result::Ok(Fingerprint {
+            path: path.clone(),
+            duration: info.duration,
+            fingerprint: info.fingerprint,
+        }),
+        Option::None => Result::Err(FingerprintError::NoFingerprint(path.clone())),
+    }
+}
+
+/// fingerprint every path on threads threads at a time. each thread
+/// decodes one file at a time through its own playlist. the results are in
+/// the order of paths.
+pub fn fingerprint_files(paths: &[Path], threads: usize) -> Vec<Result<Fingerprint, FingerprintError>> {
+    let queue: Vec<(usize, Path)> = paths.iter().map(|path| path.clone()).enumerate().collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let (sender, receiver) = channel();
+    let guards: Vec<_> = (0..cmp::max(threads, 1)).map(|_| {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        Thread::scoped(move || {
+            loop {
+                let next = queue.lock().unwrap().pop();
+                match next {
+                    Option::Some((index, path)) => {
+                        let _ = sender.send((index, fingerprint_file(&path)));
+                    },
+                    Option::None => break,
+                }
+            }
+        })
+    }).collect();
+    drop(sender);
+
+    let mut results: Vec<Option<Result<Fingerprint, FingerprintError>>> = paths.iter().map(|_| Option::None).collect();
+    for (index, result) in receiver.iter() {
+        results[index] = Option::Some(result);
+    }
+    for guard in guards.into_iter() {
+        let _ = guard.join();
+    }
+    results.into_iter().zip(paths.iter()).map(|(result, path)| {
+        // only missing if a thread panicked
+        result.unwrap_or_else(|| Result::Err(FingerprintError::NoFingerprint(path.clone())))
+    }).collect()
+}
+
+/// the best match of a and b with b shifted by up to max_offset items
+/// either way. offsets which leave fewer than min_overlap items overlapping
+/// are not tried; None if no offset is left.
+pub fn compare(a: &[i32], b: &[i32], max_offset: usize, min_overlap: usize) -> Option<Match> {
+    let min_overlap = cmp::max(min_overlap, 1);
+    let mut best: Option<Match> = Option::None;
+    let max_offset = max_offset as isize;
+    for offset in -max_offset..max_offset + 1 {
+        let start = if offset < 0 {(-offset) as usize} else {0};
+        let end = cmp::min(a.len() as isize, b.len() as isize - offset);
+        if end < (start + min_overlap) as isize {
+            continue;
+        }
+        let end = end as usize;
+        let mut errors = 0;
+        for i in start..end {
+            errors += (a[i] ^ b[(i as isize + offset) as usize]).count_ones() as usize;
+        }
+        let score = 1.0 - errors as f64 / (32 * (end - start)) as f64;
+        let better = match best {
+            Option::Some(ref best) => score > best.score,
+            Option::None => true,
+        };
+        if better {
+            best = Option::Some(Match { score: score, offset: offset });
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Int;
+    use std::option::Option;
+
+    use super::compare;
+
+    fn items(seed: i32, len: usize) -> Vec<i32> {
+        let mut item = seed;
+        (0..len).map(|_| {
+            item = item.wrapping_mul(1103515245).wrapping_add(12345);
+            item
+        }).collect()
+    }
+
+    #[test]
+    fn compare_identical() {
+        let a = items(1, 100);
+        let found = compare(a.as_slice(), a.as_slice(), 5, 50).unwrap();
+        assert_eq!(found.score, 1.0);
+        assert_eq!(found.offset, 0);
+    }
+
+    #[test]
+    fn compare_finds_shifts_either_way() {
+        let a = items(1, 100);
+        // b starts 3 items later
+        let mut b = items(2, 3);
+        b.push_all(a.as_slice());
+        let found = compare(a.as_slice(), b.as_slice(), 5, 50).unwrap();
+        assert_eq!(found.score, 1.0);
+        assert_eq!(found.offset, 3);
+        let found = compare(b.as_slice(), a.as_slice(), 5, 50).unwrap();
+        assert_eq!(found.score, 1.0);
+        assert_eq!(found.offset, -3);
+        // too far to be found
+        let found = compare(a.as_slice(), b.as_slice(), 2, 50).unwrap();
+        assert!(found.score < 0.8);
+    }
+
+    #[test]
+    fn compare_counts_differing_bits() {
+        let a = items(1, 100);
+        let mut b = a.clone();
+        for item in b.iter_mut().take(10) {
+            *item ^= 0x10001;
+        }
+        let found = compare(a.as_slice(), b.as_slice(), 0, 1).unwrap();
+        assert_eq!(found.score, 1.0 - 20.0 / (32.0 * 100.0));
+    }
+
+    #[test]
+    fn compare_unrelated() {
+        let found = compare(items(1, 100).as_slice(), items(7, 100).as_slice(), 5, 50).unwrap();
+        assert!(found.score > 0.3 && found.score < 0.7);
+    }
+
+    #[test]
+    fn compare_needs_min_overlap() {
+        let a = items(1, 10);
+        assert_eq!(compare(a.as_slice(), a.as_slice(), 3, 11), Option::None);
+        assert!(compare(a.as_slice(), a.as_slice(), 3, 10).is_some());
+        assert_eq!(compare(a.as_slice(), &[], 3, 0), Option::None);
+    }
+}
@@ -0,0 +1,441 @@
+//! a local index of fingerprints, and identifying tracks from excerpts.
+//!
+//! An Index holds the fingerprint of every track of a collection and can
+//! be saved to and loaded from a file, so the collection only has to be
+//! decoded once. Given the fingerprint of an excerpt, such as a few
+//! seconds recorded off the air, it finds the tracks the excerpt was taken
+//! from and where in them it starts, without any network service.
+//!
+//! Candidates are found by looking up the high bits of every fingerprint
+//! item of the excerpt and voting for track and offset, and then checked by
+//! comparing every bit at the offsets with the most votes. Fingerprint
+//! items are ITEM_DURATION seconds apart, so the offset is only that
+//! precise, and excerpts which are very short or start between two items
+//! score lower than the same audio taken from the start of a file.
+//!
+//! The file is text: a header line, then a line per track with the
+//! duration, the fingerprint in the compressed form of
+//! Fingerprinter::encode and the path, separated by tabs. Bytes of the
+//! path which are not printable ASCII, and %, are written as %XX.
+
+use std::collections::HashMap;
+use std::cmp;
+use std::old_io;
+use std::old_io::{fs, IoError, IoResult, Reader, Writer};
+use std::num::Int;
+use std::option::Option;
+use std::result::Result;
+
+use super::{temp_path, DecodedBuffer, File, Fingerprinter, WavWriter};
+use super::fingerprint::{self, Fingerprint, FingerprintError, ITEM_DURATION};
+
+const HEADER: &'static str = "groove-fingerprint-index 1";
+
+/// items are looked up by this many of their high bits, which are the
+/// least likely to change when the audio is encoded again
+const KEY_BITS: usize = 20;
+
+/// how many of the offsets with the most votes are checked bit by bit
+const MAX_CANDIDATES: usize = 20;
+
+#[derive(Debug)]
+pub enum IndexError {
+    Io(IoError),
+    /// line number, starting at 1, and what is wrong with it
+    Parse(usize, String),
+    /// chromaprint could not compress the fingerprint of this track
+    Encode(Path),
+    Fingerprint(FingerprintError),
+    /// the buffers passed to identify_buffers are not all in the same
+    /// format
+    MixedFormats,
+}
+
+/// where an excerpt was found
+#[derive(Clone, Debug)]
+pub struct Identification {
+    pub path: Path,
+    /// seconds into the track where the excerpt starts
+    pub offset: f64,
+    /// the fraction of fingerprint bits which are equal, from 0 to 1.
+    /// about 0.5 for unrelated audio.
+    pub score: f64,
+}
+
+pub struct Index {
+    tracks: Vec<Fingerprint>,
+    /// the path of each track to its index in tracks
+    paths: HashMap<Path, usize>,
+    /// KEY_BITS of an item to the tracks and positions which have it
+    lookup: HashMap<u32, Vec<(usize, usize)>>,
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index {
+            tracks: Vec::new(),
+            paths: HashMap::new(),
+            lookup: HashMap::new(),
+        }
+    }
+
+    /// fingerprint every path, threads at a time, and add the tracks.
+    /// returns the paths which could not be fingerprinted.
+    pub fn add_files(&mut self, paths: &[Path], threads: usize) -> Vec<FingerprintError> {
+        let mut errors = Vec::new();
+        for result in fingerprint::fingerprint_files(paths, threads).into_iter() {
+            match result {
+                Result::Ok(track) => self.add(track),
+                Result::Err(err) => errors.push(err),
+            }
+        }
+        errors
+    }
+
+    /// add a track, replacing any track with the same path
+    pub fn add(&mut self, track: Fingerprint) {
+        if self.paths.contains_key(&track.path) {
+            self.remove(&track.path);
+        }
+        let index = self.tracks.len();
+        for (position, &item) in track.fingerprint.iter().enumerate() {
+            self.insert_key(key(item), (index, position));
+        }
+        self.paths.insert(track.path.clone(), index);
+        self.tracks.push(track);
+    }
+
+    /// returns whether there was a track with this path
+    pub fn remove(&mut self, path: &Path) -> bool {
+        match self.paths.get(path).map(|&index| index) {
+            Option::Some(index) => {
+                self.tracks.remove(index);
+                self.rebuild_lookup();
+                true
+            },
+            Option::None => false,
+        }
+    }
+
+    pub fn tracks(&self) -> &[Fingerprint] {
+        self.tracks.as_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn load(path: &Path) -> Result<Index, IndexError> {
+        let bytes = try!(old_io::File::open(path).and_then(|mut f| f.read_to_end()).map_err(IndexError::Io));
+        let text = String::from_utf8_lossy(bytes.as_slice()).into_owned();
+        let mut index = Index::new();
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            if number == 1 {
+                if line != HEADER {
+                    return Result::Err(IndexError::Parse(number, "not a fingerprint index".to_string()));
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 3 {
+                return Result::Err(IndexError::Parse(number, "expected 3 fields".to_string()));
+            }
+            let duration = match fields[0].parse::<f64>().ok() {
+                Option::Some(duration) => duration,
+                Option::None => return Result::Err(IndexError::Parse(number, "bad duration".to_string())),
+            };
+            let items = match Fingerprinter::decode(fields[1]) {
+                Option::Some(items) => items,
+                Option::None => return Result::Err(IndexError::Parse(number, "bad fingerprint".to_string())),
+            };
+            let track_path = match unescape_path(fields[2]) {
+                Option::Some(track_path) => track_path,
+                Option::None => return Result::Err(IndexError::Parse(number, "bad path".to_string())),
+            };
+            index.add(Fingerprint { path: track_path, duration: duration, fingerprint: items });
+        }
+        Result::Ok(index)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), IndexError> {
+        let mut out = String::from_str(HEADER);
+        out.push('\n');
+        for track in self.tracks.iter() {
+            let encoded = try!(Fingerprinter::encode(track.fingerprint.as_slice())
+                               .ok_or(IndexError::Encode(track.path.clone())));
+            out.push_str(format!("{}\t{}\t{}\n", track.duration, encoded, escape_path(&track.path)).as_slice());
+        }
+        let mut f = try!(old_io::File::create(path).map_err(IndexError::Io));
+        f.write_str(out.as_slice()).map_err(IndexError::Io)
+    }
+
+    /// the tracks which excerpt matches with at least min_score, best
+    /// first, with one identification per track. 0.7 is a reasonable
+    /// min_score for clean recordings.
+    pub fn identify(&self, excerpt: &[i32], min_score: f64) -> Vec<Identification> {
+        // votes for (track, offset of the excerpt start in the track)
+        let mut votes: HashMap<(usize, isize), usize> = HashMap::new();
+        for (i, &item) in excerpt.iter().enumerate() {
+            let positions = match self.lookup.get(&key(item)) {
+                Option::Some(positions) => positions,
+                Option::None => continue,
+            };
+            for &(track, position) in positions.iter() {
+                let candidate = (track, position as isize - i as isize);
+                let counted = match votes.get_mut(&candidate) {
+                    Option::Some(count) => {
+                        *count += 1;
+                        true
+                    },
+                    Option::None => false,
+                };
+                if !counted {
+                    votes.insert(candidate, 1);
+                }
+            }
+        }
+        let mut candidates: Vec<((usize, isize), usize)> = votes.into_iter().collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1));
+        candidates.truncate(MAX_CANDIDATES);
+
+        let min_overlap = cmp::max(excerpt.len() / 2, 1);
+        let mut found: Vec<Identification> = Vec::new();
+        for &((track, offset), _) in candidates.iter() {
+            let items = self.tracks[track].fingerprint.as_slice();
+            // look one item either way, for excerpts which start between items
+            let best = (offset - 1..offset + 2)
+                .filter_map(|offset| score_at(excerpt, items, offset, min_overlap).map(|score| (offset, score)))
+                .fold(Option::None, |best: Option<(isize, f64)>, (offset, score)| match best {
+                    Option::Some((_, best_score)) if best_score >= score => best,
+                    _ => Option::Some((offset, score)),
+                });
+            let (offset, score) = match best {
+                Option::Some(best) => best,
+                Option::None => continue,
+            };
+            if score < min_score {
+                continue;
+            }
+            let path = &self.tracks[track].path;
+            match found.iter().position(|identification| identification.path == *path) {
+                Option::Some(existing) => if found[existing].score < score {
+                    found[existing].score = score;
+                    found[existing].offset = offset as f64 * ITEM_DURATION;
+                },
+                Option::None => found.push(Identification {
+                    path: path.clone(),
+                    offset: offset as f64 * ITEM_DURATION,
+                    score: score,
+                }),
+            }
+        }
+        found.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        found
+    }
+
+    /// fingerprint file and identify it
+    pub fn identify_file(&self, file: &File, min_score: f64) -> Result<Vec<Identification>, IndexError> {
+        let excerpt = try!(fingerprint::fingerprint_file(&file.filename()).map_err(IndexError::Fingerprint));
+        Result::Ok(self.identify(excerpt.fingerprint.as_slice(), min_score))
+    }
+
+    /// fingerprint decoded audio, for example buffers collected from a
+    /// Sink, and identify it. the buffers are written to a temporary WAV
+    /// file to be fingerprinted. they must all be in the same format,
+    /// planar or interleaved.
+    pub fn identify_buffers(&self, buffers: &[DecodedBuffer], min_score: f64) -> Result<Vec<Identification>, IndexError> {
+        if buffers.is_empty() {
+            return Result::Ok(Vec::new());
+        }
+        let format = buffers[0].audio_format();
+        if buffers.iter().any(|buffer| buffer.audio_format() != format) {
+            return Result::Err(IndexError::MixedFormats);
+        }
+        let path = temp_path("identify");
+        let result = write_wav(&path, buffers).map_err(IndexError::Io)
+            .and_then(|()| fingerprint::fingerprint_file(&path).map_err(IndexError::Fingerprint));
+        let _ = fs::unlink(&path);
+        let excerpt = try!(result);
+        Result::Ok(self.identify(excerpt.fingerprint.as_slice(), min_score))
+    }
+
+    fn insert_key(&mut self, key: u32, value: (usize, usize)) {
+        let inserted = match self.lookup.get_mut(&key) {
+            Option::Some(positions) => {
+                positions.push(value);
+                true
+            },
+            Option::None => false,
+        };
+        if !inserted {
+            self.lookup.insert(key, vec![value]);
+        }
+    }
+
+    fn rebuild_lookup(&mut self) {
+        self.lookup = HashMap::new();
+        self.paths = HashMap::new();
+        let mut entries = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            self.paths.insert(track.path.clone(), index);
+            for (position, &item) in track.fingerprint.iter().enumerate() {
+                entries.push((key(item), (index, position)));
+            }
+        }
+        for (item_key, value) in entries.into_iter() {
+            self.insert_key(item_key, value);
+        }
+    }
+}
+
+fn key(item: i32) -> u32 {
+    (item as u32) >> (32 - KEY_BITS)
+}
+
+/// the fraction of equal bits with excerpt[i] lined up with
+/// items[i + offset], if at least min_overlap items overlap
+fn score_at(excerpt: &[i32], items: &[i32], offset: isize, min_overlap: usize) -> Option<f64> {
+    let start = if offset < 0 {(-offset) as usize} else {0};
+    let end = cmp::min(excerpt.len() as isize, items.len() as isize - offset);
+    if end < (start + min_overlap) as isize {
+        return Option::None;
+    }
+    let end = end as usize;
+    let mut errors = 0;
+    for i in start..end {
+        errors += (excerpt[i] ^ items[(i as isize + offset) as usize]).count_ones() as usize;
+    }
+    Option::Some(1.0 - errors as f64 / (32 * (end - start)) as f64)
+}
+
+/// buffers must all be in the format of the first. planar buffers are
+/// interleaved.
+fn write_wav(path: &Path, buffers: &[DecodedBuffer]) -> IoResult<()> {
+    let mut format = buffers[0].audio_format();
+    let planar = format.sample_fmt.planar;
+    format.sample_fmt.planar = false;
+    let channels = format.channel_layout.count() as usize;
+    let bytes_per_sample = format.sample_fmt.bytes_per_sample() as usize;
+    let mut writer = try!(WavWriter::create(path, format));
+    let mut interleaved = Vec::new();
+    for buffer in buffers.iter() {
+        if !planar {
+            try!(writer.write(buffer.as_slice_raw()));
+            continue;
+        }
+        let planes: Vec<&[u8]> = (0..channels).map(|channel| buffer.channel_as_slice_raw(channel as u32)).collect();
+        interleaved.clear();
+        for frame in 0..buffer.frame_count() {
+            let start = frame * bytes_per_sample;
+            for plane in planes.iter() {
+                interleaved.push_all(&plane[start..start + bytes_per_sample]);
+            }
+        }
+        try!(writer.write(interleaved.as_slice()));
+    }
+    writer.finish()
+}
+
+fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for &byte in path.as_vec().iter() {
+        if byte > 0x20 && byte < 0x7f && byte != b'%' {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(format!("%{:02X}", byte).as_slice());
+        }
+    }
+    escaped
+}
+
+fn unescape_path(escaped: &str) -> Option<Path> {
+    let bytes = escaped.as_bytes();
+    let mut path = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return Option::None;
+            }
+            let high = match (bytes[i + 1] as char).to_digit(16) {
+                Option::Some(digit) => digit,
+                Option::None => return Option::None,
+            };
+            let low = match (bytes[i + 2] as char).to_digit(16) {
+                Option::Some(digit) => digit,
+                Option::None => return Option::None,
+            };
+            path.push((high * 16 + low) as u8);
+            i += 3;
+        } else {
+            path.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Option::Some(Path::new(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Int;
+    use std::option::Option;
+
+    use super::{escape_path, unescape_path, Index};
+    use super::super::fingerprint::Fingerprint;
+
+    fn track(path: &str, seed: i32, len: usize) -> Fingerprint {
+        // a simple generator, so that items differ in their high bits
+        let mut item = seed;
+        let fingerprint = (0..len).map(|_| {
+            item = item.wrapping_mul(1103515245).wrapping_add(12345);
+            item
+        }).collect();
+        Fingerprint { path: Path::new(path), duration: len as f64 * 0.1238, fingerprint: fingerprint }
+    }
+
+    #[test]
+    fn add_replaces_and_remove() {
+        let mut index = Index::new();
+        index.add(track("a.flac", 1, 50));
+        index.add(track("b.flac", 2, 50));
+        index.add(track("a.flac", 3, 60));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.tracks()[1].path, Path::new("a.flac"));
+        assert_eq!(index.tracks()[1].fingerprint.len(), 60);
+
+        assert!(index.remove(&Path::new("b.flac")));
+        assert!(!index.remove(&Path::new("b.flac")));
+        assert_eq!(index.len(), 1);
+        // indexes were shifted by the removal
+        index.add(track("a.flac", 4, 70));
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.tracks()[0].fingerprint.len(), 70);
+    }
+
+    #[test]
+    fn identify_excerpt() {
+        let mut index = Index::new();
+        index.add(track("a.flac", 1, 200));
+        index.add(track("b.flac", 2, 200));
+        let excerpt = index.tracks()[1].fingerprint[40..100].to_vec();
+        let found = index.identify(excerpt.as_slice(), 0.7);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, Path::new("b.flac"));
+        assert_eq!(found[0].score, 1.0);
+        assert!((found[0].offset - 40.0 * 0.1238).abs() < 1e-9);
+    }
+
+    #[test]
+    fn path_escaping() {
+        let path = Path::new(b"music/100% \xff\tsong.flac");
+        let escaped = escape_path(&path);
+        assert_eq!(escaped.as_slice(), "music/100%25%20%FF%09song.flac");
+        assert_eq!(unescape_path(escaped.as_slice()), Option::Some(path));
+        assert_eq!(unescape_path("a%2"), Option::None);
+        assert_eq!(unescape_path("a%zz"), Option::None);
+    }
+}
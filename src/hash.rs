@@ -0,0 +1,208 @@
+//! content hashes of decoded audio.
+//!
+//! File::audio_hash decodes a file to interleaved signed 32 bit samples at
+//! its own sample rate and channel layout, and computes the MD5 of the
+//! samples in little endian byte order. This is not the MD5 in a FLAC
+//! STREAMINFO block, which covers the samples at the bit depth of the file,
+//! so the two only agree for 32 bit FLAC. Tags and container details do not
+//! affect it, so it stays the same after a metadata-only save and across
+//! bit-exact lossless transcodes. 16 and 24 bit audio converts to 32 bit
+//! exactly; float audio is converted, so it is only comparable to other
+//! decodes of float audio.
+
+use std::num::Int;
+use std::option::Option;
+use std::result::Result;
+
+use super::{AudioFormat, File, Playlist, SampleFormat, SampleType, Sink};
+
+#[derive(Copy, Debug, PartialEq)]
+pub struct AudioHash(pub [u8; 16]);
+
+impl AudioHash {
+    /// 32 lowercase hex digits
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().concat()
+    }
+}
+
+impl File {
+    /// hash the decoded audio of this file. returns the error code of
+    /// attaching the sink, or -1 if the file could not be opened again for
+    /// decoding.
+    pub fn audio_hash(&self) -> Result<AudioHash, i32> {
+        // a File of our own, so that playlists containing self are not
+        // disturbed
+        let file = try!(File::open(&self.filename()).ok_or(-1));
+        let source_format = file.audio_format();
+        let playlist = Playlist::new();
+        let sink = Sink::new();
+        sink.set_audio_format(AudioFormat {
+            sample_rate: source_format.sample_rate,
+            channel_layout: source_format.channel_layout,
+            sample_fmt: SampleFormat { sample_type: SampleType::S32, planar: false },
+        });
+        playlist.append(&file, 1.0, 1.0);
+        try!(sink.attach(&playlist));
+
+        let mut md5 = Md5::new();
+        let mut bytes = Vec::new();
+        loop {
+            let buffer = match sink.buffer_get_blocking() {
+                Option::Some(buffer) => buffer,
+                Option::None => break,
+            };
+            bytes.clear();
+            for &sample in buffer.as_slice_i32().iter() {
+                bytes.push_all(&[sample as u8, (sample >> 8) as u8, (sample >> 16) as u8, (sample >> 24) as u8]);
+            }
+            md5.update(bytes.as_slice());
+        }
+        sink.detach();
+        playlist.clear();
+        Result::Ok(AudioHash(md5.finish()))
+    }
+}
+
+/// RFC 1321
+struct Md5 {
+    state: [u32; 4],
+    /// bytes which do not fill a block yet
+    pending: Vec<u8>,
+    length: u64,
+}
+
+const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5,  9, 14, 20, 5,  9, 14, 20, 5,  9, 14, 20, 5,  9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+/// floor(abs(sin(i + 1)) * 2^32)
+const CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+impl Md5 {
+    fn new() -> Md5 {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            pending: Vec::new(),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        let mut data = data;
+        if !self.pending.is_empty() {
+            let needed = 64 - self.pending.len();
+            if data.len() < needed {
+                self.pending.push_all(data);
+                return;
+            }
+            self.pending.push_all(&data[..needed]);
+            let block = self.pending.clone();
+            self.block(block.as_slice());
+            self.pending.clear();
+            data = &data[needed..];
+        }
+        while data.len() >= 64 {
+            self.block(&data[..64]);
+            data = &data[64..];
+        }
+        self.pending.push_all(data);
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let bit_length = self.length * 8;
+        let mut padding = vec![0x80u8];
+        while (self.pending.len() + padding.len()) % 64 != 56 {
+            padding.push(0);
+        }
+        for i in 0..8 {
+            padding.push((bit_length >> (8 * i)) as u8);
+        }
+        // update would count the padding in the length, which is already final
+        let length = self.length;
+        self.update(padding.as_slice());
+        self.length = length;
+
+        let mut digest = [0u8; 16];
+        for (i, word) in self.state.iter().enumerate() {
+            for j in 0..4 {
+                digest[i * 4 + j] = (*word >> (8 * j)) as u8;
+            }
+        }
+        digest
+    }
+
+    fn block(&mut self, block: &[u8]) {
+        let mut words = [0u32; 16];
+        for i in 0..16 {
+            words[i] = block[i * 4] as u32 | (block[i * 4 + 1] as u32) << 8 |
+                (block[i * 4 + 2] as u32) << 16 | (block[i * 4 + 3] as u32) << 24;
+        }
+        let (mut a, mut b, mut c, mut d) = (self.state[0], self.state[1], self.state[2], self.state[3]);
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let sum = a.wrapping_add(f).wrapping_add(CONSTANTS[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(sum.rotate_left(SHIFTS[i] as usize));
+        }
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioHash, Md5};
+
+    fn md5_hex(data: &[u8]) -> String {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        AudioHash(md5.finish()).to_hex()
+    }
+
+    #[test]
+    fn rfc_1321_test_suite() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_hex(b"a"), "0cc175b9c0f1b6a831c399e269772661");
+        assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(md5_hex(b"message digest"), "f96b697d7cb7938d525a2f31aaf161d0");
+        assert_eq!(md5_hex(b"abcdefghijklmnopqrstuvwxyz"), "c3fcd3d76192e4007dfb496cca67e13b");
+        assert_eq!(md5_hex(b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789"),
+                   "d174ab98d277d9f5a5611c2c9f419d9f");
+        assert_eq!(md5_hex(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"),
+                   "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn split_updates() {
+        let data = b"12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+        for split in [0, 1, 55, 56, 63, 64, 65, 80].iter() {
+            let mut md5 = Md5::new();
+            md5.update(&data[..*split]);
+            md5.update(&data[*split..]);
+            assert_eq!(AudioHash(md5.finish()).to_hex(), "57edf4a22be3c955ac49da2e2107b67a");
+        }
+    }
+}
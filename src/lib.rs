@@ -3,6 +3,8 @@
 #![feature(std_misc)]
 #![feature(libc)]
 #![feature(path)]
+#![feature(collections)]
+#![feature(io)]
 extern crate libc;
 
 #[macro_use]
@@ -12,16 +14,77 @@ use std::sync::{Once, ONCE_INIT};
 use std::str::Utf8Error;
 use std::option::Option;
 use std::result::Result;
-use libc::{c_int, uint64_t, c_char, c_void, c_double, uint8_t};
+use libc::{c_int, c_uint, uint64_t, c_char, c_void, c_double, uint8_t, int32_t};
 use std::ffi::CString;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::collections::hash_map::Hasher;
 use std::sync::Mutex;
+use std::cell::{Cell, RefCell};
+use std::old_io::{fs, IoError, IoResult, Seek, SeekSet, Writer};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::old_io::fs::PathExtensions;
+use std::num::Float;
+use std::ascii::AsciiExt;
+
+pub mod analysis;
+pub mod catalog;
+pub mod check;
+pub mod cue;
+pub mod dupes;
+pub mod excerpt;
+pub mod fingerprint;
+pub mod fingerprint_index;
+pub mod hash;
+pub mod playlist_file;
+pub mod replaygain;
+pub mod tags;
+pub mod transcode;
+pub mod transition;
+pub mod waveform;
 
 lazy_static! {
     static ref GROOVE_FILE_RC: Mutex<PointerReferenceCounter<*mut GrooveFile>> =
         Mutex::new(PointerReferenceCounter::new());
+    // in and out points of trimmed playlist items, by item address. see
+    // excerpt.
+    static ref ITEM_RANGES: Mutex<HashMap<usize, excerpt::Range>> = Mutex::new(HashMap::new());
+    // what is attached to each playlist, by playlist address, so that a
+    // Sink knows whether it may seek. see excerpt.
+    static ref PLAYLIST_CONSUMERS: Mutex<HashMap<usize, Consumers>> = Mutex::new(HashMap::new());
+}
+
+/// the sinks, encoders, loudness detectors and fingerprinters attached to
+/// a playlist
+#[derive(Copy, Default, PartialEq)]
+struct Consumers {
+    sinks: usize,
+    /// the ones which are not a Sink, and do not trim items
+    others: usize,
+}
+
+fn consumers(playlist: *mut GroovePlaylist) -> Consumers {
+    PLAYLIST_CONSUMERS.lock().unwrap().get(&(playlist as usize)).map_or(Default::default(), |c| *c)
+}
+
+/// count a consumer attached to playlist, or detached from it
+fn count_consumer(playlist: *mut GroovePlaylist, sink: bool, attached: bool) {
+    let mut all = PLAYLIST_CONSUMERS.lock().unwrap();
+    let mut counts = all.get(&(playlist as usize)).map_or(Default::default(), |c| *c);
+    {
+        let count = if sink {&mut counts.sinks} else {&mut counts.others};
+        *count = if attached {*count + 1} else {*count - 1};
+    }
+    if counts == Default::default() {
+        all.remove(&(playlist as usize));
+    } else {
+        all.insert(playlist as usize, counts);
+    }
+}
+
+/// the in and out points of item, if it was trimmed
+fn item_range(item: *mut GroovePlaylistItem) -> Option<excerpt::Range> {
+    ITEM_RANGES.lock().unwrap().get(&(item as usize)).map(|range| *range)
 }
 
 fn init() {
@@ -70,7 +133,10 @@ extern {
     fn groove_playlist_destroy(playlist: *mut GroovePlaylist);
     fn groove_playlist_count(playlist: *mut GroovePlaylist) -> c_int;
     fn groove_playlist_clear(playlist: *mut GroovePlaylist);
+    fn groove_playlist_remove(playlist: *mut GroovePlaylist, item: *mut GroovePlaylistItem);
     fn groove_playlist_set_fill_mode(playlist: *mut GroovePlaylist, mode: c_int);
+    fn groove_playlist_seek(playlist: *mut GroovePlaylist, item: *mut GroovePlaylistItem,
+                            seconds: c_double);
 
     fn groove_encoder_create() -> *mut GrooveEncoder;
     fn groove_encoder_destroy(encoder: *mut GrooveEncoder);
@@ -91,6 +157,125 @@ extern {
                               block: c_int) -> c_int;
 }
 
+#[link(name="avcodec")]
+extern {
+    fn avcodec_find_encoder_by_name(name: *const c_char) -> *const c_void;
+    fn av_codec_next(codec: *const AVCodec) -> *const AVCodec;
+    fn av_codec_is_encoder(codec: *const AVCodec) -> c_int;
+    fn av_codec_is_decoder(codec: *const AVCodec) -> c_int;
+}
+
+#[link(name="avformat")]
+extern {
+    fn av_oformat_next(format: *const AVOutputFormat) -> *const AVOutputFormat;
+    fn av_iformat_next(format: *const AVInputFormat) -> *const AVInputFormat;
+    fn avformat_open_input(context: *mut *mut AVFormatContext, filename: *const c_char,
+                           format: *const AVInputFormat, options: *mut *mut c_void) -> c_int;
+    fn avformat_close_input(context: *mut *mut AVFormatContext);
+}
+
+/// the va_list a log callback receives, as C passes it to a function.
+/// on x86_64 va_list is an array of one __va_list_tag, so it arrives as a
+/// pointer to the tag; on x86 and arm it is a plain pointer.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct VaListTag {
+    gp_offset: c_uint,
+    fp_offset: c_uint,
+    overflow_arg_area: *mut c_void,
+    reg_save_area: *mut c_void,
+}
+
+#[cfg(target_arch = "x86_64")]
+type VaList = *mut VaListTag;
+
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+type VaList = *mut c_char;
+
+#[link(name="avutil")]
+extern {
+    fn av_log_set_callback(callback: extern fn(*mut c_void, c_int, *const c_char, VaList));
+    fn av_log_get_level() -> c_int;
+    fn av_log_format_line(ptr: *mut c_void, level: c_int, fmt: *const c_char, args: VaList,
+                          line: *mut c_char, line_size: c_int, print_prefix: *mut c_int);
+}
+
+#[link(name="grooveloudness")]
+extern {
+    fn groove_loudness_detector_create() -> *mut GrooveLoudnessDetector;
+    fn groove_loudness_detector_destroy(detector: *mut GrooveLoudnessDetector);
+    fn groove_loudness_detector_attach(detector: *mut GrooveLoudnessDetector,
+                                       playlist: *mut GroovePlaylist) -> c_int;
+    fn groove_loudness_detector_detach(detector: *mut GrooveLoudnessDetector) -> c_int;
+    fn groove_loudness_detector_info_get(detector: *mut GrooveLoudnessDetector,
+                                         info: *mut GrooveLoudnessDetectorInfo,
+                                         block: c_int) -> c_int;
+}
+
+#[link(name="groovefingerprinter")]
+extern {
+    fn groove_fingerprinter_create() -> *mut GrooveFingerprinter;
+    fn groove_fingerprinter_destroy(printer: *mut GrooveFingerprinter);
+    fn groove_fingerprinter_attach(printer: *mut GrooveFingerprinter,
+                                   playlist: *mut GroovePlaylist) -> c_int;
+    fn groove_fingerprinter_detach(printer: *mut GrooveFingerprinter) -> c_int;
+    fn groove_fingerprinter_info_get(printer: *mut GrooveFingerprinter,
+                                     info: *mut GrooveFingerprinterInfo,
+                                     block: c_int) -> c_int;
+    fn groove_fingerprinter_free_info(info: *mut GrooveFingerprinterInfo);
+    fn groove_fingerprinter_encode(fp: *mut int32_t, size: c_int,
+                                   encoded_fp: *mut *mut c_char) -> c_int;
+    fn groove_fingerprinter_decode(encoded_fp: *mut c_char, fp: *mut *mut int32_t,
+                                   size: *mut c_int) -> c_int;
+    fn groove_fingerprinter_dealloc(ptr: *mut c_void);
+}
+
+const AVMEDIA_TYPE_AUDIO: c_int = 1;
+
+/// only the leading public fields, which have been stable across libav
+/// versions. never allocate one of these; only read through pointers.
+#[repr(C)]
+struct AVOutputFormat {
+    name: *const c_char,
+    long_name: *const c_char,
+    mime_type: *const c_char,
+    extensions: *const c_char,
+}
+
+/// only the leading public fields. see AVOutputFormat.
+#[repr(C)]
+struct AVInputFormat {
+    name: *const c_char,
+    long_name: *const c_char,
+    flags: c_int,
+    extensions: *const c_char,
+}
+
+/// only the leading public fields. see AVOutputFormat.
+#[repr(C)]
+struct AVFormatContext {
+    av_class: *const c_void,
+    iformat: *const AVInputFormat,
+}
+
+/// only the leading public fields. see AVOutputFormat.
+#[repr(C)]
+struct AVCodec {
+    name: *const c_char,
+    long_name: *const c_char,
+    media_type: c_int,
+    id: c_int,
+    capabilities: c_int,
+    supported_framerates: *const c_void,
+    pix_fmts: *const c_int,
+    /// terminated by 0
+    supported_samplerates: *const c_int,
+    /// terminated by SAMPLE_FMT_NONE
+    sample_fmts: *const c_int,
+    /// terminated by 0
+    channel_layouts: *const uint64_t,
+}
+
 #[repr(C)]
 struct GrooveSink {
     audio_format: GrooveAudioFormat,
@@ -140,13 +325,17 @@ struct GrooveSink {
 /// GroovePlayer uses this internally to get the audio buffer for playback
 pub struct Sink {
     groove_sink: *mut GrooveSink,
+    /// the item of the previous buffer, to notice when an item starts
+    current_item: Cell<*mut GroovePlaylistItem>,
+    /// a trimmed item whose out point was reached
+    finished_item: Cell<*mut GroovePlaylistItem>,
 }
 
 impl Drop for Sink {
     fn drop(&mut self) {
         unsafe {
             if !(*self.groove_sink).playlist.is_null() {
-                groove_sink_detach(self.groove_sink);
+                self.detach();
             }
             groove_sink_destroy(self.groove_sink)
         }
@@ -157,7 +346,11 @@ impl Sink {
     pub fn new() -> Self {
         init();
         unsafe {
-            Sink { groove_sink: groove_sink_create() }
+            Sink {
+                groove_sink: groove_sink_create(),
+                current_item: Cell::new(std::ptr::null_mut()),
+                finished_item: Cell::new(std::ptr::null_mut()),
+            }
         }
     }
 
@@ -169,9 +362,12 @@ impl Sink {
     }
 
     pub fn attach(&self, playlist: &Playlist) -> Result<(), i32> {
+        self.current_item.set(std::ptr::null_mut());
+        self.finished_item.set(std::ptr::null_mut());
         unsafe {
             let err_code = groove_sink_attach(self.groove_sink, playlist.groove_playlist);
             if err_code >= 0 {
+                count_consumer(playlist.groove_playlist, true, true);
                 Result::Ok(())
             } else {
                 Result::Err(err_code as i32)
@@ -181,20 +377,99 @@ impl Sink {
 
     pub fn detach(&self) {
         unsafe {
+            let playlist = (*self.groove_sink).playlist;
             let _ = groove_sink_detach(self.groove_sink);
+            if !playlist.is_null() {
+                count_consumer(playlist, true, false);
+            }
         }
     }
 
     /// returns None on end of playlist, Some<DecodedBuffer> when there is a buffer
     /// blocks the thread until a buffer or end is found
+    ///
+    /// buffers of trimmed items (see Playlist::append_trimmed) only contain
+    /// the audio between the in and out points. when this is the only sink
+    /// attached to the playlist, it seeks the playlist to the in point when
+    /// a trimmed item starts, and to the next item at the out point. seeking
+    /// flushes every sink, so with more than one Sink attached none of them
+    /// seeks, and each drops the audio outside the points instead.
     pub fn buffer_get_blocking(&self) -> Option<DecodedBuffer> {
+        loop {
+            let buffer = unsafe {
+                let mut buffer: *mut GrooveBuffer = std::ptr::null_mut();
+                match groove_sink_buffer_get(self.groove_sink, &mut buffer, 1) {
+                    BUFFER_NO  => panic!("did not expect BUFFER_NO when blocking"),
+                    BUFFER_YES => DecodedBuffer::new(buffer),
+                    BUFFER_END => {
+                        self.current_item.set(std::ptr::null_mut());
+                        self.finished_item.set(std::ptr::null_mut());
+                        return Option::None;
+                    },
+                    _ => panic!("unexpected buffer result"),
+                }
+            };
+            match self.trim(buffer) {
+                Option::Some(buffer) => return Option::Some(buffer),
+                Option::None => continue,
+            }
+        }
+    }
+
+    /// apply the in and out points of the buffer's item. None if none of
+    /// the buffer is between them.
+    fn trim(&self, mut buffer: DecodedBuffer) -> Option<DecodedBuffer> {
+        let item = unsafe { (*buffer.groove_buffer).item };
+        let starts = item != self.current_item.get();
+        self.current_item.set(item);
+        let range = match item_range(item) {
+            Option::Some(range) => range,
+            Option::None => return Option::Some(buffer),
+        };
+        if item == self.finished_item.get() {
+            return Option::None;
+        }
+        let pos = buffer.pos();
+        if starts && pos < range.start && self.may_seek() {
+            // buffers already decoded from before the in point are flushed
+            self.seek(item, range.start);
+            return Option::None;
+        }
+
+        let rate = buffer.audio_format().sample_rate as f64;
+        let frame_count = buffer.frame_count();
+        let to_frame = |seconds: f64| ((seconds - pos) * rate).round().max(0.0).min(frame_count as f64) as usize;
+        let first = to_frame(range.start);
+        let last = match range.end {
+            Option::Some(end) => to_frame(end),
+            Option::None => frame_count,
+        };
+        if range.end.map_or(false, |end| pos + frame_count as f64 / rate >= end) {
+            self.finished_item.set(item);
+            let next = unsafe { (*item).next };
+            if !next.is_null() && self.may_seek() {
+                self.seek(next, item_range(next).map_or(0.0, |range| range.start));
+            }
+        }
+        if last <= first {
+            return Option::None;
+        }
+        buffer.first_frame = first;
+        buffer.frame_count = last - first;
+        Option::Some(buffer)
+    }
+
+    /// whether seeking the playlist flushes no other sink
+    fn may_seek(&self) -> bool {
+        let playlist = unsafe { (*self.groove_sink).playlist };
+        consumers(playlist) == Consumers { sinks: 1, others: 0 }
+    }
+
+    fn seek(&self, item: *mut GroovePlaylistItem, seconds: f64) {
         unsafe {
-            let mut buffer: *mut GrooveBuffer = std::ptr::null_mut();
-            match groove_sink_buffer_get(self.groove_sink, &mut buffer, 1) {
-                BUFFER_NO  => panic!("did not expect BUFFER_NO when blocking"),
-                BUFFER_YES => Option::Some(DecodedBuffer { groove_buffer: buffer }),
-                BUFFER_END => Option::None,
-                _ => panic!("unexpected buffer result"),
+            let playlist = (*self.groove_sink).playlist;
+            if !playlist.is_null() {
+                groove_playlist_seek(playlist, item, seconds);
             }
         }
     }
@@ -265,11 +540,35 @@ impl EncodedBuffer {
             std::mem::transmute::<std::raw::Slice<uint8_t>, &[u8]>(raw_slice)
         }
     }
+
+    /// the playlist item this audio was encoded from.
+    /// None if this buffer is a format header or trailer.
+    pub fn item(&self) -> Option<PlaylistItem> {
+        unsafe {
+            let item = (*self.groove_buffer).item;
+            if item.is_null() {
+                Option::None
+            } else {
+                Option::Some(PlaylistItem {groove_playlist_item: item})
+            }
+        }
+    }
+
+    /// position in seconds within the playlist item
+    pub fn pos(&self) -> f64 {
+        unsafe {
+            (*self.groove_buffer).pos
+        }
+    }
 }
 
 /// A buffer which contains raw samples
 pub struct DecodedBuffer {
     groove_buffer: *mut GrooveBuffer,
+    /// the frames of the buffer which are passed on. trimmed items use only
+    /// part of some buffers.
+    first_frame: usize,
+    frame_count: usize,
 }
 unsafe impl Sync for DecodedBuffer {}
 unsafe impl Send for DecodedBuffer {}
@@ -283,6 +582,14 @@ impl Drop for DecodedBuffer {
 }
 
 impl DecodedBuffer {
+    fn new(groove_buffer: *mut GrooveBuffer) -> DecodedBuffer {
+        DecodedBuffer {
+            groove_buffer: groove_buffer,
+            first_frame: 0,
+            frame_count: unsafe { (*groove_buffer).frame_count as usize },
+        }
+    }
+
     /// returns a vector of f64
     /// panics if the buffer is not planar
     /// panics if the buffer is not SampleType::Dbl
@@ -339,6 +646,32 @@ impl DecodedBuffer {
         }
     }
 
+    pub fn audio_format(&self) -> AudioFormat {
+        unsafe {
+            AudioFormat::from_groove(&(*self.groove_buffer).format)
+        }
+    }
+
+    /// number of audio frames in this buffer
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// the playlist item this audio was decoded from
+    pub fn item(&self) -> PlaylistItem {
+        unsafe {
+            PlaylistItem {groove_playlist_item: (*self.groove_buffer).item}
+        }
+    }
+
+    /// position in seconds within the playlist item
+    pub fn pos(&self) -> f64 {
+        unsafe {
+            let rate = (*self.groove_buffer).format.sample_rate as f64;
+            (*self.groove_buffer).pos + self.first_frame as f64 / rate
+        }
+    }
+
     fn channel_as_slice_generic<T>(&self, channel_index: u32) -> &[T] {
         unsafe {
             let sample_fmt = self.sample_format();
@@ -350,10 +683,10 @@ impl DecodedBuffer {
             if channel_index >= channel_count {
                 panic!("invalid channel index");
             }
-            let frame_count = (*self.groove_buffer).frame_count as usize;
+            let offset = self.first_frame * sample_fmt.bytes_per_sample() as usize;
             let raw_slice = std::raw::Slice {
-                data: *((*self.groove_buffer).data.offset(channel_index as isize)),
-                len: frame_count,
+                data: (*((*self.groove_buffer).data.offset(channel_index as isize))).offset(offset as isize),
+                len: self.frame_count,
             };
             std::mem::transmute::<std::raw::Slice<uint8_t>, &[T]>(raw_slice)
         }
@@ -362,7 +695,15 @@ impl DecodedBuffer {
     /// returns a single channel and always returns [u8]
     /// panics if the buffer is not planar
     pub fn channel_as_slice_raw(&self, channel_index: u32) -> &[u8] {
-        self.channel_as_slice_generic(channel_index)
+        let samples: &[u8] = self.channel_as_slice_generic(channel_index);
+        let bytes_per_sample = self.sample_format().bytes_per_sample() as usize;
+        unsafe {
+            let raw_slice = std::raw::Slice {
+                data: samples.as_ptr(),
+                len: samples.len() * bytes_per_sample,
+            };
+            std::mem::transmute::<std::raw::Slice<uint8_t>, &[u8]>(raw_slice)
+        }
     }
 
     /// returns a vector of f64
@@ -418,21 +759,39 @@ impl DecodedBuffer {
     /// returns all the buffer data as [u8]
     /// panics if the buffer is planar
     pub fn as_slice_raw(&self) -> &[u8] {
-        self.as_slice_generic()
+        unsafe {
+            let sample_fmt = SampleFormat::from_groove((*self.groove_buffer).format.sample_fmt);
+            if sample_fmt.planar {
+                panic!("as_vec works for interleaved buffers only");
+            }
+            let channel_count = groove_channel_layout_count(
+                (*self.groove_buffer).format.channel_layout) as usize;
+            let frame_size = channel_count * sample_fmt.bytes_per_sample() as usize;
+            let len = if self.frame_count == (*self.groove_buffer).frame_count as usize {
+                (*self.groove_buffer).size as usize
+            } else {
+                self.frame_count * frame_size
+            };
+            let raw_slice = std::raw::Slice {
+                data: (*(*self.groove_buffer).data).offset((self.first_frame * frame_size) as isize),
+                len: len,
+            };
+            std::mem::transmute::<std::raw::Slice<uint8_t>, &[u8]>(raw_slice)
+        }
     }
 
     fn as_slice_generic<T>(&self) -> &[T] {
         unsafe {
-            let sample_fmt = (*self.groove_buffer).format.sample_fmt;
-            if SampleFormat::from_groove(sample_fmt).planar {
+            let sample_fmt = SampleFormat::from_groove((*self.groove_buffer).format.sample_fmt);
+            if sample_fmt.planar {
                 panic!("as_vec works for interleaved buffers only");
             }
             let channel_count = groove_channel_layout_count(
                 (*self.groove_buffer).format.channel_layout) as usize;
-            let frame_count = (*self.groove_buffer).frame_count as usize;
+            let offset = self.first_frame * channel_count * sample_fmt.bytes_per_sample() as usize;
             let raw_slice = std::raw::Slice {
-                data: *(*self.groove_buffer).data,
-                len: channel_count * frame_count,
+                data: (*(*self.groove_buffer).data).offset(offset as isize),
+                len: channel_count * self.frame_count,
             };
             std::mem::transmute::<std::raw::Slice<uint8_t>, &[T]>(raw_slice)
         }
@@ -457,6 +816,13 @@ pub struct PlaylistItem {
     groove_playlist_item: *mut GroovePlaylistItem,
 }
 
+/// two PlaylistItems are equal when they refer to the same item
+impl PartialEq for PlaylistItem {
+    fn eq(&self, other: &PlaylistItem) -> bool {
+        self.groove_playlist_item == other.groove_playlist_item
+    }
+}
+
 impl PlaylistItem {
     /// A volume adjustment in float format to apply to the file when it plays.
     /// This is typically used for loudness compensation, for example ReplayGain.
@@ -505,6 +871,7 @@ pub struct Playlist {
 impl Drop for Playlist {
     fn drop(&mut self) {
         self.clear();
+        PLAYLIST_CONSUMERS.lock().unwrap().remove(&(self.groove_playlist as usize));
         unsafe { groove_playlist_destroy(self.groove_playlist) }
     }
 }
@@ -582,6 +949,16 @@ impl Playlist {
         }
     }
 
+    /// remove item from the playlist. item must not be used afterwards.
+    pub fn remove(&self, item: &PlaylistItem) {
+        unsafe {
+            let groove_file = (*item.groove_playlist_item).file;
+            ITEM_RANGES.lock().unwrap().remove(&(item.groove_playlist_item as usize));
+            groove_playlist_remove(self.groove_playlist, item.groove_playlist_item);
+            GROOVE_FILE_RC.lock().unwrap().decr(groove_file);
+        }
+    }
+
     /// return the count of playlist items
     pub fn len(&self) -> i32 {
         unsafe {
@@ -594,6 +971,12 @@ impl Playlist {
         unsafe {
             let groove_files: Vec<*mut GrooveFile> =
                 self.iter().map(|x| (*x.groove_playlist_item).file).collect();
+            {
+                let mut ranges = ITEM_RANGES.lock().unwrap();
+                for item in self.iter() {
+                    ranges.remove(&(item.groove_playlist_item as usize));
+                }
+            }
             groove_playlist_clear(self.groove_playlist);
             for groove_file in groove_files.iter() {
                 GROOVE_FILE_RC.lock().unwrap().decr(*groove_file);
@@ -608,6 +991,13 @@ impl Playlist {
         };
         unsafe { groove_playlist_set_fill_mode(self.groove_playlist, mode_int) }
     }
+
+    /// continue decoding from seconds into item. sinks are flushed.
+    pub fn seek(&self, item: &PlaylistItem, seconds: f64) {
+        unsafe {
+            groove_playlist_seek(self.groove_playlist, item.groove_playlist_item, seconds)
+        }
+    }
 }
 
 pub struct PlaylistIterator {
@@ -677,6 +1067,23 @@ impl File {
             Path::new(slice)
         }
     }
+    /// the short name of the demuxer libav reads this file with, such as
+    /// "mp3", "aiff" or "flac". libgroove does not expose the demuxer it
+    /// opened, so the file is probed again. None if that fails.
+    pub fn demuxer_name(&self) -> Option<String> {
+        unsafe {
+            let mut context: *mut AVFormatContext = std::ptr::null_mut();
+            let err_code = avformat_open_input(&mut context, (*self.groove_file).filename,
+                                               std::ptr::null(), std::ptr::null_mut());
+            if err_code < 0 {
+                return Option::None;
+            }
+            let name = c_str_to_string((*(*context).iformat).name);
+            avformat_close_input(&mut context);
+            Option::Some(name)
+        }
+    }
+
     /// whether the file has pending edits
     pub fn is_dirty(&self) -> bool {
         unsafe {
@@ -711,6 +1118,48 @@ impl File {
         MetadataIterator { file: self, curr: std::ptr::null() }
     }
 
+    /// like metadata_get, but returns every tag with this key instead of
+    /// only the first one
+    pub fn metadata_get_all(&self, key: &str, case_sensitive: bool) -> Vec<Tag> {
+        let flags: c_int = if case_sensitive {TAG_MATCH_CASE} else {0};
+        let c_tag_key = CString::from_slice(key.as_bytes());
+        let mut result = Vec::new();
+        let mut prev: *const c_void = std::ptr::null();
+        unsafe {
+            loop {
+                let tag = groove_file_metadata_get(self.groove_file, c_tag_key.as_ptr(),
+                                                   prev, flags);
+                if tag.is_null() {
+                    break;
+                }
+                result.push(Tag {groove_tag: tag});
+                prev = tag;
+            }
+        }
+        result
+    }
+
+    /// replace every tag with this key by one tag per value, for example
+    /// several ARTIST entries. an empty slice deletes the key.
+    /// containers which can not store repeated keys keep only the last value.
+    /// requires a libav which supports AV_DICT_MULTIKEY.
+    pub fn metadata_set_all(&self, key: &str, values: &[&str], case_sensitive: bool) -> Result<(), i32> {
+        try!(self.metadata_delete(key, case_sensitive));
+        let flags: c_int = TAG_MULTIKEY | if case_sensitive {TAG_MATCH_CASE} else {0};
+        let c_tag_key = CString::from_slice(key.as_bytes());
+        for value in values.iter() {
+            let c_tag_value = CString::from_slice(value.as_bytes());
+            unsafe {
+                let err_code = groove_file_metadata_set(self.groove_file, c_tag_key.as_ptr(),
+                                                        c_tag_value.as_ptr(), flags);
+                if err_code < 0 {
+                    return Result::Err(err_code as i32);
+                }
+            }
+        }
+        Result::Ok(())
+    }
+
     pub fn metadata_set(&self, key: &str, value: &str, case_sensitive: bool) -> Result<(), i32> {
         let flags: c_int = if case_sensitive {TAG_MATCH_CASE} else {0};
         let c_tag_key = CString::from_slice(key.as_bytes());
@@ -726,18 +1175,21 @@ impl File {
         }
     }
 
+    /// delete every tag with this key, not only the first one
     pub fn metadata_delete(&self, key: &str, case_sensitive: bool) -> Result<(), i32> {
         let flags: c_int = if case_sensitive {TAG_MATCH_CASE} else {0};
         let c_tag_key = CString::from_slice(key.as_bytes());
-        unsafe {
-            let err_code = groove_file_metadata_set(self.groove_file, c_tag_key.as_ptr(),
-                                                    std::ptr::null(), flags);
-            if err_code >= 0 {
-                Result::Ok(())
-            } else {
-                Result::Err(err_code as i32)
+        // each call removes the first match
+        while self.metadata_get(key, case_sensitive).is_some() {
+            let err_code = unsafe {
+                groove_file_metadata_set(self.groove_file, c_tag_key.as_ptr(),
+                                         std::ptr::null(), flags)
+            };
+            if err_code < 0 {
+                return Result::Err(err_code as i32);
             }
         }
+        Result::Ok(())
     }
 
     /// write changes made to metadata to disk.
@@ -764,6 +1216,145 @@ impl File {
             AudioFormat::from_groove(&result)
         }
     }
+
+    /// write changes made to metadata to disk without ever leaving a
+    /// half-written file behind. The file is copied to a temporary file in
+    /// the same directory, the metadata is saved into the copy, and the copy
+    /// is opened again to check that its audio format and duration match.
+    /// Only then is it renamed over the original.
+    /// returns the saved file, opened again. this File still refers to the
+    /// original, which is no longer on disk under its name.
+    pub fn save_atomic(&self, backup: Backup) -> Result<File, SaveError> {
+        let path = self.filename();
+        let file_name = String::from_utf8_lossy(path.filename().unwrap_or(b"")).into_owned();
+        let tmp_name = match path.extension_str() {
+            Option::Some(ext) => format!(".{}.groove-tmp.{}", file_name, ext),
+            Option::None => format!(".{}.groove-tmp", file_name),
+        };
+        let tmp_path = path.with_filename(tmp_name);
+
+        match self.replace_via(&path, &tmp_path, backup) {
+            Result::Ok(()) => {},
+            Result::Err(err) => {
+                let _ = fs::unlink(&tmp_path);
+                return Result::Err(err);
+            },
+        }
+
+        File::open(&path).ok_or(SaveError::Open)
+    }
+
+    /// the steps of save_atomic which leave tmp_path behind when they fail
+    fn replace_via(&self, path: &Path, tmp_path: &Path, backup: Backup)
+        -> Result<(), SaveError>
+    {
+        try!(fs::copy(path, tmp_path).map_err(SaveError::Io));
+        try!(self.save_copy(tmp_path));
+
+        match backup {
+            Backup::Keep => {
+                let file_name = path.filename().unwrap_or(b"");
+                let bak_path = Backup::path_for(path);
+                let mut bak_tmp_name = b".".to_vec();
+                bak_tmp_name.push_all(file_name);
+                bak_tmp_name.push_all(b".groove-bak");
+                let bak_tmp_path = path.with_filename(bak_tmp_name);
+                if bak_tmp_path.exists() {
+                    let _ = fs::unlink(&bak_tmp_path);
+                }
+                // a hard link keeps the original under its name until the
+                // rename below replaces it
+                let created = match fs::link(path, &bak_tmp_path) {
+                    Result::Ok(()) => Result::Ok(()),
+                    Result::Err(_) => fs::copy(path, &bak_tmp_path),
+                };
+                // an older backup is only replaced once the new one is complete
+                match created.and_then(|()| fs::rename(&bak_tmp_path, &bak_path)) {
+                    Result::Ok(()) => {},
+                    Result::Err(err) => {
+                        let _ = fs::unlink(&bak_tmp_path);
+                        return Result::Err(SaveError::Io(err));
+                    },
+                }
+            },
+            Backup::None => {},
+        }
+        fs::rename(tmp_path, path).map_err(SaveError::Io)
+    }
+
+    /// copy this file's metadata into the file at path, save it, and verify
+    /// the result
+    fn save_copy(&self, path: &Path) -> Result<(), SaveError> {
+        {
+            let copy = try!(File::open(path).ok_or(SaveError::Open));
+            let keys: Vec<Vec<u8>> = copy.metadata_iter().map(|tag| tag.key_bytes().to_vec()).collect();
+            for key in keys.iter() {
+                let c_tag_key = CString::from_slice(key.as_slice());
+                let err_code = unsafe {
+                    groove_file_metadata_set(copy.groove_file, c_tag_key.as_ptr(),
+                                             std::ptr::null(), TAG_MATCH_CASE)
+                };
+                if err_code < 0 {
+                    return Result::Err(SaveError::Groove(err_code as i32));
+                }
+            }
+            for tag in self.metadata_iter() {
+                let c_tag_key = CString::from_slice(tag.key_bytes());
+                let c_tag_value = CString::from_slice(tag.value_bytes());
+                let err_code = unsafe {
+                    groove_file_metadata_set(copy.groove_file, c_tag_key.as_ptr(),
+                                             c_tag_value.as_ptr(), TAG_MATCH_CASE | TAG_MULTIKEY)
+                };
+                if err_code < 0 {
+                    return Result::Err(SaveError::Groove(err_code as i32));
+                }
+            }
+            try!(copy.save().map_err(SaveError::Groove));
+        }
+
+        let saved = try!(File::open(path).ok_or(SaveError::Open));
+        let duration_matches = (saved.duration() - self.duration()).abs() < SAVE_DURATION_TOLERANCE;
+        if saved.audio_format() == self.audio_format() && duration_matches {
+            Result::Ok(())
+        } else {
+            Result::Err(SaveError::Verify)
+        }
+    }
+}
+
+/// seconds by which File::duration of a saved copy may differ from the
+/// original, since it relies partly on heuristics
+const SAVE_DURATION_TOLERANCE: f64 = 0.05;
+
+/// what File::save_atomic does with the original file
+#[derive(Copy, Debug)]
+pub enum Backup {
+    /// the original is replaced
+    None,
+    /// the original is kept next to the saved file with a .bak suffix,
+    /// replacing any older backup
+    Keep,
+}
+
+impl Backup {
+    /// where Backup::Keep puts the original of the file at path
+    pub fn path_for(path: &Path) -> Path {
+        let mut bak_name = path.filename().unwrap_or(b"").to_vec();
+        bak_name.push_all(b".bak");
+        path.with_filename(bak_name)
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(IoError),
+    /// the temporary copy or the saved file could not be opened
+    Open,
+    /// libgroove returned this error code while copying tags or saving
+    Groove(i32),
+    /// the saved copy did not have the same audio format or duration as the
+    /// original. the original was left untouched.
+    Verify,
 }
 
 pub struct MetadataIterator<'a> {
@@ -811,13 +1402,15 @@ pub enum Log {
     Info,
 }
 
-#[derive(Copy, Debug)]
+#[derive(Copy, Debug, PartialEq)]
 pub enum ChannelLayout {
     FrontLeft,
     FrontRight,
     FrontCenter,
     LayoutMono,
     LayoutStereo,
+    /// any other layout, as a libav channel mask
+    Mask(u64),
 }
 
 const CH_FRONT_LEFT    :uint64_t = 0x00000001;
@@ -845,6 +1438,7 @@ impl ChannelLayout {
             ChannelLayout::FrontCenter  => CH_FRONT_CENTER,
             ChannelLayout::LayoutMono   => CH_LAYOUT_MONO,
             ChannelLayout::LayoutStereo => CH_LAYOUT_STEREO,
+            ChannelLayout::Mask(mask)   => mask,
         }
     }
 
@@ -854,7 +1448,7 @@ impl ChannelLayout {
             CH_FRONT_RIGHT    => ChannelLayout::FrontRight,
             CH_FRONT_CENTER   => ChannelLayout::FrontCenter,
             CH_LAYOUT_STEREO  => ChannelLayout::LayoutStereo,
-            _                 => panic!("invalid channel layout"),
+            _                 => ChannelLayout::Mask(x),
         }
     }
 }
@@ -873,6 +1467,7 @@ const SAMPLE_FMT_FLTP: i32 =  8;
 const SAMPLE_FMT_DBLP: i32 =  9;
 
 /// how to organize bits which represent audio samples
+#[derive(Debug, PartialEq)]
 pub struct SampleFormat {
     pub sample_type: SampleType,
     /// planar means non-interleaved
@@ -880,7 +1475,7 @@ pub struct SampleFormat {
 }
 impl Copy for SampleFormat {}
 
-#[derive(Copy, Debug)]
+#[derive(Copy, Debug, PartialEq)]
 pub enum SampleType {
     NoType,
     /// unsigned 8 bits
@@ -933,6 +1528,41 @@ impl SampleFormat {
         }
     }
 
+    /// parse a libav sample format name such as "s16" or "fltp"
+    fn from_name(name: &str) -> Option<SampleFormat> {
+        let groove_sample_format = match name {
+            "u8"   => SAMPLE_FMT_U8,
+            "s16"  => SAMPLE_FMT_S16,
+            "s32"  => SAMPLE_FMT_S32,
+            "flt"  => SAMPLE_FMT_FLT,
+            "dbl"  => SAMPLE_FMT_DBL,
+            "u8p"  => SAMPLE_FMT_U8P,
+            "s16p" => SAMPLE_FMT_S16P,
+            "s32p" => SAMPLE_FMT_S32P,
+            "fltp" => SAMPLE_FMT_FLTP,
+            "dblp" => SAMPLE_FMT_DBLP,
+            _      => return Option::None,
+        };
+        Option::Some(SampleFormat::from_groove(groove_sample_format))
+    }
+
+    /// the libav name of this sample format, the inverse of from_name
+    fn name(&self) -> &'static str {
+        match self.to_groove() {
+            SAMPLE_FMT_U8   => "u8",
+            SAMPLE_FMT_S16  => "s16",
+            SAMPLE_FMT_S32  => "s32",
+            SAMPLE_FMT_FLT  => "flt",
+            SAMPLE_FMT_DBL  => "dbl",
+            SAMPLE_FMT_U8P  => "u8p",
+            SAMPLE_FMT_S16P => "s16p",
+            SAMPLE_FMT_S32P => "s32p",
+            SAMPLE_FMT_FLTP => "fltp",
+            SAMPLE_FMT_DBLP => "dblp",
+            _               => "none",
+        }
+    }
+
     pub fn bytes_per_sample(&self) -> u32 {
         unsafe { groove_sample_format_bytes_per_sample(self.to_groove()) as u32 }
     }
@@ -963,6 +1593,76 @@ impl<'a> Tag<'a> {
             }
         }
     }
+
+    /// the key exactly as stored, in whatever encoding the tag used
+    pub fn key_bytes(&self) -> &'a [u8] {
+        unsafe {
+            let key_c_str = groove_tag_key(self.groove_tag);
+            let slice = std::ffi::c_str_to_bytes(&key_c_str);
+            std::mem::transmute::<&[u8], &'a [u8]>(slice)
+        }
+    }
+
+    /// the value exactly as stored, in whatever encoding the tag used
+    pub fn value_bytes(&self) -> &'a [u8] {
+        unsafe {
+            let val_c_str = groove_tag_value(self.groove_tag);
+            let slice = std::ffi::c_str_to_bytes(&val_c_str);
+            std::mem::transmute::<&[u8], &'a [u8]>(slice)
+        }
+    }
+
+    /// the key as a string. see value_lossy.
+    pub fn key_lossy(&self) -> String {
+        decode_lossy(self.key_bytes())
+    }
+
+    /// the value as a string. values which are not UTF-8, such as the
+    /// Latin-1 text of ID3v1 tags, are decoded as Windows-1252, which is a
+    /// superset of the printable part of Latin-1.
+    pub fn value_lossy(&self) -> String {
+        decode_lossy(self.value_bytes())
+    }
+}
+
+// Windows-1252 characters for bytes 0x80 through 0x9F. the unassigned bytes
+// map to the Latin-1 control character with the same value.
+const WINDOWS_1252_HIGH: [u32; 32] = [
+    0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008D, 0x017D, 0x008F,
+    0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+];
+
+/// append s to out as a quoted JSON string
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn decode_lossy(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Result::Ok(s) => s.to_string(),
+        Result::Err(_) => bytes.iter().map(|&byte| {
+            let code_point = if byte >= 0x80 && byte < 0xA0 {
+                WINDOWS_1252_HIGH[(byte - 0x80) as usize]
+            } else {
+                byte as u32
+            };
+            std::char::from_u32(code_point).unwrap()
+        }).collect(),
+    }
 }
 
 #[repr(C)]
@@ -972,6 +1672,7 @@ struct GrooveAudioFormat {
     sample_fmt: c_int,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct AudioFormat {
     pub sample_rate: i32,
     pub channel_layout: ChannelLayout,
@@ -1031,13 +1732,21 @@ struct GrooveEncoder {
 /// for example you could use it to implement an http audio stream
 pub struct Encoder {
     groove_encoder: *mut GrooveEncoder,
+    preset: Cell<Option<EncoderPreset>>,
+    options: RefCell<Vec<(String, EncoderOption)>>,
+    /// strings handed to libgroove must live as long as the encoder uses
+    /// them, one per field so that setting a field again frees the old one
+    c_strings: RefCell<HashMap<&'static str, CString>>,
+    /// the key of every value set with metadata_set, so that clear_metadata
+    /// can remove them again
+    metadata_keys: RefCell<Vec<String>>,
 }
 
 impl Drop for Encoder {
     fn drop(&mut self) {
         unsafe {
             if !(*self.groove_encoder).playlist.is_null() {
-                groove_encoder_detach(self.groove_encoder);
+                self.detach();
             }
             groove_encoder_destroy(self.groove_encoder)
         }
@@ -1048,7 +1757,13 @@ impl Encoder {
     pub fn new() -> Self {
         init();
         unsafe {
-            Encoder { groove_encoder: groove_encoder_create() }
+            Encoder {
+                groove_encoder: groove_encoder_create(),
+                preset: Cell::new(Option::None),
+                options: RefCell::new(Vec::new()),
+                c_strings: RefCell::new(HashMap::new()),
+                metadata_keys: RefCell::new(Vec::new()),
+            }
         }
     }
 
@@ -1086,40 +1801,140 @@ impl Encoder {
 
     /// optional - choose a short name for the format
     /// to help libgroove guess which format to use
-    /// use `avconv -formats` to get a list of possibilities
+    /// use groove::formats() to get a list of possibilities
     pub fn set_format_short_name(&self, format: &str) {
-        let format_c_str = CString::from_slice(format.as_bytes());
+        let format_c_str = self.keep_c_str("format_short_name", format);
         unsafe {
-            (*self.groove_encoder).format_short_name = format_c_str.as_ptr();
+            (*self.groove_encoder).format_short_name = format_c_str;
         }
     }
 
     /// optional - choose a short name for the codec
     /// to help libgroove guess which codec to use
-    /// use `avconv -codecs` to get a list of possibilities
+    /// use groove::codecs() to get a list of possibilities
     pub fn set_codec_short_name(&self, codec: &str) {
-        let codec_c_str = CString::from_slice(codec.as_bytes());
+        let codec_c_str = self.keep_c_str("codec_short_name", codec);
         unsafe {
-            (*self.groove_encoder).codec_short_name = codec_c_str.as_ptr();
+            (*self.groove_encoder).codec_short_name = codec_c_str;
         }
     }
 
     /// optional - provide an example filename
     /// to help libgroove guess which format/codec to use
     pub fn set_filename(&self, filename: &str) {
-        let filename_c_str = CString::from_slice(filename.as_bytes());
+        let filename_c_str = self.keep_c_str("filename", filename);
         unsafe {
-            (*self.groove_encoder).filename = filename_c_str.as_ptr();
+            (*self.groove_encoder).filename = filename_c_str;
         }
     }
 
     /// optional - provide a mime type string
     /// to help libgroove guess which format/codec to use
     pub fn set_mime_type(&self, mime_type: &str) {
-        let mime_type_c_str = CString::from_slice(mime_type.as_bytes());
+        let mime_type_c_str = self.keep_c_str("mime_type", mime_type);
+        unsafe {
+            (*self.groove_encoder).mime_type = mime_type_c_str;
+        }
+    }
+
+    fn keep_c_str(&self, field: &'static str, s: &str) -> *const c_char {
+        let c_str = CString::from_slice(s.as_bytes());
+        let ptr = c_str.as_ptr();
+        self.c_strings.borrow_mut().insert(field, c_str);
+        ptr
+    }
+
+    /// configure format, codec, mime type, target audio format and quality
+    /// all at once. The codec is looked up when you attach; use
+    /// check_preset to find out beforehand whether the installed libav has
+    /// it, since attach can only return libgroove's error code.
+    /// The codec chosen by the preset takes precedence over
+    /// set_codec_short_name.
+    pub fn set_preset(&self, preset: EncoderPreset) {
+        self.set_target_audio_format(preset.target_audio_format());
+        match preset.bit_rate() {
+            Option::Some(rate) => self.set_bit_rate(rate),
+            Option::None => {},
+        }
         unsafe {
-            (*self.groove_encoder).mime_type = mime_type_c_str.as_ptr();
+            (*self.groove_encoder).format_short_name =
+                preset.format_short_name().as_ptr() as *const c_char;
+            (*self.groove_encoder).mime_type = preset.mime_type().as_ptr() as *const c_char;
+        }
+        self.preset.set(Option::Some(preset));
+    }
+
+    /// the codec the preset will use. EncoderError::CodecNotFound names the
+    /// codecs which were tried if the installed libav has none of them.
+    /// Ok(None) without a preset.
+    pub fn check_preset(&self) -> Result<Option<&'static str>, EncoderError> {
+        match self.preset.get() {
+            Option::Some(preset) => preset.find_codec().map(|codec| {
+                Option::Some(codec.trim_right_matches('\0'))
+            }),
+            Option::None => Result::Ok(Option::None),
+        }
+    }
+
+    /// set an encoder option using the same names as libav. Options are
+    /// applied when you attach and override set_preset and the other
+    /// setters; setting the same key again replaces the value.
+    /// libgroove opens the codec and the muxer without an options
+    /// dictionary, so only the options it has fields for are accepted:
+    /// "b" (bit rate), "ar" (sample rate), "ac" (channel count) and
+    /// "sample_fmt". Any other key, such as "compression_level" or
+    /// "id3v2_version", returns EncoderError::UnsupportedOption rather than
+    /// being ignored.
+    pub fn set_option(&self, key: &str, value: &str) -> Result<(), EncoderError> {
+        let invalid = || EncoderError::InvalidOption(key.to_string(), value.to_string());
+        let option = match key {
+            "b" => EncoderOption::BitRate(try!(value.parse::<i32>().map_err(|_| invalid()))),
+            "ar" => EncoderOption::SampleRate(try!(value.parse::<i32>().map_err(|_| invalid()))),
+            "ac" => {
+                let count = try!(value.parse::<i32>().map_err(|_| invalid()));
+                if count < 1 {
+                    return Result::Err(invalid());
+                }
+                let layout = unsafe { groove_channel_layout_default(count) };
+                if layout == 0 {
+                    return Result::Err(invalid());
+                }
+                EncoderOption::Channels(ChannelLayout::from_groove(layout))
+            },
+            "sample_fmt" => EncoderOption::SampleFormat(try!(SampleFormat::from_name(value).ok_or_else(invalid))),
+            _ => return Result::Err(EncoderError::UnsupportedOption(key.to_string())),
+        };
+        let mut options = self.options.borrow_mut();
+        options.retain(|&(ref k, _)| k.as_slice() != key);
+        options.push((key.to_string(), option));
+        Result::Ok(())
+    }
+
+    /// after attach, the keys given to set_option whose values libav did
+    /// not use as they were, because it substituted a sample rate, channel
+    /// layout or sample format that the codec supports. a bit rate can not
+    /// be checked, so "b" is never reported.
+    pub fn unused_options(&self) -> Vec<String> {
+        let actual = self.get_actual_audio_format();
+        self.options.borrow().iter().filter(|&&(_, option)| match option {
+            EncoderOption::BitRate(_) => false,
+            EncoderOption::SampleRate(rate) => rate != actual.sample_rate,
+            EncoderOption::Channels(layout) => layout != actual.channel_layout,
+            EncoderOption::SampleFormat(format) => format != actual.sample_fmt,
+        }).map(|&(ref key, _)| key.clone()).collect()
+    }
+
+    fn apply_options(&self) {
+        let mut target = self.get_target_audio_format();
+        for &(_, option) in self.options.borrow().iter() {
+            match option {
+                EncoderOption::BitRate(rate) => self.set_bit_rate(rate),
+                EncoderOption::SampleRate(rate) => target.sample_rate = rate,
+                EncoderOption::Channels(layout) => target.channel_layout = layout,
+                EncoderOption::SampleFormat(format) => target.sample_fmt = format,
+            }
         }
+        self.set_target_audio_format(target);
     }
 
     /// set to the actual format you get when you attach to a
@@ -1140,6 +1955,7 @@ impl Encoder {
             let err_code = groove_encoder_metadata_set(self.groove_encoder, c_tag_key.as_ptr(),
                                                        c_tag_value.as_ptr(), flags);
             if err_code >= 0 {
+                self.metadata_keys.borrow_mut().push(key.to_string());
                 Result::Ok(())
             } else {
                 Result::Err(err_code as i32)
@@ -1147,12 +1963,98 @@ impl Encoder {
         }
     }
 
+    /// copy the tags of a file to the output according to policy.
+    /// repeated keys keep all of their values.
+    pub fn copy_metadata_from(&self, file: &File, policy: &TagPolicy) -> Result<(), i32> {
+        let tags: Vec<(String, String)> = file.metadata_iter()
+            .map(|tag| (tag.key_lossy(), tag.value_lossy()))
+            .collect();
+        self.set_all_metadata(policy.apply(tags).as_slice())
+    }
+
+    /// copy the tags that describe the playlist as a whole, for encodes of
+    /// several items into one output. the policy is applied to each item's
+    /// tags first. then:
+    ///  * tags that are the same for every item are copied
+    ///  * tags that describe a single track, such as title and track
+    ///    number, are dropped
+    ///  * if the artists differ, the album artist becomes the artist, or
+    ///    "Various Artists" if there is none
+    ///  * the album becomes the title
+    ///  * any other tag that differs between items is dropped
+    pub fn copy_metadata_from_playlist(&self, playlist: &Playlist, policy: &TagPolicy) -> Result<(), i32> {
+        let items: Vec<Vec<(String, String)>> = playlist.iter().map(|item| {
+            let tags = item.file().metadata_iter()
+                .map(|tag| (tag.key_lossy(), tag.value_lossy()))
+                .collect();
+            policy.apply(tags)
+        }).collect();
+        self.set_all_metadata(merge_album_tags(items.as_slice()).as_slice())
+    }
+
+    fn set_all_metadata(&self, tags: &[(String, String)]) -> Result<(), i32> {
+        let mut seen: Vec<String> = Vec::new();
+        for &(ref key, ref value) in tags.iter() {
+            let lower_key = key.to_ascii_lowercase();
+            // the first value replaces whatever was set before
+            let flags = if seen.contains(&lower_key) { TAG_MULTIKEY } else { 0 };
+            seen.push(lower_key);
+            let c_tag_key = CString::from_slice(key.as_bytes());
+            let c_tag_value = CString::from_slice(value.as_bytes());
+            let err_code = unsafe {
+                groove_encoder_metadata_set(self.groove_encoder, c_tag_key.as_ptr(),
+                                            c_tag_value.as_ptr(), flags)
+            };
+            if err_code < 0 {
+                return Result::Err(err_code as i32);
+            }
+            self.metadata_keys.borrow_mut().push(key.clone());
+        }
+        Result::Ok(())
+    }
+
+    /// remove every tag set with metadata_set or copied with
+    /// copy_metadata_from and copy_metadata_from_playlist, so that the next
+    /// output of this encoder starts without tags
+    pub fn clear_metadata(&self) -> Result<(), i32> {
+        let keys = std::mem::replace(&mut *self.metadata_keys.borrow_mut(), Vec::new());
+        for key in keys.iter() {
+            // removes the first value with this key, if there is one left
+            let c_tag_key = CString::from_slice(key.as_bytes());
+            let err_code = unsafe {
+                groove_encoder_metadata_set(self.groove_encoder, c_tag_key.as_ptr(),
+                                            std::ptr::null(), 0)
+            };
+            if err_code < 0 {
+                return Result::Err(err_code as i32);
+            }
+        }
+        Result::Ok(())
+    }
+
     /// at playlist begin, format headers are generated. when end of playlist is
     /// reached, format trailers are generated.
+    /// fails with excerpt::TRIMMED_PLAYLIST if the playlist has trimmed items.
     pub fn attach(&self, playlist: &Playlist) -> Result<(), i32> {
+        match self.preset.get() {
+            Option::Some(preset) => {
+                // without any of its codecs, the first one makes libgroove
+                // fail rather than guess a different codec
+                let codec = preset.find_codec().unwrap_or(preset.codec_short_names()[0]);
+                unsafe {
+                    (*self.groove_encoder).codec_short_name = codec.as_ptr() as *const c_char;
+                }
+            },
+            Option::None => {},
+        }
+        if playlist.has_trimmed_items() {
+            return Result::Err(excerpt::TRIMMED_PLAYLIST);
+        }
+        self.apply_options();
         unsafe {
             let err_code = groove_encoder_attach(self.groove_encoder, playlist.groove_playlist);
             if err_code >= 0 {
+                count_consumer(playlist.groove_playlist, false, true);
                 Result::Ok(())
             } else {
                 Result::Err(err_code as i32)
@@ -1162,7 +2064,11 @@ impl Encoder {
 
     pub fn detach(&self) {
         unsafe {
+            let playlist = (*self.groove_encoder).playlist;
             let _ = groove_encoder_detach(self.groove_encoder);
+            if !playlist.is_null() {
+                count_consumer(playlist, false, false);
+            }
         }
     }
 
@@ -1181,6 +2087,547 @@ impl Encoder {
     }
 }
 
+/// which tags Encoder::copy_metadata_from copies
+#[derive(Clone, Debug)]
+pub enum TagPolicy {
+    /// every tag
+    All,
+    /// only the tags with these keys, compared case insensitively
+    Allow(Vec<String>),
+    /// every tag, renaming keys found in the map from the first name to
+    /// the second. keys are compared case insensitively.
+    Map(Vec<(String, String)>),
+}
+
+// Vorbis comment names and the ID3v2.4 frames with the same meaning.
+// libav already converts the generic names it knows, such as "title", so
+// this is only needed for tags that were copied by their raw name.
+const VORBIS_ID3_KEYS: [(&'static str, &'static str); 14] = [
+    ("TITLE", "TIT2"),
+    ("ARTIST", "TPE1"),
+    ("ALBUM", "TALB"),
+    ("ALBUMARTIST", "TPE2"),
+    ("DATE", "TDRC"),
+    ("GENRE", "TCON"),
+    ("COMPOSER", "TCOM"),
+    ("TRACKNUMBER", "TRCK"),
+    ("DISCNUMBER", "TPOS"),
+    ("BPM", "TBPM"),
+    ("ISRC", "TSRC"),
+    ("COPYRIGHT", "TCOP"),
+    ("PUBLISHER", "TPUB"),
+    ("ENCODEDBY", "TENC"),
+];
+
+impl TagPolicy {
+    /// every tag, with Vorbis comment names converted to ID3v2 frames
+    pub fn vorbis_to_id3() -> TagPolicy {
+        TagPolicy::Map(VORBIS_ID3_KEYS.iter()
+                       .map(|&(vorbis, id3)| (vorbis.to_string(), id3.to_string()))
+                       .collect())
+    }
+
+    /// every tag, with ID3v2 frames converted to Vorbis comment names
+    pub fn id3_to_vorbis() -> TagPolicy {
+        TagPolicy::Map(VORBIS_ID3_KEYS.iter()
+                       .map(|&(vorbis, id3)| (id3.to_string(), vorbis.to_string()))
+                       .collect())
+    }
+
+    fn apply(&self, tags: Vec<(String, String)>) -> Vec<(String, String)> {
+        match *self {
+            TagPolicy::All => tags,
+            TagPolicy::Allow(ref keys) => tags.into_iter().filter(|&(ref key, _)| {
+                keys.iter().any(|k| k.eq_ignore_ascii_case(key.as_slice()))
+            }).collect(),
+            TagPolicy::Map(ref map) => tags.into_iter().map(|(key, value)| {
+                match map.iter().find(|&&(ref from, _)| from.eq_ignore_ascii_case(key.as_slice())) {
+                    Option::Some(&(_, ref to)) => (to.clone(), value),
+                    Option::None => (key, value),
+                }
+            }).collect(),
+        }
+    }
+}
+
+// tags which only make sense for a single track
+const TRACK_LEVEL_KEYS: [&'static str; 16] = [
+    "title", "track", "tracknumber", "tracktotal", "totaltracks", "isrc", "tsrc", "tit2",
+    "trck", "lyrics", "bpm", "tbpm", "musicbrainz_trackid", "musicbrainz track id",
+    "replaygain_track_gain", "replaygain_track_peak",
+];
+
+/// see Encoder::copy_metadata_from_playlist
+fn merge_album_tags(items: &[Vec<(String, String)>]) -> Vec<(String, String)> {
+    let values_of = |tags: &Vec<(String, String)>, key: &str| -> Vec<String> {
+        tags.iter().filter(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v)| v.clone()).collect()
+    };
+    let first = match items.first() {
+        Option::Some(first) => first,
+        Option::None => return Vec::new(),
+    };
+    if items.len() == 1 {
+        return first.clone();
+    }
+
+    let mut result: Vec<(String, String)> = Vec::new();
+    for &(ref key, _) in first.iter() {
+        let lower_key = key.to_ascii_lowercase();
+        if TRACK_LEVEL_KEYS.contains(&lower_key.as_slice()) ||
+            result.iter().any(|&(ref k, _)| k.eq_ignore_ascii_case(key.as_slice()))
+        {
+            continue;
+        }
+        let values = values_of(first, key.as_slice());
+        if items.iter().all(|tags| values_of(tags, key.as_slice()) == values) {
+            for value in values.into_iter() {
+                result.push((key.clone(), value));
+            }
+        }
+    }
+
+    let has = |result: &Vec<(String, String)>, key: &str| {
+        result.iter().any(|&(ref k, _)| k.eq_ignore_ascii_case(key))
+    };
+    if !has(&result, "artist") {
+        let album_artist = values_of(first, "album_artist");
+        let artist = if !album_artist.is_empty() &&
+            items.iter().all(|tags| values_of(tags, "album_artist") == album_artist)
+        {
+            album_artist[0].clone()
+        } else {
+            "Various Artists".to_string()
+        };
+        result.push(("artist".to_string(), artist));
+    }
+    if !has(&result, "title") {
+        let album = values_of(&result, "album");
+        if !album.is_empty() {
+            result.push(("title".to_string(), album[0].clone()));
+        }
+    }
+    result
+}
+
+#[derive(Debug)]
+pub enum EncoderError {
+    /// none of the codecs the preset can use are available in the installed
+    /// libav. contains the codec short names that were tried.
+    CodecNotFound(String),
+    /// the value given to set_option for this key could not be parsed
+    InvalidOption(String, String),
+    /// libgroove can not pass this option on to libav
+    UnsupportedOption(String),
+}
+
+/// an option accepted by Encoder::set_option
+#[derive(Copy)]
+enum EncoderOption {
+    BitRate(i32),
+    SampleRate(i32),
+    Channels(ChannelLayout),
+    SampleFormat(SampleFormat),
+}
+
+/// a complete encoder configuration for a common output format.
+/// use with Encoder::set_preset.
+/// libgroove's encoder opens the codec with nothing but a bit rate and an
+/// audio format, so there are no presets for quality levels such as LAME
+/// VBR or FLAC compression levels; every lossy preset is bit rate based.
+#[derive(Copy, Debug)]
+pub enum EncoderPreset {
+    /// MP3, constant bit rate in kbps
+    Mp3Cbr(i32),
+    /// Vorbis in an Ogg container, average bit rate in kbps
+    OggVorbis(i32),
+    /// Opus in an Ogg container, bit rate in kbps
+    Opus(i32),
+    /// FLAC at libav's default compression level
+    Flac,
+    /// uncompressed WAV with the given sample format
+    Wav(SampleFormat),
+    /// AAC in an ADTS stream, bit rate in kbps
+    Aac(i32),
+}
+
+impl EncoderPreset {
+    // all of these strings are nul terminated so that they can be handed
+    // to libgroove directly and outlive the encoder.
+
+    fn format_short_name(&self) -> &'static str {
+        match *self {
+            EncoderPreset::Mp3Cbr(_)    => "mp3\0",
+            EncoderPreset::OggVorbis(_) => "ogg\0",
+            EncoderPreset::Opus(_)      => "ogg\0",
+            EncoderPreset::Flac         => "flac\0",
+            EncoderPreset::Wav(_)       => "wav\0",
+            EncoderPreset::Aac(_)       => "adts\0",
+        }
+    }
+
+    fn mime_type(&self) -> &'static str {
+        match *self {
+            EncoderPreset::Mp3Cbr(_)    => "audio/mpeg\0",
+            EncoderPreset::OggVorbis(_) => "audio/ogg\0",
+            EncoderPreset::Opus(_)      => "audio/ogg\0",
+            EncoderPreset::Flac         => "audio/flac\0",
+            EncoderPreset::Wav(_)       => "audio/x-wav\0",
+            EncoderPreset::Aac(_)       => "audio/aac\0",
+        }
+    }
+
+    /// codec short names in order of preference
+    fn codec_short_names(&self) -> Vec<&'static str> {
+        match *self {
+            EncoderPreset::Mp3Cbr(_)    => vec!["libmp3lame\0"],
+            EncoderPreset::OggVorbis(_) => vec!["libvorbis\0"],
+            EncoderPreset::Opus(_)      => vec!["libopus\0"],
+            EncoderPreset::Flac         => vec!["flac\0"],
+            EncoderPreset::Wav(fmt)     => vec![match fmt.sample_type {
+                SampleType::U8  => "pcm_u8\0",
+                SampleType::S32 => "pcm_s32le\0",
+                SampleType::Flt => "pcm_f32le\0",
+                SampleType::Dbl => "pcm_f64le\0",
+                _               => "pcm_s16le\0",
+            }],
+            EncoderPreset::Aac(_)       => vec!["libfdk_aac\0", "libvo_aacenc\0", "aac\0"],
+        }
+    }
+
+    /// lossless presets leave the bit rate alone
+    fn bit_rate(&self) -> Option<i32> {
+        match *self {
+            EncoderPreset::Mp3Cbr(kbps) => Option::Some(kbps * 1000),
+            EncoderPreset::OggVorbis(kbps) => Option::Some(kbps * 1000),
+            EncoderPreset::Opus(kbps)   => Option::Some(kbps * 1000),
+            EncoderPreset::Flac         => Option::None,
+            EncoderPreset::Wav(_)       => Option::None,
+            EncoderPreset::Aac(kbps)    => Option::Some(kbps * 1000),
+        }
+    }
+
+    fn target_audio_format(&self) -> AudioFormat {
+        let (sample_rate, sample_type, planar) = match *self {
+            EncoderPreset::Mp3Cbr(_)    => (44100, SampleType::S16, true),
+            EncoderPreset::OggVorbis(_) => (44100, SampleType::Flt, true),
+            EncoderPreset::Opus(_)      => (48000, SampleType::S16, false),
+            EncoderPreset::Flac         => (44100, SampleType::S16, false),
+            EncoderPreset::Wav(fmt)     => (44100, fmt.sample_type, fmt.planar),
+            EncoderPreset::Aac(_)       => (44100, SampleType::Flt, true),
+        };
+        AudioFormat {
+            sample_rate: sample_rate,
+            channel_layout: ChannelLayout::LayoutStereo,
+            sample_fmt: SampleFormat { sample_type: sample_type, planar: planar },
+        }
+    }
+
+    fn find_codec(&self) -> Result<&'static str, EncoderError> {
+        let names = self.codec_short_names();
+        for name in names.iter() {
+            let codec = unsafe { avcodec_find_encoder_by_name(name.as_ptr() as *const c_char) };
+            if !codec.is_null() {
+                return Result::Ok(*name);
+            }
+        }
+        let tried: Vec<&str> = names.iter().map(|name| name.trim_right_matches('\0')).collect();
+        Result::Err(EncoderError::CodecNotFound(tried.connect(", ")))
+    }
+}
+
+#[repr(C)]
+struct GrooveLoudnessDetectorInfo {
+    /// integrated loudness in LUFS
+    loudness: c_double,
+    /// sample peak in float format of the file
+    peak: c_double,
+    duration: c_double,
+    /// if item is NULL, this info applies to all songs analyzed until
+    /// this point. otherwise it is the playlist item that this info applies to.
+    item: *mut GroovePlaylistItem,
+}
+
+#[repr(C)]
+struct GrooveLoudnessDetector {
+    /// maximum number of GrooveLoudnessDetectorInfo items to store in this
+    /// detector's queue. defaults to MAX_INT
+    info_queue_size: c_int,
+    /// how big the sink buffer should be, in sample frames.
+    /// groove_loudness_detector_create defaults this to 8192
+    sink_buffer_size: c_int,
+    /// set to 1 to only compute track loudness. This is faster and requires
+    /// less memory than computing both.
+    disable_album: c_int,
+
+    /// read-only. set when attached and cleared when detached
+    playlist: *mut GroovePlaylist,
+}
+
+/// attach a LoudnessDetector to a playlist to measure the EBU R128 loudness
+/// of each item, and of the playlist as a whole.
+pub struct LoudnessDetector {
+    groove_loudness_detector: *mut GrooveLoudnessDetector,
+}
+
+impl Drop for LoudnessDetector {
+    fn drop(&mut self) {
+        unsafe {
+            if !(*self.groove_loudness_detector).playlist.is_null() {
+                self.detach();
+            }
+            groove_loudness_detector_destroy(self.groove_loudness_detector)
+        }
+    }
+}
+
+impl LoudnessDetector {
+    pub fn new() -> Self {
+        init();
+        unsafe {
+            LoudnessDetector { groove_loudness_detector: groove_loudness_detector_create() }
+        }
+    }
+
+    /// set to true to only compute track loudness. This is faster and
+    /// requires less memory than computing both.
+    pub fn set_disable_album(&self, disabled: bool) {
+        unsafe {
+            (*self.groove_loudness_detector).disable_album = if disabled {1} else {0}
+        }
+    }
+
+    /// fails with excerpt::TRIMMED_PLAYLIST if the playlist has trimmed items.
+    pub fn attach(&self, playlist: &Playlist) -> Result<(), i32> {
+        if playlist.has_trimmed_items() {
+            return Result::Err(excerpt::TRIMMED_PLAYLIST);
+        }
+        unsafe {
+            let err_code = groove_loudness_detector_attach(self.groove_loudness_detector,
+                                                           playlist.groove_playlist);
+            if err_code >= 0 {
+                count_consumer(playlist.groove_playlist, false, true);
+                Result::Ok(())
+            } else {
+                Result::Err(err_code as i32)
+            }
+        }
+    }
+
+    pub fn detach(&self) {
+        unsafe {
+            let playlist = (*self.groove_loudness_detector).playlist;
+            let _ = groove_loudness_detector_detach(self.groove_loudness_detector);
+            if !playlist.is_null() {
+                count_consumer(playlist, false, false);
+            }
+        }
+    }
+
+    /// returns Some<LoudnessDetectorInfo> when there is info, None if the
+    /// detector was detached. blocks the thread until one of those happens.
+    /// the info for the whole playlist comes last and has no item.
+    pub fn info_get_blocking(&self) -> Option<LoudnessDetectorInfo> {
+        unsafe {
+            let mut info = GrooveLoudnessDetectorInfo {
+                loudness: 0.0,
+                peak: 0.0,
+                duration: 0.0,
+                item: std::ptr::null_mut(),
+            };
+            match groove_loudness_detector_info_get(self.groove_loudness_detector, &mut info, 1) {
+                1 => Option::Some(LoudnessDetectorInfo {
+                    loudness: info.loudness,
+                    peak: info.peak,
+                    duration: info.duration,
+                    item: if info.item.is_null() {
+                        Option::None
+                    } else {
+                        Option::Some(PlaylistItem {groove_playlist_item: info.item})
+                    },
+                }),
+                _ => Option::None,
+            }
+        }
+    }
+}
+
+pub struct LoudnessDetectorInfo {
+    /// integrated loudness in LUFS. negative infinity for silence.
+    pub loudness: f64,
+    /// sample peak in float format
+    pub peak: f64,
+    /// duration in seconds, measured by decoding
+    pub duration: f64,
+    /// the playlist item this info applies to. None means the info applies
+    /// to everything analyzed until this point; this is always the last info.
+    pub item: Option<PlaylistItem>,
+}
+
+#[repr(C)]
+struct GrooveFingerprinterInfo {
+    /// raw fingerprint. a fingerprint is a 32 bit integer for every 0.1238
+    /// seconds of audio. use groove_fingerprinter_encode to compress it.
+    fingerprint: *mut int32_t,
+    /// number of 32 bit integers in the fingerprint
+    fingerprint_size: c_int,
+    /// how many seconds long this song is
+    duration: c_double,
+    /// the playlist item that this info applies to. when this is NULL this
+    /// is the end-of-playlist sentinel and other properties are undefined.
+    item: *mut GroovePlaylistItem,
+}
+
+#[repr(C)]
+struct GrooveFingerprinter {
+    /// maximum number of GrooveFingerprinterInfo items to store in this
+    /// fingerprinter's queue. defaults to MAX_INT
+    info_queue_size: c_int,
+    /// how big the sink buffer should be, in sample frames.
+    /// groove_fingerprinter_create defaults this to 8192
+    sink_buffer_size: c_int,
+
+    /// read-only. set when attached and cleared when detached
+    playlist: *mut GroovePlaylist,
+}
+
+/// attach a Fingerprinter to a playlist to compute the acoustid
+/// (chromaprint) fingerprint of each item.
+pub struct Fingerprinter {
+    groove_fingerprinter: *mut GrooveFingerprinter,
+}
+
+impl Drop for Fingerprinter {
+    fn drop(&mut self) {
+        unsafe {
+            if !(*self.groove_fingerprinter).playlist.is_null() {
+                self.detach();
+            }
+            groove_fingerprinter_destroy(self.groove_fingerprinter)
+        }
+    }
+}
+
+impl Fingerprinter {
+    pub fn new() -> Self {
+        init();
+        unsafe {
+            Fingerprinter { groove_fingerprinter: groove_fingerprinter_create() }
+        }
+    }
+
+    /// fails with excerpt::TRIMMED_PLAYLIST if the playlist has trimmed items.
+    pub fn attach(&self, playlist: &Playlist) -> Result<(), i32> {
+        if playlist.has_trimmed_items() {
+            return Result::Err(excerpt::TRIMMED_PLAYLIST);
+        }
+        unsafe {
+            let err_code = groove_fingerprinter_attach(self.groove_fingerprinter,
+                                                       playlist.groove_playlist);
+            if err_code >= 0 {
+                count_consumer(playlist.groove_playlist, false, true);
+                Result::Ok(())
+            } else {
+                Result::Err(err_code as i32)
+            }
+        }
+    }
+
+    pub fn detach(&self) {
+        unsafe {
+            let playlist = (*self.groove_fingerprinter).playlist;
+            let _ = groove_fingerprinter_detach(self.groove_fingerprinter);
+            if !playlist.is_null() {
+                count_consumer(playlist, false, false);
+            }
+        }
+    }
+
+    /// returns Some<FingerprinterInfo> when there is info for an item, None
+    /// at the end of the playlist or if the fingerprinter was detached.
+    /// blocks the thread until one of those happens.
+    pub fn info_get_blocking(&self) -> Option<FingerprinterInfo> {
+        unsafe {
+            let mut info = GrooveFingerprinterInfo {
+                fingerprint: std::ptr::null_mut(),
+                fingerprint_size: 0,
+                duration: 0.0,
+                item: std::ptr::null_mut(),
+            };
+            if groove_fingerprinter_info_get(self.groove_fingerprinter, &mut info, 1) != 1 {
+                return Option::None;
+            }
+            if info.item.is_null() {
+                groove_fingerprinter_free_info(&mut info);
+                return Option::None;
+            }
+            let fingerprint = if info.fingerprint.is_null() {
+                Vec::new()
+            } else {
+                let raw_slice = std::raw::Slice {
+                    data: info.fingerprint as *const i32,
+                    len: info.fingerprint_size as usize,
+                };
+                std::mem::transmute::<std::raw::Slice<i32>, &[i32]>(raw_slice).to_vec()
+            };
+            let result = FingerprinterInfo {
+                fingerprint: fingerprint,
+                duration: info.duration,
+                item: PlaylistItem {groove_playlist_item: info.item},
+            };
+            groove_fingerprinter_free_info(&mut info);
+            Option::Some(result)
+        }
+    }
+
+    /// compress a raw fingerprint into the base64 string acoustid uses.
+    /// None if chromaprint fails.
+    pub fn encode(fingerprint: &[i32]) -> Option<String> {
+        init();
+        unsafe {
+            let mut encoded: *mut c_char = std::ptr::null_mut();
+            let err_code = groove_fingerprinter_encode(fingerprint.as_ptr() as *mut int32_t,
+                                                       fingerprint.len() as c_int, &mut encoded);
+            if err_code < 0 || encoded.is_null() {
+                return Option::None;
+            }
+            let result = c_str_to_string(encoded as *const c_char);
+            groove_fingerprinter_dealloc(encoded as *mut c_void);
+            Option::Some(result)
+        }
+    }
+
+    /// the raw fingerprint of an encoded one. None if it is not valid.
+    pub fn decode(encoded: &str) -> Option<Vec<i32>> {
+        init();
+        let c_encoded = CString::from_slice(encoded.as_bytes());
+        unsafe {
+            let mut fingerprint: *mut int32_t = std::ptr::null_mut();
+            let mut size: c_int = 0;
+            let err_code = groove_fingerprinter_decode(c_encoded.as_ptr() as *mut c_char,
+                                                       &mut fingerprint, &mut size);
+            if err_code < 0 || fingerprint.is_null() {
+                return Option::None;
+            }
+            let raw_slice = std::raw::Slice {
+                data: fingerprint as *const i32,
+                len: size as usize,
+            };
+            let result = std::mem::transmute::<std::raw::Slice<i32>, &[i32]>(raw_slice).to_vec();
+            groove_fingerprinter_dealloc(fingerprint as *mut c_void);
+            Option::Some(result)
+        }
+    }
+}
+
+pub struct FingerprinterInfo {
+    /// raw fingerprint, a 32 bit integer for every 0.1238 seconds of audio
+    pub fingerprint: Vec<i32>,
+    /// duration in seconds, measured by decoding
+    pub duration: f64,
+    pub item: PlaylistItem,
+}
+
 /// Call at the end of your program to clean up. After calling this you may no
 /// longer use this API. You may choose to never call this function, in which
 /// case the worst thing that can happen is valgrind may report a memory leak.
@@ -1222,7 +2669,255 @@ pub fn version() -> &'static str {
     }
 }
 
+/// a container format known to the installed libav
+pub struct FormatInfo {
+    /// short name, for use with Encoder::set_format_short_name.
+    /// demuxers sometimes have a comma separated list of names.
+    pub name: String,
+    pub description: String,
+    /// file extensions without the leading dot
+    pub extensions: Vec<String>,
+    /// always empty for demuxers
+    pub mime_types: Vec<String>,
+    /// true if this format can be written by an Encoder, false if it can
+    /// only be read by File::open
+    pub is_muxer: bool,
+}
+
+/// an audio codec known to the installed libav
+pub struct CodecInfo {
+    /// short name, for use with Encoder::set_codec_short_name
+    pub name: String,
+    pub description: String,
+    pub is_encoder: bool,
+    pub is_decoder: bool,
+    /// empty if the codec did not declare which sample formats it supports
+    pub sample_formats: Vec<SampleFormat>,
+    /// empty if the codec did not declare which sample rates it supports
+    pub sample_rates: Vec<i32>,
+}
+
+/// list every muxer and demuxer in the installed libav
+pub fn formats() -> Vec<FormatInfo> {
+    init();
+    let mut result = Vec::new();
+    unsafe {
+        let mut oformat = av_oformat_next(std::ptr::null());
+        while !oformat.is_null() {
+            result.push(FormatInfo {
+                name: c_str_to_string((*oformat).name),
+                description: c_str_to_string((*oformat).long_name),
+                extensions: split_c_str_list((*oformat).extensions),
+                mime_types: split_c_str_list((*oformat).mime_type),
+                is_muxer: true,
+            });
+            oformat = av_oformat_next(oformat);
+        }
+        let mut iformat = av_iformat_next(std::ptr::null());
+        while !iformat.is_null() {
+            result.push(FormatInfo {
+                name: c_str_to_string((*iformat).name),
+                description: c_str_to_string((*iformat).long_name),
+                extensions: split_c_str_list((*iformat).extensions),
+                mime_types: Vec::new(),
+                is_muxer: false,
+            });
+            iformat = av_iformat_next(iformat);
+        }
+    }
+    result
+}
+
+/// list every audio encoder and decoder in the installed libav
+pub fn codecs() -> Vec<CodecInfo> {
+    init();
+    let mut result = Vec::new();
+    unsafe {
+        let mut codec = av_codec_next(std::ptr::null());
+        while !codec.is_null() {
+            if (*codec).media_type == AVMEDIA_TYPE_AUDIO {
+                let mut sample_formats = Vec::new();
+                let mut fmt_ptr = (*codec).sample_fmts;
+                while !fmt_ptr.is_null() && *fmt_ptr != SAMPLE_FMT_NONE {
+                    // skip formats newer than this binding knows about
+                    if *fmt_ptr <= SAMPLE_FMT_DBLP {
+                        sample_formats.push(SampleFormat::from_groove(*fmt_ptr));
+                    }
+                    fmt_ptr = fmt_ptr.offset(1);
+                }
+                let mut sample_rates = Vec::new();
+                let mut rate_ptr = (*codec).supported_samplerates;
+                while !rate_ptr.is_null() && *rate_ptr != 0 {
+                    sample_rates.push(*rate_ptr as i32);
+                    rate_ptr = rate_ptr.offset(1);
+                }
+                result.push(CodecInfo {
+                    name: c_str_to_string((*codec).name),
+                    description: c_str_to_string((*codec).long_name),
+                    is_encoder: av_codec_is_encoder(codec) != 0,
+                    is_decoder: av_codec_is_decoder(codec) != 0,
+                    sample_formats: sample_formats,
+                    sample_rates: sample_rates,
+                });
+            }
+            codec = av_codec_next(codec);
+        }
+    }
+    result
+}
+
+/// the files to work on for tools which take files and directories.
+/// directories are walked recursively and the files in them are kept only
+/// if they have an audio stream, sorted. libav's demuxers do not say which
+/// media they hold, and some of them read text, subtitles or images, so
+/// every file whose extension one of them claims is opened to find out.
+/// other paths are kept as they are, with the flag set, so that a file
+/// named explicitly which can not be opened can be reported.
+pub fn find_audio_files(paths: &[Path]) -> IoResult<Vec<(Path, bool)>> {
+    let mut extensions = HashSet::new();
+    for format in formats().into_iter().filter(|format| !format.is_muxer) {
+        // demuxer names are often extensions too, like "mov,mp4,m4a"
+        extensions.extend(format.name.split(',').map(|name| name.to_ascii_lowercase()));
+        extensions.extend(format.extensions.iter().map(|ext| ext.to_ascii_lowercase()));
+    }
+    let mut files = Vec::new();
+    for path in paths.iter() {
+        if path.is_dir() {
+            let mut found: Vec<Path> = try!(fs::walk_dir(path)).filter(|entry| {
+                entry.is_file() && entry.extension_str().map_or(false, |ext| {
+                    extensions.contains(&ext.to_ascii_lowercase())
+                }) && File::open(entry).is_some()
+            }).collect();
+            found.sort();
+            files.extend(found.into_iter().map(|path| (path, false)));
+        } else {
+            files.push((path.clone(), true));
+        }
+    }
+    Result::Ok(files)
+}
+
+/// copy a C string which libav owns. null becomes the empty string.
+fn c_str_to_string(c_str: *const c_char) -> String {
+    if c_str.is_null() {
+        return String::new();
+    }
+    unsafe {
+        let slice = std::ffi::c_str_to_bytes(&c_str);
+        String::from_utf8_lossy(slice).into_owned()
+    }
+}
+
+/// split a comma separated C string such as "mp3,mp2"
+fn split_c_str_list(c_str: *const c_char) -> Vec<String> {
+    c_str_to_string(c_str).split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// a unique path in the temporary directory, such as
+/// /tmp/groove-identify-1234-0.wav for kind "identify"
+fn temp_path(kind: &str) -> Path {
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let pid = unsafe { libc::getpid() };
+    std::os::tmpdir().join(format!("groove-{}-{}-{}.wav", kind, pid, n))
+}
+
+/// writes a WAV file with a WAVE_FORMAT_EXTENSIBLE header, which keeps
+/// the channel layout. the sizes in the header are filled in by finish;
+/// when the data grows past what 32 bit sizes can describe, finish turns
+/// the file into RF64 (EBU Tech 3306), using the JUNK chunk reserved for
+/// it. samples are written as they come from a sink, in native byte order,
+/// which is little endian on every platform libgroove supports.
+struct WavWriter {
+    file: std::old_io::File,
+    block_align: u64,
+    data_size: u64,
+}
+
+/// bytes before the samples: RIFF header, JUNK, fmt and data chunk headers
+const WAV_HEADER_SIZE: u64 = 12 + (8 + 28) + (8 + 40) + 8;
+
+impl WavWriter {
+    /// format must be interleaved
+    fn create(path: &Path, format: AudioFormat) -> IoResult<WavWriter> {
+        let mut file = try!(std::old_io::File::create(path));
+        let channels = format.channel_layout.count() as u16;
+        let bytes_per_sample = format.sample_fmt.bytes_per_sample() as u16;
+        let format_tag = match format.sample_fmt.sample_type {
+            SampleType::Flt | SampleType::Dbl => 3,
+            _ => 1,
+        };
+        try!(file.write_all(b"RIFF"));
+        try!(file.write_le_u32(0));
+        try!(file.write_all(b"WAVE"));
+        // space for the ds64 chunk of RF64
+        try!(file.write_all(b"JUNK"));
+        try!(file.write_le_u32(28));
+        try!(file.write_all(&[0u8; 28]));
+        try!(file.write_all(b"fmt "));
+        try!(file.write_le_u32(40));
+        try!(file.write_le_u16(0xfffe));
+        try!(file.write_le_u16(channels));
+        try!(file.write_le_u32(format.sample_rate as u32));
+        try!(file.write_le_u32(format.sample_rate as u32 * (channels * bytes_per_sample) as u32));
+        try!(file.write_le_u16(channels * bytes_per_sample));
+        try!(file.write_le_u16(bytes_per_sample * 8));
+        try!(file.write_le_u16(22));
+        try!(file.write_le_u16(bytes_per_sample * 8));
+        // the speaker positions of libav channel masks match WAV's
+        try!(file.write_le_u32((format.channel_layout.to_groove() & 0x3ffff) as u32));
+        try!(file.write_le_u16(format_tag));
+        try!(file.write_all(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71"));
+        try!(file.write_all(b"data"));
+        try!(file.write_le_u32(0));
+        Result::Ok(WavWriter {
+            file: file,
+            block_align: (channels * bytes_per_sample) as u64,
+            data_size: 0,
+        })
+    }
+
+    fn write(&mut self, samples: &[u8]) -> IoResult<()> {
+        self.data_size += samples.len() as u64;
+        self.file.write_all(samples)
+    }
+
+    fn finish(mut self) -> IoResult<()> {
+        // chunks have an even size
+        let padding = self.data_size % 2;
+        if padding == 1 {
+            try!(self.file.write_u8(0));
+        }
+        let riff_size = WAV_HEADER_SIZE - 8 + self.data_size + padding;
+        if riff_size > 0xffffffff {
+            try!(self.file.seek(0, SeekSet));
+            try!(self.file.write_all(b"RF64"));
+            try!(self.file.write_le_u32(0xffffffff));
+            try!(self.file.seek(12, SeekSet));
+            try!(self.file.write_all(b"ds64"));
+            try!(self.file.write_le_u32(28));
+            try!(self.file.write_le_u64(riff_size));
+            try!(self.file.write_le_u64(self.data_size));
+            try!(self.file.write_le_u64(self.data_size / self.block_align));
+            try!(self.file.write_le_u32(0));
+            try!(self.file.seek(WAV_HEADER_SIZE as i64 - 4, SeekSet));
+            try!(self.file.write_le_u32(0xffffffff));
+        } else {
+            try!(self.file.seek(4, SeekSet));
+            try!(self.file.write_le_u32(riff_size as u32));
+            try!(self.file.seek(WAV_HEADER_SIZE as i64 - 4, SeekSet));
+            try!(self.file.write_le_u32(self.data_size as u32));
+        }
+        self.file.flush()
+    }
+}
+
 const TAG_MATCH_CASE: c_int = 1;
+/// allow several tags with the same key. same value as AV_DICT_MULTIKEY.
+const TAG_MULTIKEY:   c_int = 64;
 
 const BUFFER_NO:  c_int = 0;
 const BUFFER_YES: c_int = 1;
@@ -1259,3 +2954,41 @@ impl<P: Destroy + Hash<Hasher> + Eq> PointerReferenceCounter<P> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{consumers, count_consumer, decode_lossy, Consumers, GroovePlaylist};
+
+    #[test]
+    fn decode_lossy_keeps_utf8() {
+        assert_eq!(decode_lossy("Sigur Rós – Hoppípolla".as_bytes()), "Sigur Rós – Hoppípolla");
+        assert_eq!(decode_lossy(b""), "");
+    }
+
+    #[test]
+    fn decode_lossy_reads_latin1() {
+        assert_eq!(decode_lossy(b"Sigur R\xf3s"), "Sigur Rós");
+        assert_eq!(decode_lossy(b"caf\xe9 \xff"), "café ÿ");
+    }
+
+    #[test]
+    fn decode_lossy_reads_windows_1252() {
+        // 0x80 to 0x9f are printable in windows-1252 and controls in latin-1
+        assert_eq!(decode_lossy(b"\x93quoted\x94 \x96 \x80"), "\u{201c}quoted\u{201d} \u{2013} \u{20ac}");
+    }
+
+    #[test]
+    fn counts_consumers() {
+        // only the address is used
+        let playlist = 0x1000 as *mut GroovePlaylist;
+        count_consumer(playlist, true, true);
+        count_consumer(playlist, true, true);
+        count_consumer(playlist, false, true);
+        assert!(consumers(playlist) == Consumers { sinks: 2, others: 1 });
+        count_consumer(playlist, true, false);
+        count_consumer(playlist, false, false);
+        assert!(consumers(playlist) == Consumers { sinks: 1, others: 0 });
+        count_consumer(playlist, true, false);
+        assert!(consumers(playlist) == Consumers { sinks: 0, others: 0 });
+    }
+}
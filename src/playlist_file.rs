@@ -0,0 +1,707 @@
+//! load and save M3U/M3U8, PLS and XSPF playlist files.
+//!
+//! Loading appends to an existing Playlist, so sinks can be attached
+//! first. Relative paths are resolved against the directory of the
+//! playlist file. Entries which can not be opened are reported in the
+//! LoadReport and skipped.
+//!
+//! Only XSPF can carry a per entry gain and peak. They are stored as
+//! `<meta>` elements with the rels GAIN_REL and PEAK_REL, in float format.
+
+use std::old_io;
+use std::old_io::{IoError, IoResult, Reader, Writer};
+use std::ascii::AsciiExt;
+use std::char;
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::{decode_lossy, File, Playlist, PlaylistItem};
+
+/// XSPF meta rel of the gain in float format of an entry
+pub const GAIN_REL: &'static str = "https://github.com/andrewrk/groove-rs#gain";
+/// XSPF meta rel of the peak in float format of an entry
+pub const PEAK_REL: &'static str = "https://github.com/andrewrk/groove-rs#peak";
+
+/// an entry that was added to the playlist
+pub struct LoadedEntry {
+    pub item: PlaylistItem,
+    pub path: Path,
+    /// the title given by the playlist file, if any
+    pub title: Option<String>,
+    /// the duration in seconds given by the playlist file, if any
+    pub duration: Option<f64>,
+}
+
+pub struct LoadReport {
+    /// in playlist order
+    pub entries: Vec<LoadedEntry>,
+    /// the location as written in the playlist file, and why it was skipped
+    pub skipped: Vec<(String, String)>,
+}
+
+/// an entry as read from a playlist file, before it is opened
+struct Entry {
+    location: String,
+    title: Option<String>,
+    duration: Option<f64>,
+    gain: f64,
+    peak: f64,
+}
+
+impl Entry {
+    fn new(location: String) -> Entry {
+        Entry { location: location, title: Option::None, duration: Option::None, gain: 1.0, peak: 1.0 }
+    }
+}
+
+impl Playlist {
+    /// append the entries of an M3U or M3U8 file, with or without #EXTINF
+    /// lines
+    pub fn load_m3u(&self, path: &Path) -> IoResult<LoadReport> {
+        let text = try!(read_text(path));
+        Result::Ok(self.append_entries(path, parse_m3u(text.as_slice()), false))
+    }
+
+    /// append the entries of a PLS file
+    pub fn load_pls(&self, path: &Path) -> IoResult<LoadReport> {
+        let text = try!(read_text(path));
+        Result::Ok(self.append_entries(path, parse_pls(text.as_slice()), false))
+    }
+
+    /// append the tracks of an XSPF file. the XML parser is a small one,
+    /// without DTD validation or external entities. a file which is not
+    /// well-formed fails with InvalidInput.
+    pub fn load_xspf(&self, path: &Path) -> IoResult<LoadReport> {
+        let text = try!(read_text(path));
+        let entries = try!(parse_xspf(text.as_slice()).map_err(|msg| IoError {
+            kind: old_io::InvalidInput,
+            desc: "not a valid XSPF file",
+            detail: Option::Some(msg),
+        }));
+        Result::Ok(self.append_entries(path, entries, true))
+    }
+
+    /// write an extended M3U file. use the .m3u8 extension; the file is
+    /// always UTF-8.
+    pub fn save_m3u(&self, path: &Path) -> IoResult<()> {
+        let mut out = String::from_str("#EXTM3U\n");
+        for item in self.iter() {
+            let file = item.file();
+            out.push_str(format!("#EXTINF:{},{}\n", file.duration().round() as i64,
+                                 display_title(&file)).as_slice());
+            out.push_str(relative_location(path, &file.filename()).as_slice());
+            out.push('\n');
+        }
+        write_text(path, out.as_slice())
+    }
+
+    pub fn save_pls(&self, path: &Path) -> IoResult<()> {
+        let mut out = String::from_str("[playlist]\n");
+        let mut count = 0;
+        for (index, item) in self.iter().enumerate() {
+            let file = item.file();
+            let number = index + 1;
+            out.push_str(format!("File{}={}\n", number,
+                                 relative_location(path, &file.filename())).as_slice());
+            out.push_str(format!("Title{}={}\n", number, display_title(&file)).as_slice());
+            out.push_str(format!("Length{}={}\n", number, file.duration().round() as i64).as_slice());
+            count = number;
+        }
+        out.push_str(format!("NumberOfEntries={}\nVersion=2\n", count).as_slice());
+        write_text(path, out.as_slice())
+    }
+
+    /// write an XSPF file, including the gain and peak of every item
+    pub fn save_xspf(&self, path: &Path) -> IoResult<()> {
+        let mut out = String::from_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n");
+        for item in self.iter() {
+            let file = item.file();
+            let location = uri_encode(relative_location(path, &file.filename()).as_slice());
+            out.push_str("    <track>\n");
+            out.push_str(format!("      <location>{}</location>\n", xml_escape(location.as_slice())).as_slice());
+            for &(key, element) in [("title", "title"), ("artist", "creator"), ("album", "album")].iter() {
+                match file.metadata_get(key, false) {
+                    Option::Some(tag) => out.push_str(format!("      <{}>{}</{}>\n", element,
+                        xml_escape(tag.value_lossy().as_slice()), element).as_slice()),
+                    Option::None => {},
+                }
+            }
+            out.push_str(format!("      <duration>{}</duration>\n",
+                                 (file.duration() * 1000.0).round() as i64).as_slice());
+            out.push_str(format!("      <meta rel=\"{}\">{}</meta>\n", GAIN_REL, item.gain()).as_slice());
+            out.push_str(format!("      <meta rel=\"{}\">{}</meta>\n", PEAK_REL, item.peak()).as_slice());
+            out.push_str("    </track>\n");
+        }
+        out.push_str("  </trackList>\n</playlist>\n");
+        write_text(path, out.as_slice())
+    }
+
+    fn append_entries(&self, playlist_path: &Path, entries: Vec<Entry>, is_uri: bool) -> LoadReport {
+        let base_dir = playlist_path.dir_path();
+        let mut report = LoadReport { entries: Vec::new(), skipped: Vec::new() };
+        for entry in entries.into_iter() {
+            let path = match resolve_location(&base_dir, entry.location.as_slice(), is_uri) {
+                Result::Ok(path) => path,
+                Result::Err(reason) => {
+                    report.skipped.push((entry.location, reason));
+                    continue;
+                },
+            };
+            match File::open(&path) {
+                Option::Some(file) => {
+                    let item = self.append(&file, entry.gain, entry.peak);
+                    report.entries.push(LoadedEntry {
+                        item: item,
+                        path: path,
+                        title: entry.title,
+                        duration: entry.duration,
+                    });
+                },
+                Option::None => report.skipped.push((entry.location, "unable to open".to_string())),
+            }
+        }
+        report
+    }
+}
+
+fn parse_m3u(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut pending: Option<(Option<f64>, Option<String>)> = Option::None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with("#EXTINF:") {
+            // #EXTINF:duration,title
+            let info = &line[8..];
+            pending = Option::Some(match info.find(',') {
+                Option::Some(index) => (parse_seconds(&info[..index]),
+                                        non_empty(&info[index + 1..])),
+                Option::None => (parse_seconds(info), Option::None),
+            });
+        } else if line.is_empty() || line.starts_with("#") {
+            continue;
+        } else {
+            let mut entry = Entry::new(line.to_string());
+            match pending.take() {
+                Option::Some((duration, title)) => {
+                    entry.duration = duration;
+                    entry.title = title;
+                },
+                Option::None => {},
+            }
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+fn parse_pls(text: &str) -> Vec<Entry> {
+    // PLS entries are numbered and may appear in any order
+    let mut numbered: Vec<(u32, Entry)> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let (key, value) = match line.find('=') {
+            Option::Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+            Option::None => continue,
+        };
+        let (field, number) = match key.find(|c: char| c.is_digit(10)) {
+            Option::Some(index) => match key[index..].parse::<u32>() {
+                Result::Ok(number) => (&key[..index], number),
+                Result::Err(_) => continue,
+            },
+            Option::None => continue,
+        };
+        let index = match numbered.iter().position(|&(n, _)| n == number) {
+            Option::Some(index) => index,
+            Option::None => {
+                numbered.push((number, Entry::new(String::new())));
+                numbered.len() - 1
+            },
+        };
+        let entry = &mut numbered[index].1;
+        match field.to_ascii_lowercase().as_slice() {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = non_empty(value),
+            "length" => entry.duration = parse_seconds(value),
+            _ => {},
+        }
+    }
+    numbered.sort_by(|a, b| a.0.cmp(&b.0));
+    numbered.into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+/// the tracks of /playlist/trackList. namespace prefixes are ignored.
+fn parse_xspf(text: &str) -> Result<Vec<Entry>, String> {
+    let root = try!(parse_xml(text));
+    if root.name.as_slice() != "playlist" {
+        return Result::Err(format!("the root element is {}, not playlist", root.name));
+    }
+    let mut entries = Vec::new();
+    let track_list = match root.child("trackList") {
+        Option::Some(track_list) => track_list,
+        Option::None => return Result::Ok(entries),
+    };
+    for track in track_list.children.iter().filter(|e| e.name.as_slice() == "track") {
+        // a track may list several locations of the same file; use the first
+        let location = match track.child("location") {
+            Option::Some(location) => location.text.trim().to_string(),
+            Option::None => continue,
+        };
+        let mut entry = Entry::new(location);
+        entry.title = track.child("title").and_then(|title| non_empty(title.text.as_slice()));
+        entry.duration = track.child("duration")
+            .and_then(|ms| ms.text.trim().parse::<f64>().ok())
+            .map(|ms| ms / 1000.0);
+        for meta in track.children.iter().filter(|e| e.name.as_slice() == "meta") {
+            let value = match meta.text.trim().parse::<f64>() {
+                Result::Ok(value) => value,
+                Result::Err(_) => continue,
+            };
+            match meta.attribute("rel") {
+                Option::Some(rel) if rel.trim() == GAIN_REL => entry.gain = value,
+                Option::Some(rel) if rel.trim() == PEAK_REL => entry.peak = value,
+                _ => {},
+            }
+        }
+        entries.push(entry);
+    }
+    Result::Ok(entries)
+}
+
+fn read_text(path: &Path) -> IoResult<String> {
+    let bytes = try!(old_io::File::open(path).and_then(|mut f| f.read_to_end()));
+    // .m3u files from older players are often Latin-1
+    Result::Ok(decode_lossy(bytes.as_slice()))
+}
+
+fn write_text(path: &Path, text: &str) -> IoResult<()> {
+    let mut f = try!(old_io::File::create(path));
+    f.write_str(text)
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() { Option::None } else { Option::Some(s.to_string()) }
+}
+
+/// negative durations mean unknown
+fn parse_seconds(s: &str) -> Option<f64> {
+    match s.trim().parse::<f64>() {
+        Result::Ok(seconds) if seconds >= 0.0 => Option::Some(seconds),
+        _ => Option::None,
+    }
+}
+
+fn resolve_location(base_dir: &Path, location: &str, is_uri: bool) -> Result<Path, String> {
+    let (location, decode) = if location.starts_with("file://") {
+        (&location[7..], true)
+    } else if location.contains("://") {
+        return Result::Err("only local files are supported".to_string());
+    } else {
+        (location, is_uri)
+    };
+    let bytes = if decode {
+        uri_decode(location)
+    } else {
+        location.as_bytes().to_vec()
+    };
+    // Windows playlists use backslashes
+    let bytes: Vec<u8> = bytes.into_iter().map(|b| if b == b'\\' {b'/'} else {b}).collect();
+    let path = Path::new(bytes);
+    Result::Ok(if path.is_absolute() { path } else { base_dir.join(path) })
+}
+
+/// the location of file as written into the playlist at playlist_path:
+/// relative if the file is inside the playlist's directory
+fn relative_location(playlist_path: &Path, file_path: &Path) -> String {
+    let base_dir = playlist_path.dir_path();
+    let location = match file_path.path_relative_from(&base_dir) {
+        Option::Some(relative) => if relative.as_vec().starts_with(b"..") {
+            file_path.clone()
+        } else {
+            relative
+        },
+        Option::None => file_path.clone(),
+    };
+    String::from_utf8_lossy(location.as_vec()).into_owned()
+}
+
+fn display_title(file: &File) -> String {
+    let get = |key: &str| file.metadata_get(key, false).map(|tag| tag.value_lossy());
+    match (get("artist"), get("title")) {
+        (Option::Some(artist), Option::Some(title)) => format!("{} - {}", artist, title),
+        (Option::None, Option::Some(title)) => title,
+        _ => {
+            let path = file.filename();
+            let name = path.filename().unwrap_or(b"");
+            String::from_utf8_lossy(name).into_owned()
+        },
+    }
+}
+
+/// an XML element with what XSPF needs of it
+struct XmlElement {
+    /// without namespace prefix
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<XmlElement>,
+    /// the character data directly inside the element, with entities and
+    /// CDATA sections decoded
+    text: String,
+}
+
+impl XmlElement {
+    fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|e| e.name.as_slice() == name)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| v.as_slice())
+    }
+}
+
+/// parse a well-formed XML document into its root element. comments,
+/// processing instructions and the doctype are skipped.
+fn parse_xml(text: &str) -> Result<XmlElement, String> {
+    let bytes = text.as_bytes();
+    let mut stack: Vec<XmlElement> = Vec::new();
+    let mut root = Option::None;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &text[pos..];
+        if bytes[pos] != b'<' {
+            let end = rest.find('<').unwrap_or(rest.len());
+            match stack.last_mut() {
+                Option::Some(element) => element.text.push_str(xml_unescape(&rest[..end]).as_slice()),
+                Option::None => if !rest[..end].trim().is_empty() {
+                    return Result::Err("text outside of the root element".to_string());
+                },
+            }
+            pos += end;
+        } else if rest.starts_with("<!--") {
+            pos += try!(skip_past(rest, "-->"));
+        } else if rest.starts_with("<![CDATA[") {
+            let end = try!(skip_past(rest, "]]>"));
+            match stack.last_mut() {
+                Option::Some(element) => element.text.push_str(&rest[9..end - 3]),
+                Option::None => return Result::Err("CDATA outside of the root element".to_string()),
+            }
+            pos += end;
+        } else if rest.starts_with("<?") {
+            pos += try!(skip_past(rest, "?>"));
+        } else if rest.starts_with("<!") {
+            // a doctype, possibly with an internal subset in brackets
+            let mut depth = 0;
+            let mut end = Option::None;
+            for (index, &byte) in rest.as_bytes().iter().enumerate() {
+                match byte {
+                    b'[' => depth += 1,
+                    b']' => depth -= 1,
+                    b'>' if depth == 0 => {
+                        end = Option::Some(index + 1);
+                        break;
+                    },
+                    _ => {},
+                }
+            }
+            pos += try!(end.ok_or("unterminated doctype".to_string()));
+        } else if rest.starts_with("</") {
+            let end = try!(skip_past(rest, ">"));
+            let name = local_name(rest[2..end - 1].trim());
+            let element = try!(stack.pop().ok_or(format!("unexpected </{}>", name)));
+            if element.name.as_slice() != name {
+                return Result::Err(format!("<{}> closed by </{}>", element.name, name));
+            }
+            match stack.last_mut() {
+                Option::Some(parent) => parent.children.push(element),
+                Option::None => root = Option::Some(element),
+            }
+            pos += end;
+        } else {
+            if root.is_some() {
+                return Result::Err("more than one root element".to_string());
+            }
+            let (element, self_closing, end) = try!(parse_start_tag(rest));
+            if self_closing {
+                match stack.last_mut() {
+                    Option::Some(parent) => parent.children.push(element),
+                    Option::None => root = Option::Some(element),
+                }
+            } else {
+                stack.push(element);
+            }
+            pos += end;
+        }
+    }
+    match stack.last() {
+        Option::Some(element) => return Result::Err(format!("<{}> is not closed", element.name)),
+        Option::None => {},
+    }
+    root.ok_or("no root element".to_string())
+}
+
+/// the length of s up to and including the first terminator
+fn skip_past(s: &str, terminator: &str) -> Result<usize, String> {
+    match s.find_str(terminator) {
+        Option::Some(index) => Result::Ok(index + terminator.len()),
+        Option::None => Result::Err(format!("missing {}", terminator)),
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    match name.rfind(':') {
+        Option::Some(index) => &name[index + 1..],
+        Option::None => name,
+    }
+}
+
+fn is_xml_space(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r'
+}
+
+/// parse the start tag at the beginning of s. returns the element, whether
+/// the tag closes itself, and the length of the tag.
+fn parse_start_tag(s: &str) -> Result<(XmlElement, bool, usize), String> {
+    let bytes = s.as_bytes();
+    let mut pos = 1;
+    while pos < bytes.len() && !is_xml_space(bytes[pos]) && bytes[pos] != b'/' && bytes[pos] != b'>' {
+        pos += 1;
+    }
+    if pos == 1 {
+        return Result::Err("a tag without a name".to_string());
+    }
+    let mut element = XmlElement {
+        name: local_name(&s[1..pos]).to_string(),
+        attributes: Vec::new(),
+        children: Vec::new(),
+        text: String::new(),
+    };
+    loop {
+        while pos < bytes.len() && is_xml_space(bytes[pos]) {
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            return Result::Err(format!("<{}> is not terminated", element.name));
+        }
+        if bytes[pos] == b'>' {
+            return Result::Ok((element, false, pos + 1));
+        }
+        if s[pos..].starts_with("/>") {
+            return Result::Ok((element, true, pos + 2));
+        }
+        let name_start = pos;
+        while pos < bytes.len() && !is_xml_space(bytes[pos]) && bytes[pos] != b'=' &&
+            bytes[pos] != b'>' && bytes[pos] != b'/'
+        {
+            pos += 1;
+        }
+        let name = local_name(&s[name_start..pos]).to_string();
+        while pos < bytes.len() && is_xml_space(bytes[pos]) {
+            pos += 1;
+        }
+        if pos >= bytes.len() || bytes[pos] != b'=' {
+            return Result::Err(format!("attribute {} of <{}> has no value", name, element.name));
+        }
+        pos += 1;
+        while pos < bytes.len() && is_xml_space(bytes[pos]) {
+            pos += 1;
+        }
+        if pos >= bytes.len() || (bytes[pos] != b'"' && bytes[pos] != b'\'') {
+            return Result::Err(format!("attribute {} of <{}> is not quoted", name, element.name));
+        }
+        let quote = bytes[pos];
+        let value_start = pos + 1;
+        pos = value_start;
+        while pos < bytes.len() && bytes[pos] != quote {
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            return Result::Err(format!("attribute {} of <{}> is not terminated", name, element.name));
+        }
+        element.attributes.push((name, xml_unescape(&s[value_start..pos])));
+        pos += 1;
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+fn xml_unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        match rest.find('&') {
+            Option::Some(amp) => {
+                out.push_str(&rest[..amp]);
+                let after = &rest[amp..];
+                let semi = match after.find(';') {
+                    Option::Some(semi) => semi,
+                    Option::None => {
+                        out.push_str(after);
+                        break;
+                    },
+                };
+                let entity = &after[1..semi];
+                let decoded = match entity {
+                    "amp" => Option::Some('&'),
+                    "lt" => Option::Some('<'),
+                    "gt" => Option::Some('>'),
+                    "quot" => Option::Some('"'),
+                    "apos" => Option::Some('\''),
+                    _ if entity.starts_with("#x") => parse_hex(&entity[2..]).and_then(char::from_u32),
+                    _ if entity.starts_with("#") => entity[1..].parse::<u32>().ok()
+                        .and_then(char::from_u32),
+                    _ => Option::None,
+                };
+                match decoded {
+                    Option::Some(c) => out.push(c),
+                    Option::None => out.push_str(&after[..semi + 1]),
+                }
+                rest = &after[semi + 1..];
+            },
+            Option::None => {
+                out.push_str(rest);
+                break;
+            },
+        }
+    }
+    out
+}
+
+fn uri_encode(s: &str) -> String {
+    let mut out = String::new();
+    for &byte in s.as_bytes().iter() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            },
+            _ => out.push_str(format!("%{:02X}", byte).as_slice()),
+        }
+    }
+    out
+}
+
+fn uri_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+            match parse_hex(hex.as_slice()) {
+                Option::Some(byte) => {
+                    out.push(byte as u8);
+                    i += 3;
+                    continue;
+                },
+                Option::None => {},
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    if s.is_empty() || s.len() > 6 {
+        return Option::None;
+    }
+    let mut value = 0;
+    for c in s.chars() {
+        match c.to_digit(16) {
+            Option::Some(digit) => value = value * 16 + digit as u32,
+            Option::None => return Option::None,
+        }
+    }
+    Option::Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_m3u, parse_pls, parse_xspf, GAIN_REL, PEAK_REL};
+
+    #[test]
+    fn m3u_plain() {
+        let entries = parse_m3u("a.mp3\r\n\r\n# a comment\r\nsub/b.flac\r\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "a.mp3");
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[1].location, "sub/b.flac");
+    }
+
+    #[test]
+    fn m3u_extended() {
+        let entries = parse_m3u("#EXTM3U\n#EXTINF:215,Low - Words, Part 2\n/music/a.ogg\n\
+                                 #EXTINF:-1,\nhttp://example.com/stream\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "/music/a.ogg");
+        assert_eq!(entries[0].duration, Some(215.0));
+        assert_eq!(entries[0].title, Some("Low - Words, Part 2".to_string()));
+        assert_eq!(entries[1].duration, None);
+        assert_eq!(entries[1].title, None);
+    }
+
+    #[test]
+    fn pls_sorted_by_number() {
+        let entries = parse_pls("[playlist]\nFile2=b.mp3\nTitle2=B\nFile1 = a.mp3\nLength1=61\n\
+                                 Title3=no file\nNumberOfEntries=2\nVersion=2\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "a.mp3");
+        assert_eq!(entries[0].duration, Some(61.0));
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[1].location, "b.mp3");
+        assert_eq!(entries[1].title, Some("B".to_string()));
+    }
+
+    #[test]
+    fn xspf_as_saved() {
+        let text = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n\
+            <track>\n<location>a%20b.flac</location>\n<title>Fish &amp; Chips</title>\n\
+            <duration>1500</duration>\n<meta rel=\"{}\">0.5</meta>\n<meta rel=\"{}\">0.9</meta>\n\
+            </track>\n  </trackList>\n</playlist>\n", GAIN_REL, PEAK_REL);
+        let entries = parse_xspf(text.as_slice()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].location, "a%20b.flac");
+        assert_eq!(entries[0].title, Some("Fish & Chips".to_string()));
+        assert_eq!(entries[0].duration, Some(1.5));
+        assert_eq!(entries[0].gain, 0.5);
+        assert_eq!(entries[0].peak, 0.9);
+    }
+
+    #[test]
+    fn xspf_written_by_other_tools() {
+        let text = format!("<?xml version='1.0'?>\n<!DOCTYPE playlist [<!ENTITY x \"y\">]>\n\
+            <!-- exported -->\n<x:playlist xmlns:x='http://xspf.org/ns/0/' version='1'>\
+            <x:trackList>\
+              <x:track id = 'one' ><x:location >\n  file:///m/a.ogg  \n</x:location>\
+                <x:title><![CDATA[<Live> & \"Loud\"]]></x:title>\
+                <x:meta rel = '{}' >0.25</x:meta><x:image/></x:track>\
+              <x:track><x:title>no location</x:title></x:track>\
+              <x:track><x:location>b&#x2F;&#99;.mp3</x:location></x:track>\
+            </x:trackList></x:playlist>", GAIN_REL);
+        let entries = parse_xspf(text.as_slice()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "file:///m/a.ogg");
+        assert_eq!(entries[0].title, Some("<Live> & \"Loud\"".to_string()));
+        assert_eq!(entries[0].gain, 0.25);
+        assert_eq!(entries[0].peak, 1.0);
+        assert_eq!(entries[1].location, "b/c.mp3");
+    }
+
+    #[test]
+    fn xspf_rejects_malformed_xml() {
+        assert!(parse_xspf("<playlist><trackList></playlist>").is_err());
+        assert!(parse_xspf("<playlist><trackList>").is_err());
+        assert!(parse_xspf("<playlist attr=unquoted/>").is_err());
+        assert!(parse_xspf("<rss/>").is_err());
+        assert!(parse_xspf("").is_err());
+        assert_eq!(parse_xspf("<playlist/>").unwrap().len(), 0);
+    }
+}
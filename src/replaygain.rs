@@ -0,0 +1,230 @@
+//! ReplayGain 2.0 scanning, tagging and playback.
+//!
+//! A set of files is scanned as one album with a LoudnessDetector. The
+//! results are written as REPLAYGAIN_TRACK_GAIN, REPLAYGAIN_TRACK_PEAK,
+//! REPLAYGAIN_ALBUM_GAIN and REPLAYGAIN_ALBUM_PEAK tags, and can be read
+//! back to get the gain and peak arguments of Playlist::append.
+
+use std::ascii::AsciiExt;
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::{File, LoudnessDetector, Playlist, PlaylistItem};
+
+/// ReplayGain 2.0 reference loudness in LUFS
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+const TRACK_GAIN: &'static str = "REPLAYGAIN_TRACK_GAIN";
+const TRACK_PEAK: &'static str = "REPLAYGAIN_TRACK_PEAK";
+const ALBUM_GAIN: &'static str = "REPLAYGAIN_ALBUM_GAIN";
+const ALBUM_PEAK: &'static str = "REPLAYGAIN_ALBUM_PEAK";
+
+#[derive(Copy, Debug)]
+pub struct Gain {
+    /// adjustment in dB to bring the audio to the reference loudness
+    pub gain_db: f64,
+    /// sample peak in float format
+    pub peak: f64,
+}
+
+impl Gain {
+    fn from_loudness(loudness: f64, peak: f64) -> Gain {
+        Gain {
+            // silence has no meaningful loudness; leave it alone
+            gain_db: if loudness.is_finite() { REFERENCE_LUFS - loudness } else { 0.0 },
+            peak: peak,
+        }
+    }
+
+    /// the gain in float format, as used by PlaylistItem
+    pub fn gain_float(&self) -> f64 {
+        db_to_float(self.gain_db)
+    }
+}
+
+pub struct AlbumScan {
+    /// in the same order as the files passed to scan
+    pub tracks: Vec<Gain>,
+    pub album: Gain,
+}
+
+/// the ReplayGain tags found in a file
+#[derive(Copy, Debug)]
+pub struct Tags {
+    pub track: Option<Gain>,
+    pub album: Option<Gain>,
+}
+
+/// which gain to honour during playback
+#[derive(Copy, Debug)]
+pub enum Mode {
+    Track,
+    /// falls back to the track gain if there is no album gain
+    Album,
+}
+
+/// convert dB to float format
+pub fn db_to_float(db: f64) -> f64 {
+    (10.0f64.ln() * 0.05 * db).exp()
+}
+
+/// decode all files and compute track and album gain and peak.
+/// the files are treated as one album.
+/// returns the error code of LoudnessDetector::attach on failure.
+pub fn scan(files: &[File]) -> Result<AlbumScan, i32> {
+    let playlist = Playlist::new();
+    let detector = LoudnessDetector::new();
+    try!(detector.attach(&playlist));
+
+    let items: Vec<PlaylistItem> = files.iter().map(|file| playlist.append(file, 1.0, 1.0)).collect();
+    let mut tracks: Vec<Gain> = files.iter().map(|_| Gain { gain_db: 0.0, peak: 1.0 }).collect();
+    let mut album = Gain { gain_db: 0.0, peak: 1.0 };
+    loop {
+        match detector.info_get_blocking() {
+            Option::Some(info) => match info.item {
+                Option::Some(item) => match items.iter().position(|x| *x == item) {
+                    Option::Some(index) => tracks[index] = Gain::from_loudness(info.loudness, info.peak),
+                    Option::None => {},
+                },
+                Option::None => {
+                    album = Gain::from_loudness(info.loudness, info.peak);
+                    break;
+                },
+            },
+            Option::None => break,
+        }
+    }
+    detector.detach();
+    Result::Ok(AlbumScan { tracks: tracks, album: album })
+}
+
+/// set the ReplayGain tags on a file. call File::save to write them to disk.
+pub fn write_tags(file: &File, track: &Gain, album: Option<&Gain>) -> Result<(), i32> {
+    try!(file.metadata_set(TRACK_GAIN, format_gain(track.gain_db).as_slice(), false));
+    try!(file.metadata_set(TRACK_PEAK, format_peak(track.peak).as_slice(), false));
+    match album {
+        Option::Some(album) => {
+            try!(file.metadata_set(ALBUM_GAIN, format_gain(album.gain_db).as_slice(), false));
+            try!(file.metadata_set(ALBUM_PEAK, format_peak(album.peak).as_slice(), false));
+        },
+        Option::None => {
+            try!(file.metadata_delete(ALBUM_GAIN, false));
+            try!(file.metadata_delete(ALBUM_PEAK, false));
+        },
+    }
+    Result::Ok(())
+}
+
+/// scan files as one album, then tag and save each of them
+pub fn scan_and_tag(files: &[File]) -> Result<AlbumScan, i32> {
+    let result = try!(scan(files));
+    for (file, track) in files.iter().zip(result.tracks.iter()) {
+        try!(write_tags(file, track, Option::Some(&result.album)));
+        try!(file.save());
+    }
+    Result::Ok(result)
+}
+
+/// parse the ReplayGain tags of a file. a gain without a peak is assumed
+/// to have a peak of 1.0.
+pub fn read_tags(file: &File) -> Tags {
+    Tags {
+        track: read_gain(file, TRACK_GAIN, TRACK_PEAK),
+        album: read_gain(file, ALBUM_GAIN, ALBUM_PEAK),
+    }
+}
+
+/// the gain and peak arguments for Playlist::append and Playlist::insert
+/// according to the file's tags. (1.0, 1.0) if the file has no tags.
+pub fn playback_gain(file: &File, mode: Mode) -> (f64, f64) {
+    let tags = read_tags(file);
+    let gain = match mode {
+        Mode::Track => tags.track,
+        Mode::Album => tags.album.or(tags.track),
+    };
+    match gain {
+        Option::Some(gain) => (gain.gain_float(), gain.peak),
+        Option::None => (1.0, 1.0),
+    }
+}
+
+/// append a file to a playlist, honouring its ReplayGain tags
+pub fn append(playlist: &Playlist, file: &File, mode: Mode) -> PlaylistItem {
+    let (gain, peak) = playback_gain(file, mode);
+    playlist.append(file, gain, peak)
+}
+
+fn read_gain(file: &File, gain_key: &str, peak_key: &str) -> Option<Gain> {
+    let gain_db = match file.metadata_get(gain_key, false) {
+        Option::Some(tag) => match tag.value() {
+            Result::Ok(value) => parse_gain(value),
+            Result::Err(_) => Option::None,
+        },
+        Option::None => Option::None,
+    };
+    let peak = match file.metadata_get(peak_key, false) {
+        Option::Some(tag) => match tag.value() {
+            Result::Ok(value) => value.trim().parse::<f64>().ok(),
+            Result::Err(_) => Option::None,
+        },
+        Option::None => Option::None,
+    };
+    gain_db.map(|gain_db| Gain { gain_db: gain_db, peak: peak.unwrap_or(1.0) })
+}
+
+/// parse a value such as "-6.54 dB" or "+2.10 dB". the unit is optional.
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = if value.to_ascii_lowercase().ends_with("db") {
+        value[..value.len() - 2].trim()
+    } else {
+        value
+    };
+    let number = if number.starts_with("+") {&number[1..]} else {number};
+    number.parse::<f64>().ok().and_then(|gain| if gain.is_finite() {Option::Some(gain)} else {Option::None})
+}
+
+fn format_gain(gain_db: f64) -> String {
+    format!("{:.2} dB", gain_db)
+}
+
+fn format_peak(peak: f64) -> String {
+    format!("{:.6}", peak)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::option::Option;
+
+    use super::parse_gain;
+
+    #[test]
+    fn gain_with_unit() {
+        assert_eq!(parse_gain("-6.54 dB"), Option::Some(-6.54));
+        assert_eq!(parse_gain("+2.10 dB"), Option::Some(2.1));
+        assert_eq!(parse_gain("0.00 dB"), Option::Some(0.0));
+        assert_eq!(parse_gain("-6.54dB"), Option::Some(-6.54));
+        assert_eq!(parse_gain("-6.54 db"), Option::Some(-6.54));
+        assert_eq!(parse_gain("  -6.54 DB \n"), Option::Some(-6.54));
+    }
+
+    #[test]
+    fn gain_without_unit() {
+        assert_eq!(parse_gain("-6.54"), Option::Some(-6.54));
+        assert_eq!(parse_gain("+1"), Option::Some(1.0));
+        assert_eq!(parse_gain("12"), Option::Some(12.0));
+    }
+
+    #[test]
+    fn invalid_gain() {
+        assert_eq!(parse_gain(""), Option::None);
+        assert_eq!(parse_gain("dB"), Option::None);
+        assert_eq!(parse_gain("loud"), Option::None);
+        assert_eq!(parse_gain("-6.54 LU"), Option::None);
+        assert_eq!(parse_gain("1.5 dB dB"), Option::None);
+        assert_eq!(parse_gain("+-3 dB"), Option::None);
+        assert_eq!(parse_gain("inf dB"), Option::None);
+        assert_eq!(parse_gain("NaN"), Option::None);
+    }
+}
@@ -0,0 +1,386 @@
+//! typed access to the common tags of a File.
+//!
+//! libav already maps most ID3v2 frames, Vorbis comments, APE items and MP4
+//! atoms to generic names such as "title" and "album_artist", but some
+//! fields, such as BPM, ISRC and the MusicBrainz IDs, keep the name of the
+//! container's convention. Tags reads every known spelling and writes the
+//! spelling that matches the file.
+
+use std::option::Option;
+use std::result::Result;
+
+use super::File;
+
+/// how a field is stored
+struct Key {
+    /// looked up in order, case insensitively
+    read: &'static [&'static str],
+    /// written to ID3v2 tags
+    id3: &'static str,
+    /// written to every other kind of tag
+    other: &'static str,
+}
+
+static TITLE: Key = Key { read: &["title"], id3: "title", other: "title" };
+static ARTIST: Key = Key { read: &["artist"], id3: "artist", other: "artist" };
+static ALBUM: Key = Key { read: &["album"], id3: "album", other: "album" };
+static ALBUM_ARTIST: Key = Key {
+    read: &["album_artist", "albumartist", "album artist"],
+    id3: "album_artist",
+    other: "album_artist",
+};
+static DATE: Key = Key { read: &["date", "year", "TDRC", "TYER"], id3: "date", other: "date" };
+static GENRE: Key = Key { read: &["genre"], id3: "genre", other: "genre" };
+static COMPOSER: Key = Key { read: &["composer"], id3: "composer", other: "composer" };
+static COMMENT: Key = Key { read: &["comment", "description"], id3: "comment", other: "comment" };
+static ISRC: Key = Key { read: &["TSRC", "ISRC"], id3: "TSRC", other: "ISRC" };
+static BPM: Key = Key { read: &["TBPM", "BPM"], id3: "TBPM", other: "BPM" };
+static MB_RECORDING_ID: Key = Key {
+    read: &["MUSICBRAINZ_TRACKID", "MusicBrainz Track Id"],
+    id3: "MusicBrainz Track Id",
+    other: "MUSICBRAINZ_TRACKID",
+};
+static MB_ALBUM_ID: Key = Key {
+    read: &["MUSICBRAINZ_ALBUMID", "MusicBrainz Album Id"],
+    id3: "MusicBrainz Album Id",
+    other: "MUSICBRAINZ_ALBUMID",
+};
+static MB_ARTIST_ID: Key = Key {
+    read: &["MUSICBRAINZ_ARTISTID", "MusicBrainz Artist Id"],
+    id3: "MusicBrainz Artist Id",
+    other: "MUSICBRAINZ_ARTISTID",
+};
+static MB_ALBUM_ARTIST_ID: Key = Key {
+    read: &["MUSICBRAINZ_ALBUMARTISTID", "MusicBrainz Album Artist Id"],
+    id3: "MusicBrainz Album Artist Id",
+    other: "MUSICBRAINZ_ALBUMARTISTID",
+};
+static MB_RELEASE_GROUP_ID: Key = Key {
+    read: &["MUSICBRAINZ_RELEASEGROUPID", "MusicBrainz Release Group Id"],
+    id3: "MusicBrainz Release Group Id",
+    other: "MUSICBRAINZ_RELEASEGROUPID",
+};
+
+// "n" or "n/total"
+static TRACK: Key = Key { read: &["track", "tracknumber"], id3: "track", other: "track" };
+static TRACK_TOTAL: &'static [&'static str] = &["TRACKTOTAL", "TOTALTRACKS"];
+static DISC: Key = Key { read: &["disc", "discnumber"], id3: "disc", other: "disc" };
+static DISC_TOTAL: &'static [&'static str] = &["DISCTOTAL", "TOTALDISCS"];
+
+/// the common tags of a file. None means the file does not have the tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<u32>,
+    pub isrc: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_album_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    pub musicbrainz_album_artist_id: Option<String>,
+    pub musicbrainz_release_group_id: Option<String>,
+}
+
+impl Tags {
+    /// no tags at all
+    pub fn new() -> Tags {
+        Tags {
+            title: Option::None,
+            artist: Option::None,
+            album: Option::None,
+            album_artist: Option::None,
+            track: Option::None,
+            track_total: Option::None,
+            disc: Option::None,
+            disc_total: Option::None,
+            date: Option::None,
+            genre: Option::None,
+            composer: Option::None,
+            comment: Option::None,
+            bpm: Option::None,
+            isrc: Option::None,
+            musicbrainz_recording_id: Option::None,
+            musicbrainz_album_id: Option::None,
+            musicbrainz_artist_id: Option::None,
+            musicbrainz_album_artist_id: Option::None,
+            musicbrainz_release_group_id: Option::None,
+        }
+    }
+
+    pub fn read(file: &File) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in tags.text_fields_mut().into_iter() {
+            *value = read_first(file, key.read);
+        }
+        let (track, track_total) = read_position(file, &TRACK, TRACK_TOTAL);
+        tags.track = track;
+        tags.track_total = track_total;
+        let (disc, disc_total) = read_position(file, &DISC, DISC_TOTAL);
+        tags.disc = disc;
+        tags.disc_total = disc_total;
+        tags.bpm = read_first(file, BPM.read).and_then(|bpm| parse_number(bpm.as_slice()));
+        tags
+    }
+
+    /// write the fields which differ from what the file has now using
+    /// File::metadata_set and File::metadata_delete. every known spelling of
+    /// a changed field is removed first so that stale values do not linger.
+    /// returns whether anything changed. call File::save to write to disk.
+    pub fn write(&self, file: &File) -> Result<bool, i32> {
+        let id3 = is_id3(file);
+        let current = Tags::read(file);
+        let mut changed = false;
+
+        for ((key, value), (_, current_value)) in
+            self.text_fields().into_iter().zip(current.text_fields().into_iter())
+        {
+            if value != current_value {
+                try!(replace(file, key.read, if id3 {key.id3} else {key.other}, value));
+                changed = true;
+            }
+        }
+        if self.bpm != current.bpm {
+            let value = self.bpm.map(|bpm| bpm.to_string());
+            try!(replace(file, BPM.read, if id3 {BPM.id3} else {BPM.other}, &value));
+            changed = true;
+        }
+        let track = (self.track, self.track_total);
+        match position_update(track, (current.track, current.track_total)) {
+            Option::Some(value) => {
+                try!(delete_all(file, TRACK_TOTAL));
+                try!(replace(file, TRACK.read, if id3 {TRACK.id3} else {TRACK.other}, &value));
+                changed = true;
+            },
+            Option::None => {},
+        }
+        let disc = (self.disc, self.disc_total);
+        match position_update(disc, (current.disc, current.disc_total)) {
+            Option::Some(value) => {
+                try!(delete_all(file, DISC_TOTAL));
+                try!(replace(file, DISC.read, if id3 {DISC.id3} else {DISC.other}, &value));
+                changed = true;
+            },
+            Option::None => {},
+        }
+        Result::Ok(changed)
+    }
+
+    /// write and then save the file if anything changed
+    pub fn save(&self, file: &File) -> Result<(), i32> {
+        if try!(self.write(file)) {
+            try!(file.save());
+        }
+        Result::Ok(())
+    }
+
+    fn text_fields(&self) -> Vec<(&'static Key, &Option<String>)> {
+        vec![
+            (&TITLE, &self.title),
+            (&ARTIST, &self.artist),
+            (&ALBUM, &self.album),
+            (&ALBUM_ARTIST, &self.album_artist),
+            (&DATE, &self.date),
+            (&GENRE, &self.genre),
+            (&COMPOSER, &self.composer),
+            (&COMMENT, &self.comment),
+            (&ISRC, &self.isrc),
+            (&MB_RECORDING_ID, &self.musicbrainz_recording_id),
+            (&MB_ALBUM_ID, &self.musicbrainz_album_id),
+            (&MB_ARTIST_ID, &self.musicbrainz_artist_id),
+            (&MB_ALBUM_ARTIST_ID, &self.musicbrainz_album_artist_id),
+            (&MB_RELEASE_GROUP_ID, &self.musicbrainz_release_group_id),
+        ]
+    }
+
+    fn text_fields_mut(&mut self) -> Vec<(&'static Key, &mut Option<String>)> {
+        vec![
+            (&TITLE, &mut self.title),
+            (&ARTIST, &mut self.artist),
+            (&ALBUM, &mut self.album),
+            (&ALBUM_ARTIST, &mut self.album_artist),
+            (&DATE, &mut self.date),
+            (&GENRE, &mut self.genre),
+            (&COMPOSER, &mut self.composer),
+            (&COMMENT, &mut self.comment),
+            (&ISRC, &mut self.isrc),
+            (&MB_RECORDING_ID, &mut self.musicbrainz_recording_id),
+            (&MB_ALBUM_ID, &mut self.musicbrainz_album_id),
+            (&MB_ARTIST_ID, &mut self.musicbrainz_artist_id),
+            (&MB_ALBUM_ARTIST_ID, &mut self.musicbrainz_album_artist_id),
+            (&MB_RELEASE_GROUP_ID, &mut self.musicbrainz_release_group_id),
+        ]
+    }
+}
+
+/// demuxers which read their tags from ID3v2, whether at the start of the
+/// file or in a chunk of their own
+static ID3_DEMUXERS: &'static [&'static str] = &["mp3", "aiff", "wav", "aac", "tta"];
+
+/// libav names ID3v2 frames it does not know by their frame id, so files
+/// with ID3v2 tags need different spellings for some fields.
+fn is_id3(file: &File) -> bool {
+    match file.demuxer_name() {
+        // some demuxers have a list of names, such as "mov,mp4,m4a"
+        Option::Some(name) => name.split(',').any(|name| ID3_DEMUXERS.contains(&name)),
+        Option::None => false,
+    }
+}
+
+fn read_first(file: &File, keys: &[&str]) -> Option<String> {
+    for key in keys.iter() {
+        match file.metadata_get(*key, false) {
+            Option::Some(tag) => {
+                let value = tag.value_lossy();
+                let value = value.trim();
+                if !value.is_empty() {
+                    return Option::Some(value.to_string());
+                }
+            },
+            Option::None => {},
+        }
+    }
+    Option::None
+}
+
+/// read "n/total", falling back to a separate total tag
+fn read_position(file: &File, key: &Key, total_keys: &[&str]) -> (Option<u32>, Option<u32>) {
+    let value = read_first(file, key.read);
+    let total = read_first(file, total_keys);
+    parse_position(value.as_ref().map(|v| v.as_slice()), total.as_ref().map(|v| v.as_slice()))
+}
+
+/// "0/total" is how format_position writes a total without a number
+fn parse_position(value: Option<&str>, total: Option<&str>) -> (Option<u32>, Option<u32>) {
+    let (number, own_total) = match value {
+        Option::Some(value) => match value.find('/') {
+            Option::Some(index) => (parse_number(&value[..index]),
+                                    parse_number(&value[index + 1..])),
+            Option::None => (parse_number(value), Option::None),
+        },
+        Option::None => (Option::None, Option::None),
+    };
+    let number = match number {
+        Option::Some(0) => Option::None,
+        number => number,
+    };
+    (number, own_total.or_else(|| total.and_then(parse_number)))
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    let value = value.trim();
+    // BPM is sometimes written with a fraction
+    let value = match value.find('.') {
+        Option::Some(index) => &value[..index],
+        Option::None => value,
+    };
+    value.parse::<u32>().ok()
+}
+
+fn format_position(number: Option<u32>, total: Option<u32>) -> Option<String> {
+    match (number, total) {
+        (Option::Some(number), Option::Some(total)) => Option::Some(format!("{}/{}", number, total)),
+        (Option::Some(number), Option::None) => Option::Some(number.to_string()),
+        (Option::None, Option::Some(total)) => Option::Some(format!("0/{}", total)),
+        (Option::None, Option::None) => Option::None,
+    }
+}
+
+/// the value to write for a number and total if it differs from the
+/// current one. Some(None) removes the tag.
+fn position_update(new: (Option<u32>, Option<u32>), current: (Option<u32>, Option<u32>))
+    -> Option<Option<String>>
+{
+    if new == current {
+        Option::None
+    } else {
+        Option::Some(format_position(new.0, new.1))
+    }
+}
+
+fn delete_all(file: &File, keys: &[&str]) -> Result<(), i32> {
+    for key in keys.iter() {
+        try!(file.metadata_delete(*key, false));
+    }
+    Result::Ok(())
+}
+
+fn replace(file: &File, keys: &[&str], write_key: &str, value: &Option<String>) -> Result<(), i32> {
+    try!(delete_all(file, keys));
+    match *value {
+        Option::Some(ref value) => file.metadata_set(write_key, value.as_slice(), false),
+        Option::None => Result::Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::option::Option;
+    use super::{format_position, parse_number, parse_position, position_update};
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("7"), Option::Some(7));
+        assert_eq!(parse_number(" 12 "), Option::Some(12));
+        assert_eq!(parse_number("120.5"), Option::Some(120));
+        assert_eq!(parse_number(""), Option::None);
+        assert_eq!(parse_number("A1"), Option::None);
+        assert_eq!(parse_number("-3"), Option::None);
+    }
+
+    #[test]
+    fn parse_positions() {
+        assert_eq!(parse_position(Option::Some("3/12"), Option::None),
+                   (Option::Some(3), Option::Some(12)));
+        assert_eq!(parse_position(Option::Some("3"), Option::None), (Option::Some(3), Option::None));
+        assert_eq!(parse_position(Option::Some("0/12"), Option::None), (Option::None, Option::Some(12)));
+        // a separate TRACKTOTAL tag
+        assert_eq!(parse_position(Option::Some("3"), Option::Some("12")),
+                   (Option::Some(3), Option::Some(12)));
+        assert_eq!(parse_position(Option::None, Option::Some("12")), (Option::None, Option::Some(12)));
+        // the total in the number tag wins
+        assert_eq!(parse_position(Option::Some("3/12"), Option::Some("10")),
+                   (Option::Some(3), Option::Some(12)));
+        assert_eq!(parse_position(Option::None, Option::None), (Option::None, Option::None));
+    }
+
+    #[test]
+    fn format_positions_round_trip() {
+        let cases = [
+            (Option::Some(3), Option::Some(12)),
+            (Option::Some(3), Option::None),
+            (Option::None, Option::Some(12)),
+        ];
+        for &(number, total) in cases.iter() {
+            let value = format_position(number, total).unwrap();
+            assert_eq!(parse_position(Option::Some(value.as_slice()), Option::None), (number, total));
+        }
+        assert_eq!(format_position(Option::None, Option::Some(12)), Option::Some("0/12".to_string()));
+        assert_eq!(format_position(Option::None, Option::None), Option::None);
+    }
+
+    #[test]
+    fn changing_only_the_total_rewrites_the_position() {
+        assert_eq!(position_update((Option::Some(3), Option::Some(14)),
+                                   (Option::Some(3), Option::Some(12))),
+                   Option::Some(Option::Some("3/14".to_string())));
+        assert_eq!(position_update((Option::Some(3), Option::Some(12)),
+                                   (Option::Some(3), Option::Some(12))),
+                   Option::None);
+        assert_eq!(position_update((Option::Some(3), Option::None),
+                                   (Option::Some(3), Option::Some(12))),
+                   Option::Some(Option::Some("3".to_string())));
+        assert_eq!(position_update((Option::None, Option::None),
+                                   (Option::Some(3), Option::Some(12))),
+                   Option::Some(Option::None));
+    }
+}
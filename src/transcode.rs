@@ -0,0 +1,337 @@
+//! transcode one or more files into a single output, reporting progress as
+//! it goes. This is the workflow from examples/transcode.rs as a library API.
+//!
+//! Optionally, every item can be normalized to a target loudness. This takes
+//! two passes: the first measures each input with a LoudnessDetector, the
+//! second encodes with a per item gain and tags the output with the gain a
+//! player should apply on top.
+
+use std::old_io;
+use std::old_io::{IoError, Writer};
+use std::num::Float;
+use std::option::Option;
+use std::result::Result;
+
+use super::{AudioFormat, Encoder, EncoderError, EncoderPreset, File, LoudnessDetector, Playlist,
+            TagPolicy};
+use super::replaygain;
+
+/// EBU R128 reference loudness in LUFS
+const R128_REFERENCE: f64 = -23.0;
+
+/// where the encoded audio goes
+pub enum Output<'a> {
+    /// create or truncate this file. the file name also helps libgroove
+    /// guess the format when no preset is set.
+    Path(Path),
+    Writer(&'a mut (Writer + 'a)),
+}
+
+/// what to do with the tags of the input files
+#[derive(Clone, Debug)]
+pub enum MetadataPolicy {
+    /// the output has no tags
+    Drop,
+    /// copy the tags of a single input with Encoder::copy_metadata_from,
+    /// or merge the tags of several with Encoder::copy_metadata_from_playlist
+    Copy(TagPolicy),
+}
+
+/// which tags describe the loudness of a normalized output
+#[derive(Copy, Debug)]
+pub enum LoudnessTags {
+    /// REPLAYGAIN_TRACK_GAIN, REPLAYGAIN_TRACK_PEAK and the album
+    /// equivalents, relative to -18 LUFS
+    ReplayGain,
+    /// R128_TRACK_GAIN and R128_ALBUM_GAIN as Q7.8 integers relative to
+    /// -23 LUFS, as used by Opus
+    R128,
+}
+
+/// passed to the progress callback after each encoded buffer
+#[derive(Copy, Debug)]
+pub struct Progress {
+    /// index into the inputs of the item currently being encoded
+    pub item_index: usize,
+    /// seconds encoded so far, counting all previous items
+    pub seconds: f64,
+    /// sum of the durations of all inputs. see File::duration for caveats.
+    pub total_duration: f64,
+}
+
+pub struct ItemReport {
+    pub path: Path,
+    /// duration according to File::duration
+    pub duration: f64,
+    /// integrated loudness in LUFS measured in the first pass. None if
+    /// loudness normalization is off.
+    pub loudness: Option<f64>,
+    /// the gain in float format applied to this item. 1.0 if loudness
+    /// normalization is off.
+    pub gain: f64,
+}
+
+pub struct TranscodeReport {
+    pub items: Vec<ItemReport>,
+    /// the audio format the encoder actually used
+    pub audio_format: AudioFormat,
+    pub bytes_written: u64,
+    /// sum of the item durations
+    pub duration: f64,
+}
+
+#[derive(Debug)]
+pub enum TranscodeError {
+    /// run was called without any inputs
+    NoInputs,
+    /// this input could not be opened
+    OpenInput(Path),
+    /// the loudness detector failed to attach with this error code
+    Loudness(i32),
+    /// the encoder rejected a tag with this error code, while clearing the
+    /// tags of the previous run, copying tags or adding loudness tags
+    Metadata(i32),
+    /// the codec of the preset is missing
+    Encoder(EncoderError),
+    /// the encoder failed to attach with this error code
+    Attach(i32),
+    Io(IoError),
+}
+
+pub struct Transcoder<'a> {
+    inputs: Vec<Path>,
+    encoder: Encoder,
+    metadata_policy: MetadataPolicy,
+    loudness_target: Option<(f64, LoudnessTags)>,
+    progress: Option<Box<FnMut(Progress) + 'a>>,
+}
+
+impl<'a> Transcoder<'a> {
+    pub fn new() -> Self {
+        Transcoder {
+            inputs: Vec::new(),
+            encoder: Encoder::new(),
+            metadata_policy: MetadataPolicy::Copy(TagPolicy::All),
+            loudness_target: Option::None,
+            progress: Option::None,
+        }
+    }
+
+    /// inputs are encoded back to back in the order they are added
+    pub fn add_input(&mut self, path: &Path) {
+        self.inputs.push(path.clone());
+    }
+
+    /// shorthand for encoder().set_preset
+    pub fn set_preset(&mut self, preset: EncoderPreset) {
+        self.encoder.set_preset(preset);
+    }
+
+    /// defaults to MetadataPolicy::Copy(TagPolicy::All)
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.metadata_policy = policy;
+    }
+
+    /// normalize every item to this integrated loudness in LUFS, for example
+    /// -16.0 or -23.0, and describe the result with tags.
+    /// this makes run decode every input twice.
+    pub fn set_loudness_target(&mut self, lufs: f64, tags: LoudnessTags) {
+        self.loudness_target = Option::Some((lufs, tags));
+    }
+
+    /// called after every encoded buffer
+    pub fn set_progress_callback<F: FnMut(Progress) + 'a>(&mut self, callback: F) {
+        self.progress = Option::Some(Box::new(callback));
+    }
+
+    /// the encoder used by run, for settings which have no shorthand here.
+    /// run removes the tags of the encoder before it applies the metadata
+    /// policy, so tags set here do not reach the output.
+    pub fn encoder(&self) -> &Encoder {
+        &self.encoder
+    }
+
+    /// encode all the inputs into output and block until done
+    pub fn run(&mut self, output: Output) -> Result<TranscodeReport, TranscodeError> {
+        if self.inputs.is_empty() {
+            return Result::Err(TranscodeError::NoInputs);
+        }
+        // the tags of the previous output
+        try!(self.encoder.clear_metadata().map_err(TranscodeError::Metadata));
+
+        let mut files = Vec::new();
+        for path in self.inputs.iter() {
+            match File::open(path) {
+                Option::Some(file) => files.push(file),
+                Option::None => return Result::Err(TranscodeError::OpenInput(path.clone())),
+            }
+        }
+
+        let loudness = match self.loudness_target {
+            Option::Some(_) => Option::Some(try!(measure_loudness(files.as_slice()))),
+            Option::None => Option::None,
+        };
+
+        let playlist = Playlist::new();
+        let mut items = Vec::new();
+        let mut item_reports = Vec::new();
+        let mut start_times = Vec::new();
+        let mut total_duration = 0.0;
+        let mut output_peak: f64 = 0.0;
+        for (index, file) in files.iter().enumerate() {
+            let duration = file.duration();
+            let (item_loudness, gain, peak) = match (self.loudness_target, &loudness) {
+                (Option::Some((target, _)), &Option::Some(ref measured)) => {
+                    let (item_loudness, peak) = measured[index];
+                    let gain = if item_loudness.is_finite() {
+                        replaygain::db_to_float(target - item_loudness)
+                    } else {
+                        // silence can not be normalized
+                        1.0
+                    };
+                    (Option::Some(item_loudness), gain, peak)
+                },
+                _ => (Option::None, 1.0, 1.0),
+            };
+            output_peak = output_peak.max(peak * gain);
+            items.push(playlist.append(file, gain, peak));
+            item_reports.push(ItemReport {
+                path: self.inputs[index].clone(),
+                duration: duration,
+                loudness: item_loudness,
+                gain: gain,
+            });
+            start_times.push(total_duration);
+            total_duration += duration;
+        }
+
+        match output {
+            Output::Path(ref path) => match path.as_str() {
+                Option::Some(filename) => self.encoder.set_filename(filename),
+                Option::None => {},
+            },
+            Output::Writer(_) => {},
+        }
+        if files.len() == 1 && self.encoder.preset.get().is_none() {
+            self.encoder.set_target_audio_format(files[0].audio_format());
+        }
+        match self.metadata_policy {
+            MetadataPolicy::Copy(ref policy) => {
+                let result = if files.len() == 1 {
+                    self.encoder.copy_metadata_from(&files[0], policy)
+                } else {
+                    self.encoder.copy_metadata_from_playlist(&playlist, policy)
+                };
+                try!(result.map_err(TranscodeError::Metadata));
+            },
+            MetadataPolicy::Drop => {},
+        }
+        match self.loudness_target {
+            Option::Some((target, tags)) => {
+                // every item now plays at the target loudness, so the album
+                // does too.
+                let tag_values = match tags {
+                    LoudnessTags::ReplayGain => {
+                        let gain = format!("{:.2} dB", replaygain::REFERENCE_LUFS - target);
+                        let peak = format!("{:.6}", output_peak);
+                        vec![("REPLAYGAIN_TRACK_GAIN", gain.clone()),
+                             ("REPLAYGAIN_TRACK_PEAK", peak.clone()),
+                             ("REPLAYGAIN_ALBUM_GAIN", gain),
+                             ("REPLAYGAIN_ALBUM_PEAK", peak)]
+                    },
+                    LoudnessTags::R128 => {
+                        let gain = format!("{}", ((R128_REFERENCE - target) * 256.0).round() as i32);
+                        vec![("R128_TRACK_GAIN", gain.clone()),
+                             ("R128_ALBUM_GAIN", gain)]
+                    },
+                };
+                for &(key, ref value) in tag_values.iter() {
+                    try!(self.encoder.metadata_set(key, value.as_slice(), false)
+                         .map_err(TranscodeError::Metadata));
+                }
+            },
+            Option::None => {},
+        }
+
+        try!(self.encoder.check_preset().map_err(TranscodeError::Encoder));
+        try!(self.encoder.attach(&playlist).map_err(TranscodeError::Attach));
+
+        let mut output_file;
+        let writer: &mut Writer = match output {
+            Output::Path(path) => {
+                output_file = try!(old_io::File::create(&path).map_err(TranscodeError::Io));
+                &mut output_file
+            },
+            Output::Writer(writer) => writer,
+        };
+
+        let mut bytes_written = 0u64;
+        let mut item_index = 0;
+        loop {
+            match self.encoder.buffer_get_blocking() {
+                Option::Some(buf) => {
+                    let data = buf.as_vec();
+                    try!(writer.write_all(data).map_err(TranscodeError::Io));
+                    bytes_written += data.len() as u64;
+
+                    let mut seconds = start_times[item_index];
+                    match buf.item() {
+                        Option::Some(item) => {
+                            match items.iter().position(|x| *x == item) {
+                                Option::Some(index) => item_index = index,
+                                Option::None => {},
+                            }
+                            seconds = start_times[item_index] + buf.pos();
+                        },
+                        Option::None => {},
+                    }
+                    match self.progress {
+                        Option::Some(ref mut callback) => callback(Progress {
+                            item_index: item_index,
+                            seconds: seconds,
+                            total_duration: total_duration,
+                        }),
+                        Option::None => {},
+                    }
+                },
+                Option::None => break,
+            }
+        }
+        try!(writer.flush().map_err(TranscodeError::Io));
+
+        let audio_format = self.encoder.get_actual_audio_format();
+        self.encoder.detach();
+
+        Result::Ok(TranscodeReport {
+            items: item_reports,
+            audio_format: audio_format,
+            bytes_written: bytes_written,
+            duration: total_duration,
+        })
+    }
+}
+
+/// first pass: integrated loudness in LUFS and sample peak of each file
+fn measure_loudness(files: &[File]) -> Result<Vec<(f64, f64)>, TranscodeError> {
+    let playlist = Playlist::new();
+    let detector = LoudnessDetector::new();
+    detector.set_disable_album(true);
+    try!(detector.attach(&playlist).map_err(TranscodeError::Loudness));
+
+    let items: Vec<_> = files.iter().map(|file| playlist.append(file, 1.0, 1.0)).collect();
+    let mut results: Vec<(f64, f64)> = files.iter().map(|_| (Float::neg_infinity(), 1.0)).collect();
+    loop {
+        match detector.info_get_blocking() {
+            Option::Some(info) => match info.item {
+                Option::Some(item) => match items.iter().position(|x| *x == item) {
+                    Option::Some(index) => results[index] = (info.loudness, info.peak),
+                    Option::None => {},
+                },
+                Option::None => break,
+            },
+            Option::None => break,
+        }
+    }
+    detector.detach();
+    Result::Ok(results)
+}
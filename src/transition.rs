@@ -0,0 +1,433 @@
+//! crossfades, fades and gaps between playlist items, for playback.
+//!
+//! libgroove plays items back to back and offers no way to change the
+//! decoded stream before it reaches a Sink, an Encoder or a detector.
+//! Transitions::stream therefore decodes the playlist through a Sink of
+//! its own and hands the mixed audio to a callback, for playback. An
+//! Encoder, LoudnessDetector or Fingerprinter attached to the playlist
+//! does not hear the transitions.
+//!
+//! The mixing happens as the Sink delivers buffers; nothing is decoded
+//! ahead of time. The output is interleaved 32 bit float in the channel
+//! layout of the first item, see output_format; the sink converts other
+//! items to it. Item gains and the playlist gain are applied by the
+//! decoder as usual. Use a playlist which nothing else is attached to.
+
+use std::cmp;
+use std::f64::consts::PI;
+use std::iter::repeat;
+use std::num::Float;
+use std::old_io::{IoError, IoResult};
+use std::option::Option;
+use std::result::Result;
+
+use super::{AudioFormat, ChannelLayout, Playlist, PlaylistItem, SampleFormat, SampleType, Sink};
+
+/// the shape of a fade
+#[derive(Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// keeps the combined power constant, which avoids a dip in the
+    /// middle of a crossfade between unrelated songs
+    EqualPower,
+    /// starts and ends slowly
+    SCurve,
+}
+
+impl Curve {
+    /// gain of a fade in at t from 0 to 1
+    fn fade_in(&self, t: f64) -> f64 {
+        let t = t.max(0.0).min(1.0);
+        match *self {
+            Curve::Linear => t,
+            Curve::EqualPower => (t * PI / 2.0).sin(),
+            Curve::SCurve => (1.0 - (t * PI).cos()) / 2.0,
+        }
+    }
+
+    /// gain of a fade out at t from 0 to 1
+    fn fade_out(&self, t: f64) -> f64 {
+        self.fade_in(1.0 - t)
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    /// attaching the sink failed
+    Groove(i32),
+    /// the output callback failed
+    Io(IoError),
+}
+
+pub struct Transitions {
+    crossfade: f64,
+    curve: Curve,
+    gap: f64,
+    fade_ins: Vec<(PlaylistItem, f64)>,
+    fade_outs: Vec<(PlaylistItem, f64)>,
+}
+
+/// the mixer between buffers
+struct State {
+    channels: usize,
+    current: Option<PlaylistItem>,
+    /// frames of the current item seen so far
+    frame: usize,
+    fade_in_frames: usize,
+    /// the end of the current item, held back until it is known whether it
+    /// needs fading out or crossfading
+    held: Vec<f32>,
+    hold_frames: usize,
+    /// the faded out end of the previous item, to be added to the start of
+    /// the current one
+    mix_tail: Vec<f32>,
+}
+
+impl State {
+    fn new(channels: usize) -> State {
+        State {
+            channels: channels,
+            current: Option::None,
+            frame: 0,
+            fade_in_frames: 0,
+            held: Vec::new(),
+            hold_frames: 0,
+            mix_tail: Vec::new(),
+        }
+    }
+}
+
+impl Transitions {
+    /// gapless: items follow each other with nothing in between
+    pub fn new() -> Transitions {
+        Transitions {
+            crossfade: 0.0,
+            curve: Curve::EqualPower,
+            gap: 0.0,
+            fade_ins: Vec::new(),
+            fade_outs: Vec::new(),
+        }
+    }
+
+    /// overlap consecutive items by seconds. curve is also used for the
+    /// fades of set_fade_in and set_fade_out. 0 turns crossfading off.
+    pub fn set_crossfade(&mut self, seconds: f64, curve: Curve) {
+        self.crossfade = seconds.max(0.0);
+        self.curve = curve;
+    }
+
+    /// seconds of silence between items which are not crossfaded
+    pub fn set_gap(&mut self, seconds: f64) {
+        self.gap = seconds.max(0.0);
+    }
+
+    /// fade in the start of item over seconds
+    pub fn set_fade_in(&mut self, item: &PlaylistItem, seconds: f64) {
+        set_item_seconds(&mut self.fade_ins, item, seconds);
+    }
+
+    /// fade out the end of item over seconds
+    pub fn set_fade_out(&mut self, item: &PlaylistItem, seconds: f64) {
+        set_item_seconds(&mut self.fade_outs, item, seconds);
+    }
+
+    /// the format of the mixed audio of playlist: interleaved float at
+    /// sample_rate, in the channel layout of the first item. stereo for an
+    /// empty playlist.
+    pub fn output_format(playlist: &Playlist, sample_rate: i32) -> AudioFormat {
+        let channel_layout = match playlist.iter().next() {
+            Option::Some(item) => item.file().audio_format().channel_layout,
+            Option::None => ChannelLayout::LayoutStereo,
+        };
+        AudioFormat {
+            sample_rate: sample_rate,
+            channel_layout: channel_layout,
+            sample_fmt: SampleFormat { sample_type: SampleType::Flt, planar: false },
+        }
+    }
+
+    /// decode playlist and call output with the mixed audio, in
+    /// output_format, until the end of the playlist, or until output
+    /// returns an error
+    pub fn stream(&self, playlist: &Playlist, sample_rate: i32,
+                  output: &mut FnMut(&[f32]) -> IoResult<()>) -> Result<(), TransitionError>
+    {
+        let format = Transitions::output_format(playlist, sample_rate);
+        let sink = Sink::new();
+        sink.set_audio_format(format);
+        try!(sink.attach(playlist).map_err(TransitionError::Groove));
+        let channels = format.channel_layout.count() as usize;
+        let result = self.mix(&sink, sample_rate as f64, channels, output);
+        sink.detach();
+        result.map_err(TransitionError::Io)
+    }
+
+    fn mix(&self, sink: &Sink, rate: f64, channels: usize,
+           output: &mut FnMut(&[f32]) -> IoResult<()>) -> IoResult<()>
+    {
+        let mut state = State::new(channels);
+        loop {
+            let buffer = match sink.buffer_get_blocking() {
+                Option::Some(buffer) => buffer,
+                Option::None => break,
+            };
+            try!(self.push(&mut state, rate, buffer.item(), buffer.as_slice_f32().to_vec(), output));
+        }
+        self.finish(&mut state, rate, output)
+    }
+
+    /// mix the next samples, of item, and output what is ready
+    fn push(&self, state: &mut State, rate: f64, item: PlaylistItem, mut samples: Vec<f32>,
+            output: &mut FnMut(&[f32]) -> IoResult<()>) -> IoResult<()>
+    {
+        if state.current.as_ref() != Option::Some(&item) {
+            if state.current.is_some() {
+                try!(self.end_item(state, rate, true, output));
+            }
+            state.frame = 0;
+            state.fade_in_frames = (item_seconds(&self.fade_ins, &item) * rate).round() as usize;
+            let fade_out_frames = (item_seconds(&self.fade_outs, &item) * rate).round() as usize;
+            let crossfade_frames = (self.crossfade * rate).round() as usize;
+            state.hold_frames = cmp::max(fade_out_frames, crossfade_frames);
+            state.current = Option::Some(item);
+        }
+
+        let mix_frames = state.mix_tail.len() / state.channels;
+        for f in 0..samples.len() / state.channels {
+            let frame = state.frame + f;
+            let mut gain = 1.0;
+            if frame < state.fade_in_frames {
+                gain *= self.curve.fade_in(frame as f64 / state.fade_in_frames as f64);
+            }
+            if frame < mix_frames {
+                gain *= self.curve.fade_in(frame as f64 / mix_frames as f64);
+            }
+            for c in 0..state.channels {
+                let i = f * state.channels + c;
+                samples[i] *= gain as f32;
+                if frame < mix_frames {
+                    samples[i] += state.mix_tail[frame * state.channels + c];
+                }
+            }
+        }
+        state.frame += samples.len() / state.channels;
+        state.held.push_all(samples.as_slice());
+
+        let held_frames = state.held.len() / state.channels;
+        if held_frames > state.hold_frames {
+            let ready = (held_frames - state.hold_frames) * state.channels;
+            try!(output(&state.held[..ready]));
+            let rest = state.held[ready..].to_vec();
+            state.held = rest;
+        }
+        Result::Ok(())
+    }
+
+    /// output the rest of the last item
+    fn finish(&self, state: &mut State, rate: f64,
+              output: &mut FnMut(&[f32]) -> IoResult<()>) -> IoResult<()>
+    {
+        if state.current.is_some() {
+            try!(self.end_item(state, rate, false, output));
+        }
+        Result::Ok(())
+    }
+
+    /// fade out what is held of the current item and output it, keeping
+    /// back the part which is crossfaded with the next item
+    fn end_item(&self, state: &mut State, rate: f64, has_next: bool,
+                output: &mut FnMut(&[f32]) -> IoResult<()>) -> IoResult<()>
+    {
+        // the item was shorter than the crossfade into it; the rest of the
+        // previous item plays over silence
+        let mix_frames = state.mix_tail.len() / state.channels;
+        if state.frame < mix_frames {
+            let rest = state.mix_tail[state.frame * state.channels..].to_vec();
+            state.held.push_all(rest.as_slice());
+        }
+        state.mix_tail.clear();
+
+        let held_frames = state.held.len() / state.channels;
+        let fade_out_frames = match state.current {
+            Option::Some(ref item) => (item_seconds(&self.fade_outs, item) * rate).round() as usize,
+            Option::None => 0,
+        };
+        let fade_out_frames = cmp::min(fade_out_frames, held_frames);
+        let overlap = if has_next {
+            cmp::min((self.crossfade * rate).round() as usize, held_frames)
+        } else {
+            0
+        };
+        for f in 0..held_frames {
+            let mut gain = 1.0;
+            let from_fade_out = f as isize - (held_frames - fade_out_frames) as isize;
+            if from_fade_out >= 0 {
+                gain *= self.curve.fade_out(from_fade_out as f64 / fade_out_frames as f64);
+            }
+            let from_overlap = f as isize - (held_frames - overlap) as isize;
+            if from_overlap >= 0 {
+                gain *= self.curve.fade_out(from_overlap as f64 / overlap as f64);
+            }
+            if gain != 1.0 {
+                for c in 0..state.channels {
+                    state.held[f * state.channels + c] *= gain as f32;
+                }
+            }
+        }
+
+        let split = (held_frames - overlap) * state.channels;
+        try!(output(&state.held[..split]));
+        state.mix_tail = state.held[split..].to_vec();
+        state.held.clear();
+
+        if has_next && overlap == 0 && self.gap > 0.0 {
+            let gap_frames = (self.gap * rate).round() as usize;
+            let silence: Vec<f32> = repeat(0.0).take(gap_frames * state.channels).collect();
+            try!(output(silence.as_slice()));
+        }
+        Result::Ok(())
+    }
+}
+
+fn set_item_seconds(list: &mut Vec<(PlaylistItem, f64)>, item: &PlaylistItem, seconds: f64) {
+    list.retain(|&(ref other, _)| other != item);
+    if seconds > 0.0 {
+        list.push((PlaylistItem {groove_playlist_item: item.groove_playlist_item}, seconds));
+    }
+}
+
+fn item_seconds(list: &Vec<(PlaylistItem, f64)>, item: &PlaylistItem) -> f64 {
+    list.iter().find(|&&(ref other, _)| other == item).map_or(0.0, |&(_, seconds)| seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::iter::repeat;
+    use std::old_io::IoResult;
+
+    use super::{Curve, State, Transitions};
+    use super::super::{GroovePlaylistItem, PlaylistItem};
+
+    const RATE: f64 = 10.0;
+
+    fn item(id: usize) -> PlaylistItem {
+        // only compared, never dereferenced
+        PlaylistItem { groove_playlist_item: id as *mut GroovePlaylistItem }
+    }
+
+    /// mix items given as (id, frames, value) of constant samples, in
+    /// buffers of 3 frames
+    fn mix(transitions: &Transitions, channels: usize, items: &[(usize, usize, f32)]) -> Vec<f32> {
+        let mut out = Vec::new();
+        {
+            let mut output = |samples: &[f32]| -> IoResult<()> {
+                out.push_all(samples);
+                Result::Ok(())
+            };
+            let mut state = State::new(channels);
+            for &(id, frames, value) in items.iter() {
+                let mut left = frames;
+                while left > 0 {
+                    let count = cmp::min(left, 3);
+                    let samples = repeat(value).take(count * channels).collect();
+                    transitions.push(&mut state, RATE, item(id), samples, &mut output).unwrap();
+                    left -= count;
+                }
+            }
+            transitions.finish(&mut state, RATE, &mut output).unwrap();
+        }
+        out
+    }
+
+    fn repeated(value: f32, count: usize) -> Vec<f32> {
+        repeat(value).take(count).collect()
+    }
+
+    fn assert_close(actual: Vec<f32>, expected: Vec<f32>) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn gapless() {
+        let mut expected = repeated(1.0, 5);
+        expected.push_all(repeated(2.0, 5).as_slice());
+        assert_close(mix(&Transitions::new(), 1, &[(1, 5, 1.0), (2, 5, 2.0)]), expected);
+    }
+
+    #[test]
+    fn gaps_between_items() {
+        let mut transitions = Transitions::new();
+        transitions.set_gap(0.3);
+        // no gap after the last item
+        let mut expected = repeated(1.0, 5);
+        expected.push_all(repeated(0.0, 3).as_slice());
+        expected.push_all(repeated(2.0, 5).as_slice());
+        assert_close(mix(&transitions, 1, &[(1, 5, 1.0), (2, 5, 2.0)]), expected);
+    }
+
+    #[test]
+    fn crossfade_overlaps_items() {
+        let mut transitions = Transitions::new();
+        transitions.set_crossfade(0.4, Curve::Linear);
+        // crossfaded items get no gap
+        transitions.set_gap(0.3);
+        let mut expected = repeated(1.0, 6);
+        expected.push_all(&[1.0, 0.75 + 0.5, 0.5 + 1.0, 0.25 + 1.5]);
+        expected.push_all(repeated(2.0, 6).as_slice());
+        assert_close(mix(&transitions, 1, &[(1, 10, 1.0), (2, 10, 2.0)]), expected);
+    }
+
+    #[test]
+    fn item_shorter_than_the_crossfade() {
+        let mut transitions = Transitions::new();
+        transitions.set_crossfade(0.4, Curve::Linear);
+        // the 2 frames of item 2 fade in over the first half of the tail of
+        // item 1, the second half plays over silence, and all of it fades
+        // out into item 3
+        let tail = [(0.0 * 2.0 + 1.0) * 1.0, (0.25 * 2.0 + 0.75) * 0.75, 0.5 * 0.5, 0.25 * 0.25];
+        let mut expected = repeated(1.0, 6);
+        for (f, &value) in tail.iter().enumerate() {
+            expected.push(value + 3.0 * f as f32 / 4.0);
+        }
+        expected.push_all(repeated(3.0, 6).as_slice());
+        assert_close(mix(&transitions, 1, &[(1, 10, 1.0), (2, 2, 2.0), (3, 10, 3.0)]), expected);
+    }
+
+    #[test]
+    fn fades_of_an_item() {
+        let mut transitions = Transitions::new();
+        transitions.set_fade_in(&item(1), 0.4);
+        transitions.set_fade_out(&item(1), 0.2);
+        let gains = [0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 0.5];
+        // both channels of a frame get the same gain
+        let expected = gains.iter().flat_map(|&gain| repeated(gain, 2).into_iter()).collect();
+        assert_close(mix(&transitions, 2, &[(1, 10, 1.0)]), expected);
+
+        // 0 seconds removes the fade
+        transitions.set_fade_in(&item(1), 0.0);
+        assert_eq!(mix(&transitions, 1, &[(1, 10, 1.0)])[0], 1.0);
+    }
+
+    #[test]
+    fn curves() {
+        for &curve in [Curve::Linear, Curve::EqualPower, Curve::SCurve].iter() {
+            assert_eq!(curve.fade_in(0.0), 0.0);
+            assert!((curve.fade_in(1.0) - 1.0).abs() < 1e-12);
+            assert!((curve.fade_out(0.0) - 1.0).abs() < 1e-12);
+            // clamped outside of 0 to 1
+            assert_eq!(curve.fade_in(-1.0), 0.0);
+            assert!((curve.fade_in(2.0) - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(Curve::Linear.fade_in(0.25), 0.25);
+        for &t in [0.1, 0.3, 0.5, 0.9].iter() {
+            let (fade_in, fade_out) = (Curve::EqualPower.fade_in(t), Curve::EqualPower.fade_out(t));
+            assert!((fade_in * fade_in + fade_out * fade_out - 1.0).abs() < 1e-12);
+            assert!((Curve::SCurve.fade_in(t) + Curve::SCurve.fade_out(t) - 1.0).abs() < 1e-12);
+        }
+        assert!(Curve::SCurve.fade_in(0.1) < Curve::Linear.fade_in(0.1));
+    }
+}
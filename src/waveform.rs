@@ -0,0 +1,319 @@
+//! min/max/RMS peak data for drawing waveforms.
+//!
+//! Waveform::generate decodes a playlist through a Sink of its own in
+//! planar float format and summarizes every bin of samples_per_bin frames
+//! per channel. The result can be written as JSON or in the binary .dat
+//! format of audiowaveform, so it can be drawn with waveform-data.js and
+//! peaks.js.
+
+use std::cmp;
+use std::num::Float;
+use std::old_io::{IoResult, Writer};
+use std::option::Option;
+use std::result::Result;
+
+use super::{AudioFormat, Playlist, SampleFormat, SampleType, Sink};
+
+/// how many bins to make
+#[derive(Copy, Debug)]
+pub enum Resolution {
+    PixelsPerSecond(f64),
+    /// about this many bins in total. the count is based on File::duration,
+    /// so when that estimate is off, there are a few bins more or less.
+    Bins(usize),
+}
+
+/// sample resolution of the exported min and max values
+#[derive(Copy, Debug, PartialEq)]
+pub enum Bits {
+    Eight,
+    Sixteen,
+}
+
+/// the summary of samples_per_bin frames of one channel, in float format
+#[derive(Copy, Debug)]
+pub struct Bin {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+pub struct Waveform {
+    pub sample_rate: i32,
+    pub samples_per_bin: usize,
+    /// one Vec of bins per channel
+    pub channels: Vec<Vec<Bin>>,
+}
+
+/// a bin being filled
+struct Accumulator {
+    min: f32,
+    max: f32,
+    sum_squares: f64,
+    count: usize,
+}
+
+impl Accumulator {
+    fn new() -> Accumulator {
+        Accumulator { min: 0.0, max: 0.0, sum_squares: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, sample: f32) {
+        if self.count == 0 || sample < self.min {
+            self.min = sample;
+        }
+        if self.count == 0 || sample > self.max {
+            self.max = sample;
+        }
+        self.sum_squares += sample as f64 * sample as f64;
+        self.count += 1;
+    }
+
+    fn bin(&self) -> Bin {
+        Bin {
+            min: self.min,
+            max: self.max,
+            rms: (self.sum_squares / cmp::max(self.count, 1) as f64).sqrt() as f32,
+        }
+    }
+}
+
+impl Waveform {
+    /// decode playlist and summarize it. the sample rate and channel layout
+    /// are those of the first item's file. returns the error code of
+    /// attaching the sink on failure.
+    pub fn generate(playlist: &Playlist, resolution: Resolution) -> Result<Waveform, i32> {
+        let files: Vec<_> = playlist.iter().map(|item| item.file()).collect();
+        if files.is_empty() {
+            return Result::Ok(Waveform { sample_rate: 44100, samples_per_bin: 1, channels: Vec::new() });
+        }
+        let source_format = files[0].audio_format();
+        let sample_rate = source_format.sample_rate;
+        let channel_count = source_format.channel_layout.count() as usize;
+        let samples_per_bin = match resolution {
+            Resolution::PixelsPerSecond(pixels) => (sample_rate as f64 / pixels).round() as usize,
+            Resolution::Bins(bins) => {
+                let duration = files.iter().fold(0.0, |total, file| total + file.duration());
+                (duration * sample_rate as f64 / bins as f64).ceil() as usize
+            },
+        };
+        let samples_per_bin = cmp::max(samples_per_bin, 1);
+
+        let sink = Sink::new();
+        sink.set_audio_format(AudioFormat {
+            sample_rate: sample_rate,
+            channel_layout: source_format.channel_layout,
+            sample_fmt: SampleFormat { sample_type: SampleType::Flt, planar: true },
+        });
+        try!(sink.attach(playlist));
+
+        let mut accumulators: Vec<Accumulator> = (0..channel_count).map(|_| Accumulator::new()).collect();
+        let mut channels: Vec<Vec<Bin>> = (0..channel_count).map(|_| Vec::new()).collect();
+        loop {
+            let buffer = match sink.buffer_get_blocking() {
+                Option::Some(buffer) => buffer,
+                Option::None => break,
+            };
+            for channel in 0..channel_count {
+                let accumulator = &mut accumulators[channel];
+                for &sample in buffer.channel_as_slice_f32(channel as u32).iter() {
+                    accumulator.add(sample);
+                    if accumulator.count == samples_per_bin {
+                        channels[channel].push(accumulator.bin());
+                        *accumulator = Accumulator::new();
+                    }
+                }
+            }
+        }
+        sink.detach();
+        for (channel, accumulator) in accumulators.iter().enumerate() {
+            if accumulator.count > 0 {
+                channels[channel].push(accumulator.bin());
+            }
+        }
+
+        Result::Ok(Waveform {
+            sample_rate: sample_rate,
+            samples_per_bin: samples_per_bin,
+            channels: channels,
+        })
+    }
+
+    /// number of bins per channel
+    pub fn len(&self) -> usize {
+        self.channels.iter().map(|bins| bins.len()).min().unwrap_or(0)
+    }
+
+    /// the JSON format of audiowaveform, with an extra "rms" array. data
+    /// and rms hold the values of all channels per bin, data as min, max
+    /// pairs, scaled to bits.
+    pub fn write_json(&self, writer: &mut Writer, bits: Bits) -> IoResult<()> {
+        let mut data = Vec::new();
+        let mut rms = Vec::new();
+        for index in 0..self.len() {
+            for bins in self.channels.iter() {
+                let bin = bins[index];
+                data.push(scale(bin.min, bits).to_string());
+                data.push(scale(bin.max, bits).to_string());
+                rms.push(scale(bin.rms, bits).to_string());
+            }
+        }
+        try!(writer.write_str(format!(
+            "{{\"version\":2,\"channels\":{},\"sample_rate\":{},\"samples_per_pixel\":{},\
+             \"bits\":{},\"length\":{},\"data\":[{}],\"rms\":[{}]}}\n",
+            self.channels.len(), self.sample_rate, self.samples_per_bin, bit_count(bits),
+            self.len(), data.connect(","), rms.connect(",")).as_slice()));
+        Result::Ok(())
+    }
+
+    /// the binary .dat format of audiowaveform. version 1 for one channel,
+    /// version 2 for more. RMS is not part of the format.
+    pub fn write_dat(&self, writer: &mut Writer, bits: Bits) -> IoResult<()> {
+        let version = if self.channels.len() > 1 {2} else {1};
+        try!(writer.write_le_i32(version));
+        // flags: bit 0 set means 8 bit samples
+        try!(writer.write_le_u32(if bits == Bits::Eight {1} else {0}));
+        try!(writer.write_le_i32(self.sample_rate));
+        try!(writer.write_le_i32(self.samples_per_bin as i32));
+        try!(writer.write_le_u32(self.len() as u32));
+        if version == 2 {
+            try!(writer.write_le_i32(self.channels.len() as i32));
+        }
+        for index in 0..self.len() {
+            for bins in self.channels.iter() {
+                let bin = bins[index];
+                for &value in [bin.min, bin.max].iter() {
+                    match bits {
+                        Bits::Eight => try!(writer.write_i8(scale(value, bits) as i8)),
+                        Bits::Sixteen => try!(writer.write_le_i16(scale(value, bits) as i16)),
+                    }
+                }
+            }
+        }
+        Result::Ok(())
+    }
+}
+
+fn bit_count(bits: Bits) -> u32 {
+    match bits {
+        Bits::Eight => 8,
+        Bits::Sixteen => 16,
+    }
+}
+
+/// float sample to a signed integer of bits, as audiowaveform converts
+/// 16 bit samples: -1.0 is the lowest value and values from just below 1.0
+/// up clip to the highest
+fn scale(value: f32, bits: Bits) -> i32 {
+    let full_scale = match bits {
+        Bits::Eight => 128.0,
+        Bits::Sixteen => 32768.0,
+    };
+    (value * full_scale).round().max(-full_scale).min(full_scale - 1.0) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scale, Accumulator, Bin, Bits, Waveform};
+
+    fn bin(min: f32, max: f32) -> Bin {
+        Bin { min: min, max: max, rms: 0.0 }
+    }
+
+    #[test]
+    fn accumulator() {
+        let mut accumulator = Accumulator::new();
+        for &sample in [0.5, -0.25, 0.5, -0.5].iter() {
+            accumulator.add(sample);
+        }
+        let bin = accumulator.bin();
+        assert_eq!(bin.min, -0.5);
+        assert_eq!(bin.max, 0.5);
+        assert!((bin.rms - (0.8125f32 / 4.0).sqrt()).abs() < 1e-6);
+
+        // only positive samples
+        let mut accumulator = Accumulator::new();
+        accumulator.add(0.25);
+        accumulator.add(0.75);
+        assert_eq!(accumulator.bin().min, 0.25);
+        assert_eq!(accumulator.bin().max, 0.75);
+
+        let empty = Accumulator::new().bin();
+        assert_eq!((empty.min, empty.max, empty.rms), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn scale_clips() {
+        assert_eq!(scale(0.0, Bits::Sixteen), 0);
+        assert_eq!(scale(0.5, Bits::Sixteen), 16384);
+        assert_eq!(scale(-1.0, Bits::Sixteen), -32768);
+        assert_eq!(scale(-2.0, Bits::Sixteen), -32768);
+        assert_eq!(scale(1.0, Bits::Sixteen), 32767);
+        assert_eq!(scale(1.5, Bits::Sixteen), 32767);
+        assert_eq!(scale(0.25, Bits::Eight), 32);
+        assert_eq!(scale(-1.0, Bits::Eight), -128);
+        assert_eq!(scale(-1.5, Bits::Eight), -128);
+        assert_eq!(scale(1.0, Bits::Eight), 127);
+        assert_eq!(scale(2.0, Bits::Eight), 127);
+    }
+
+    #[test]
+    fn dat_version_1() {
+        let waveform = Waveform {
+            sample_rate: 44100,
+            samples_per_bin: 256,
+            channels: vec![vec![bin(-0.5, 0.5), bin(-1.5, 1.5)]],
+        };
+        let mut out = Vec::new();
+        waveform.write_dat(&mut out, Bits::Sixteen).unwrap();
+        let expected: Vec<u8> = vec![
+            1, 0, 0, 0, // version
+            0, 0, 0, 0, // flags: 16 bit
+            0x44, 0xac, 0, 0, // sample rate
+            0, 1, 0, 0, // samples per pixel
+            2, 0, 0, 0, // length
+            0x00, 0xc0, 0x00, 0x40, // -16384, 16384
+            0x00, 0x80, 0xff, 0x7f, // clipped to -32768, 32767
+        ];
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn dat_version_2() {
+        let waveform = Waveform {
+            sample_rate: 8000,
+            samples_per_bin: 100,
+            channels: vec![vec![bin(-0.25, 0.25)], vec![bin(-2.0, 2.0)]],
+        };
+        let mut out = Vec::new();
+        waveform.write_dat(&mut out, Bits::Eight).unwrap();
+        let expected: Vec<u8> = vec![
+            2, 0, 0, 0, // version
+            1, 0, 0, 0, // flags: 8 bit
+            0x40, 0x1f, 0, 0, // sample rate
+            100, 0, 0, 0, // samples per pixel
+            1, 0, 0, 0, // length
+            2, 0, 0, 0, // channels
+            0xe0, 0x20, // -32, 32
+            0x80, 0x7f, // clipped to -128, 127
+        ];
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn json_interleaves_channels() {
+        let waveform = Waveform {
+            sample_rate: 8000,
+            samples_per_bin: 100,
+            channels: vec![
+                vec![Bin { min: -0.5, max: 0.5, rms: 0.25 }, bin(0.0, 0.0)],
+                vec![Bin { min: -1.0, max: 1.0, rms: 1.0 }, bin(0.0, 0.0)],
+            ],
+        };
+        let mut out = Vec::new();
+        waveform.write_json(&mut out, Bits::Eight).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().as_slice(),
+                   "{\"version\":2,\"channels\":2,\"sample_rate\":8000,\"samples_per_pixel\":100,\
+                    \"bits\":8,\"length\":2,\"data\":[-64,64,-128,127,0,0,0,0],\"rms\":[32,127,0,0]}\n");
+    }
+}